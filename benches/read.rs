@@ -0,0 +1,108 @@
+//! Read throughput of in-memory images, comparing reads of large buffers, which coalesce
+//! contiguous blocks into one backend call, against reading block by block as `read_at` used to.
+
+use std::io::{IoSliceMut, Read, Seek};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use positioned_io2::{ReadAt, WriteAt};
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+
+const DISK_SIZE: u64 = 64 * 1024 * 1024;
+const BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// In-memory image making a system call for every backend read, like a file backend does
+struct SyscallPerRead(Vec<u8>);
+
+impl ReadAt for SyscallPerRead {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        std::thread::yield_now();
+        self.0.as_slice().read_at(pos, buf)
+    }
+}
+
+/// Builds a fully allocated image in memory, with its blocks stored in logical order or in
+/// reverse
+fn fixture(block_size: u32, reversed: bool) -> Vec<u8> {
+    let header = VdiHeader::new(DISK_SIZE, block_size).unwrap();
+    let mut file = tempfile::tempfile().unwrap();
+    let mut disk = VdiDisk::create(Box::new(file.try_clone().unwrap()), header).unwrap();
+
+    let blocks = (DISK_SIZE / block_size as u64) as usize;
+    let mut block = vec![0u8; block_size as usize];
+    for i in 0..blocks {
+        let index = if reversed { blocks - 1 - i } else { i };
+        block.fill(index as u8 | 1);
+        disk.write_all_at(index as u64 * block_size as u64, &block)
+            .unwrap();
+    }
+    drop(disk);
+
+    let mut raw = Vec::new();
+    file.rewind().unwrap();
+    file.read_to_end(&mut raw).unwrap();
+    raw
+}
+
+fn read_block_by_block(disk: &VdiDisk, buf: &mut [u8]) {
+    let mut pos = 0;
+    while pos < DISK_SIZE {
+        for chunk in buf.chunks_mut(disk.block_size) {
+            disk.read_exact_at(pos, chunk).unwrap();
+            pos += chunk.len() as u64;
+        }
+    }
+}
+
+fn read_buffered(disk: &VdiDisk, buf: &mut [u8]) {
+    let mut pos = 0;
+    while pos < DISK_SIZE {
+        disk.read_exact_at(pos, buf).unwrap();
+        pos += buf.len() as u64;
+    }
+}
+
+fn read_vectored(disk: &VdiDisk, buf: &mut [u8]) {
+    let mut pos = 0;
+    while pos < DISK_SIZE {
+        let mut slices: Vec<IoSliceMut> = buf.chunks_mut(512 * 1024).map(IoSliceMut::new).collect();
+        pos += disk.read_vectored_at(pos, &mut slices).unwrap() as u64;
+    }
+}
+
+fn bench_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Bytes(DISK_SIZE));
+    group.sample_size(20);
+
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    for block_size in [64 * 1024, 1024 * 1024] {
+        for (layout, reversed) in [("sequential", false), ("reversed", true)] {
+            let raw = fixture(block_size, reversed);
+            let disks = [
+                ("memory", VdiDisk::open(Box::new(raw.clone())).unwrap()),
+                (
+                    "syscall",
+                    VdiDisk::open(Box::new(SyscallPerRead(raw))).unwrap(),
+                ),
+            ];
+
+            for (backend, disk) in &disks {
+                let parameter = format!("{backend}/{layout}/{}K", block_size / 1024);
+                group.bench_function(BenchmarkId::new("block_by_block", &parameter), |b| {
+                    b.iter(|| read_block_by_block(disk, &mut buf))
+                });
+                group.bench_function(BenchmarkId::new("buffered", &parameter), |b| {
+                    b.iter(|| read_buffered(disk, &mut buf))
+                });
+                group.bench_function(BenchmarkId::new("vectored", &parameter), |b| {
+                    b.iter(|| read_vectored(disk, &mut buf))
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_reads);
+criterion_main!(benches);
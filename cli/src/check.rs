@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::path::Path;
+
+use serde_json::json;
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+use vdi::lock::{LockMode, lock_file};
+
+/// Checks the image for inconsistencies, returns whether it is consistent
+pub fn check(json: bool, path: &Path) -> anyhow::Result<bool> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    // A locked image is an error rather than a problem with the image
+    lock_file(&file, LockMode::Shared)?;
+    let problems = match VdiDisk::open(Box::new(file)) {
+        Ok(disk) => find_problems(&disk, file_len),
+        Err(e) => vec![format!("Failed to open image: {e:#}")],
+    };
+
+    if json {
+        println!(
+            "{:#}",
+            json!({ "ok": problems.is_empty(), "problems": problems })
+        );
+    } else if problems.is_empty() {
+        println!("No problems found");
+    } else {
+        for problem in &problems {
+            println!("{problem}");
+        }
+        println!("{} problem(s) found", problems.len());
+    }
+    Ok(problems.is_empty())
+}
+
+fn find_problems(disk: &VdiDisk, file_len: u64) -> Vec<String> {
+    let header = &disk.header;
+    let mut problems = Vec::new();
+
+    if header.block_size.get() == 0 {
+        return vec!["Block size is zero".to_string()];
+    }
+    let block_size = header.block_size.get() as u64;
+
+    if header.disk_size.get() > header.blocks_in_image.get() as u64 * block_size {
+        problems.push(format!(
+            "Disk size {} exceeds the {} blocks in the image",
+            header.disk_size.get(),
+            header.blocks_in_image.get()
+        ));
+    }
+
+    let map_start = header.block_offsets_offset.get() as u64;
+    let map_end = map_start + header.block_map_size() as u64;
+    if map_start < std::mem::size_of::<VdiHeader>() as u64
+        || map_end > header.data_offset.get() as u64
+    {
+        problems.push("Block map overlaps the header or block data".to_string());
+    }
+
+    // Logical block stored in each file slot
+    let mut slots: Vec<Option<usize>> = vec![None; header.blocks_allocated.get() as usize];
+    let mut allocated = 0;
+    for (index, offset) in disk.block_offsets.iter().enumerate() {
+        let Some(offset) = *offset else {
+            continue;
+        };
+        allocated += 1;
+
+        let slot = ((offset - header.data_offset.get() as u64) / block_size) as usize;
+        if offset + block_size > file_len {
+            problems.push(format!("Block {index} lies beyond the end of the file"));
+        }
+        match slots.get_mut(slot) {
+            Some(Some(other)) => problems.push(format!(
+                "Blocks {other} and {index} share file block {slot}"
+            )),
+            Some(entry) => *entry = Some(index),
+            None => problems.push(format!(
+                "Block {index} is stored in file block {slot}, past the {} allocated blocks",
+                header.blocks_allocated.get()
+            )),
+        }
+    }
+
+    if allocated != header.blocks_allocated.get() as usize {
+        problems.push(format!(
+            "Header claims {} allocated blocks, the block map references {allocated}",
+            header.blocks_allocated.get()
+        ));
+    }
+    let orphaned = slots.iter().filter(|slot| slot.is_none()).count();
+    if orphaned > 0 && allocated == header.blocks_allocated.get() as usize {
+        problems.push(format!("{orphaned} file block(s) are not referenced"));
+    }
+
+    problems
+}
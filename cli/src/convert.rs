@@ -0,0 +1,93 @@
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use positioned_io2::{ReadAt, WriteAt};
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+use vdi::lock::{LockMode, lock_file};
+use vdi::progress::{ProgressHandler, Tracker};
+
+use crate::Format;
+
+/// Copies the disk contents of `input` into a new image, returns the number of bytes of data
+/// copied. Runs of zeros are left unallocated in the output.
+pub fn convert(
+    input: &Path,
+    parents: &[PathBuf],
+    output: &Path,
+    format: Format,
+    block_size: u32,
+    mut progress: Option<&mut dyn ProgressHandler>,
+) -> anyhow::Result<u64> {
+    anyhow::ensure!(
+        !same_file(input, output),
+        "Input and output are the same file"
+    );
+
+    let file = File::open(input)?;
+    let (source, size): (Box<dyn ReadAt>, u64) = if is_vdi(&file)? {
+        drop(file);
+        let disk = crate::open_readable(input, parents)?;
+        let size = disk.header.disk_size.get();
+        (Box::new(disk), size)
+    } else {
+        anyhow::ensure!(parents.is_empty(), "Only VDI images have parents");
+        let size = file.metadata()?.len();
+        (Box::new(file), size)
+    };
+
+    let mut target: Box<dyn WriteAt> = match format {
+        Format::Vdi => {
+            let header = VdiHeader::new(size, block_size)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(output)?;
+            // Lock before truncating, the output could be an image in use
+            lock_file(&file, LockMode::Exclusive)?;
+            Box::new(VdiDisk::create(Box::new(file), header)?)
+        }
+        Format::Raw => {
+            let file = File::create(output)?;
+            file.set_len(size)?;
+            Box::new(file)
+        }
+    };
+
+    let mut buf = vec![0u8; block_size as usize];
+    let mut copied = 0;
+    let mut pos = 0;
+    let mut tracker = Tracker::new(size);
+    while pos < size {
+        let len = std::cmp::min(buf.len() as u64, size - pos) as usize;
+        source.read_exact_at(pos, &mut buf[..len])?;
+        let zeros = buf[..len].iter().all(|&b| b == 0);
+        if !zeros {
+            target.write_all_at(pos, &buf[..len])?;
+            copied += len as u64;
+        }
+        pos += len as u64;
+        tracker.advance(len as u64, zeros, progress.as_deref_mut())?;
+    }
+    target.flush()?;
+    Ok(copied)
+}
+
+fn is_vdi(file: &File) -> anyhow::Result<bool> {
+    let mut signature = [0u8; 4];
+    let offset = std::mem::offset_of!(VdiHeader, signature) as u64;
+    match file.read_exact_at(offset, &mut signature) {
+        Ok(()) => Ok(u32::from_le_bytes(signature) == VdiHeader::SIGNATURE),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
@@ -0,0 +1,589 @@
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use uuid::Uuid;
+use vdi::header::VdiHeader;
+use vdi::lock::{LockMode, lock_file};
+use vdi::partitions::PartitionKind;
+use vdi::progress::Progress;
+use vdi::{CloneOptions, VdiDisk};
+
+mod check;
+mod convert;
+
+#[derive(Parser)]
+#[command(
+    name = "vdi",
+    version,
+    about = "Inspect and modify VirtualBox VDI images"
+)]
+struct Cli {
+    /// Print machine-readable JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the image header, UUIDs, allocation and partitions
+    Info {
+        image: PathBuf,
+        /// Parent of a differencing image, repeated for each image of the chain, closest first
+        #[arg(long)]
+        parent: Vec<PathBuf>,
+        /// Also show fragmentation and reclaimable space, which reads all allocated blocks
+        #[arg(long)]
+        stats: bool,
+    },
+    /// List the allocated ranges of the disk and where they are stored in the image
+    Map { image: PathBuf },
+    /// Check the header and block map for inconsistencies
+    Check { image: PathBuf },
+    /// Convert between VDI and raw disk images
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+        /// Output format, guessed from the output extension by default
+        #[arg(long, short = 'O')]
+        format: Option<Format>,
+        /// Block size of created VDI images
+        #[arg(long, default_value_t = VdiHeader::DEFAULT_BLOCK_SIZE)]
+        block_size: u32,
+        /// Parent of a differencing image, repeated for each image of the chain, closest first
+        #[arg(long)]
+        parent: Vec<PathBuf>,
+    },
+    /// Copy an image to a new file with fresh UUIDs, so both can be registered
+    Clone {
+        image: PathBuf,
+        output: PathBuf,
+        /// Parent of a differencing image, repeated for each image of the chain, closest first
+        #[arg(long)]
+        parent: Vec<PathBuf>,
+        /// Include the data of the parents, making the copy a standalone image
+        #[arg(long)]
+        flatten: bool,
+        /// Leave blocks that only contain zeros unallocated
+        #[arg(long)]
+        compact: bool,
+    },
+    /// Release blocks that only contain zeros and shrink the image file
+    Compact { image: PathBuf },
+    /// Store the blocks in logical order, so sequential reads of the disk are sequential
+    Repack {
+        image: PathBuf,
+        /// Write the repacked image to a new file instead of modifying it in place
+        output: Option<PathBuf>,
+    },
+    /// Grow the disk to a new size, eg. `20G`
+    Resize {
+        image: PathBuf,
+        #[arg(value_parser = parse_size)]
+        size: u64,
+    },
+    /// Assign a new image UUID, a random one if none is given
+    Sethduuid { image: PathBuf, uuid: Option<Uuid> },
+    /// Write the contents of the disk, or one of its partitions, to stdout
+    Cat {
+        image: PathBuf,
+        /// Partition number as listed by `info`
+        #[arg(long, short)]
+        partition: Option<usize>,
+        /// Parent of a differencing image, repeated for each image of the chain, closest first
+        #[arg(long)]
+        parent: Vec<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Vdi,
+    Raw,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Runs the command, returns `false` if it completed but found problems
+fn run(cli: &Cli) -> anyhow::Result<bool> {
+    match &cli.command {
+        Command::Info {
+            image,
+            parent,
+            stats,
+        } => info(cli, image, parent, *stats)?,
+        Command::Map { image } => map(cli, image)?,
+        Command::Check { image } => return check::check(cli.json, image),
+        Command::Convert {
+            input,
+            output,
+            format,
+            block_size,
+            parent,
+        } => {
+            let format =
+                format.unwrap_or_else(|| match output.extension().and_then(|e| e.to_str()) {
+                    Some(ext) if ext.eq_ignore_ascii_case("vdi") => Format::Vdi,
+                    _ => Format::Raw,
+                });
+            let mut progress = progress_printer();
+            let progress = progress
+                .as_mut()
+                .map(|p| p as &mut dyn vdi::progress::ProgressHandler);
+            let copied = convert::convert(input, parent, output, format, *block_size, progress)?;
+            report(
+                cli,
+                json!({ "output": output, "bytes_copied": copied }),
+                || {
+                    format!(
+                        "Copied {} of data to {}",
+                        format_size(copied),
+                        output.display()
+                    )
+                },
+            );
+        }
+        Command::Clone {
+            image,
+            output,
+            parent,
+            flatten,
+            compact,
+        } => {
+            // A clone keeps referencing the parents unless it is flattened
+            let mut disk = open_chain(image, parent)?;
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(output)?;
+            lock_file(&file, LockMode::Exclusive)?;
+            show_progress(&mut disk);
+            let options = CloneOptions {
+                flatten: *flatten,
+                compact: *compact,
+            };
+            let clone = disk.clone_to(Box::new(file), options)?;
+            let uuid = clone.info().uuid_image;
+            report(cli, json!({ "output": output, "uuid": uuid }), || {
+                format!("Cloned to {} with UUID {uuid}", output.display())
+            });
+        }
+        Command::Compact { image } => {
+            let mut disk = open_writable(image)?;
+            show_progress(&mut disk);
+            let reclaimed = disk.compact()?;
+            close_writable(disk, image)?;
+            report(cli, json!({ "bytes_reclaimed": reclaimed }), || {
+                format!("Reclaimed {}", format_size(reclaimed))
+            });
+        }
+        Command::Repack {
+            image,
+            output: Some(output),
+        } => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(output)?;
+            lock_file(&file, LockMode::Exclusive)?;
+            let mut disk = open(image)?;
+            show_progress(&mut disk);
+            let disk = disk.repack_to(Box::new(file))?;
+            let blocks = disk.header.blocks_allocated.get();
+            report(cli, json!({ "output": output, "blocks": blocks }), || {
+                format!("Wrote {blocks} blocks to {}", output.display())
+            });
+        }
+        Command::Repack {
+            image,
+            output: None,
+        } => {
+            let mut disk = open_writable(image)?;
+            disk.set_ordered_writes(true);
+            show_progress(&mut disk);
+            let moved = disk.repack()?;
+            close_writable(disk, image)?;
+            report(cli, json!({ "blocks_moved": moved }), || {
+                format!("Moved {moved} blocks")
+            });
+        }
+        Command::Resize { image, size } => {
+            let mut disk = open_writable(image)?;
+            show_progress(&mut disk);
+            disk.resize(*size)?;
+            close_writable(disk, image)?;
+            report(cli, json!({ "disk_size": size }), || {
+                format!("Resized disk to {}", format_size(*size))
+            });
+        }
+        Command::Sethduuid { image, uuid } => {
+            let uuid = uuid.unwrap_or_else(Uuid::new_v4);
+            let mut disk = open_writable(image)?;
+            disk.set_uuid_image(uuid)?;
+            close_writable(disk, image)?;
+            report(cli, json!({ "uuid": uuid }), || {
+                format!("UUID changed to: {uuid}")
+            });
+        }
+        Command::Cat {
+            image,
+            partition,
+            parent,
+        } => cat(image, parent, *partition)?,
+    }
+    Ok(true)
+}
+
+fn open(path: &Path) -> anyhow::Result<VdiDisk> {
+    let file = File::open(path)?;
+    VdiDisk::open_locked(file, LockMode::Shared)
+}
+
+/// Opens the image and attaches the parents given with `--parent`, closest first
+fn open_chain(path: &Path, parents: &[PathBuf]) -> anyhow::Result<VdiDisk> {
+    let mut disk = open(path)?;
+    let mut parents = parents.iter().rev();
+    if let Some(first) = parents.next() {
+        let mut chain = open(first)?;
+        for path in parents {
+            let mut child = open(path)?;
+            child.set_parent(chain)?;
+            chain = child;
+        }
+        disk.set_parent(chain)?;
+    }
+    Ok(disk)
+}
+
+/// Like [`open_chain`], but fails unless the whole chain of a differencing image was given, as
+/// the blocks stored in the missing parents could not be read
+fn open_readable(path: &Path, parents: &[PathBuf]) -> anyhow::Result<VdiDisk> {
+    let disk = open_chain(path, parents)?;
+    anyhow::ensure!(
+        has_whole_chain(&disk),
+        "{} is a differencing image, pass its parents with --parent",
+        path.display()
+    );
+    Ok(disk)
+}
+
+/// Whether every parent `disk` reads through is attached
+fn has_whole_chain(disk: &VdiDisk) -> bool {
+    let mut base = disk;
+    while let Some(parent) = base.parent() {
+        base = parent;
+    }
+    base.header.image_type.get() != VdiHeader::TYPE_DIFFERENCING
+}
+
+/// Opens the image with a journal next to it, finishing any operation a crash interrupted
+fn open_writable(path: &Path) -> anyhow::Result<VdiDisk> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    lock_file(&file, LockMode::Exclusive)?;
+    let journal = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(journal_path(path))?;
+    VdiDisk::open_journaled(Box::new(file), Box::new(journal))
+}
+
+/// Closes a disk opened with [`open_writable`], removing its journal that is now empty
+fn close_writable(mut disk: VdiDisk, path: &Path) -> anyhow::Result<()> {
+    disk.flush()?;
+    drop(disk);
+    std::fs::remove_file(journal_path(path))?;
+    Ok(())
+}
+
+/// Shows the progress of the bulk operations on `disk`, see [`progress_printer`]
+fn show_progress(disk: &mut VdiDisk) {
+    if let Some(printer) = progress_printer() {
+        disk.set_progress_handler(printer);
+    }
+}
+
+/// Progress handler redrawing a status line on stderr, `None` if stderr is not a terminal
+fn progress_printer() -> Option<impl FnMut(&Progress) -> ControlFlow<()>> {
+    if !std::io::stderr().is_terminal() {
+        return None;
+    }
+
+    let mut last_draw: Option<Instant> = None;
+    Some(move |progress: &Progress| {
+        let done = progress.bytes_processed >= progress.bytes_total;
+        if done || last_draw.is_none_or(|t| t.elapsed() >= Duration::from_millis(200)) {
+            last_draw = Some(Instant::now());
+            let eta = match progress.eta() {
+                Some(eta) if !done => format!(", {}s left", eta.as_secs()),
+                _ => String::new(),
+            };
+            eprint!(
+                "\r{:5.1}% of {}{eta}\x1b[K",
+                progress.fraction() * 100.0,
+                format_size(progress.bytes_total)
+            );
+            if done {
+                eprintln!();
+            }
+        }
+        ControlFlow::Continue(())
+    })
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".journal");
+    PathBuf::from(name)
+}
+
+/// Prints the result of a command in the requested format
+fn report(cli: &Cli, value: serde_json::Value, text: impl FnOnce() -> String) {
+    if cli.json {
+        println!("{value}");
+    } else {
+        println!("{}", text());
+    }
+}
+
+fn info(cli: &Cli, path: &Path, parents: &[PathBuf], with_stats: bool) -> anyhow::Result<()> {
+    // The header and statistics only describe the image itself, but the partition table may be
+    // stored in the parents
+    let mut disk = open_chain(path, parents)?;
+    if with_stats {
+        show_progress(&mut disk);
+    }
+    let info = disk.info();
+    let whole_chain = has_whole_chain(&disk);
+    let partitions = whole_chain.then(|| disk.partition_table().ok()).flatten();
+    let stats = if with_stats {
+        let file_len = std::fs::metadata(path)?.len();
+        Some(disk.stats(file_len)?)
+    } else {
+        None
+    };
+
+    if cli.json {
+        let partitions = partitions.map(|table| {
+            table
+                .partitions
+                .iter()
+                .map(|p| {
+                    json!({
+                        "number": p.number,
+                        "offset": p.first_byte,
+                        "size": p.len,
+                        "type": p.type_name(),
+                        "name": match &p.kind {
+                            PartitionKind::Gpt { name, .. } => Some(name),
+                            PartitionKind::Mbr { .. } => None,
+                        },
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut value = serde_json::to_value(&info)?;
+        value["path"] = json!(path);
+        value["partitions"] = json!(partitions);
+        if let Some(stats) = &stats {
+            value["stats"] = serde_json::to_value(stats)?;
+        }
+        println!("{value:#}");
+        return Ok(());
+    }
+
+    println!("Path:           {}", path.display());
+    println!("Text:           {}", info.text);
+    if !info.description.is_empty() {
+        println!("Description:    {}", info.description);
+    }
+    println!(
+        "Version:        {}.{}",
+        info.version_major, info.version_minor
+    );
+    println!("Type:           {}", info.image_type);
+    println!("UUID:           {}", info.uuid_image);
+    println!("Last snap UUID: {}", info.uuid_last_snap);
+    if let Some(parent) = info.uuid_parent {
+        println!("Parent UUID:    {parent}");
+    }
+    println!(
+        "Disk size:      {} ({} bytes)",
+        format_size(info.disk_size),
+        info.disk_size
+    );
+    println!("Block size:     {}", format_size(info.block_size as u64));
+    println!(
+        "Allocated:      {} of {} blocks ({:.1}%, {})",
+        info.blocks_allocated,
+        info.blocks_in_image,
+        info.allocated_ratio * 100.0,
+        format_size(info.allocated_size)
+    );
+    if let Some(stats) = stats {
+        println!(
+            "Unallocated:    {} free, {} zero blocks",
+            stats.free_blocks, stats.zero_blocks
+        );
+        println!(
+            "Zeroed blocks:  {} ({:.1}% of allocated)",
+            stats.zeroed_allocated_blocks,
+            stats.zeroed_allocated_ratio * 100.0
+        );
+        println!(
+            "Out of order:   {} blocks ({:.1}%), {} fragments",
+            stats.out_of_order_blocks,
+            stats.out_of_order_ratio * 100.0,
+            stats.fragments
+        );
+        println!(
+            "Unused space:   {} unused blocks, {} past the last block",
+            stats.unused_slots,
+            format_size(stats.tail_bytes)
+        );
+        println!("Reclaimable:    {}", format_size(stats.reclaimable_bytes));
+    }
+
+    if !whole_chain {
+        println!("Partitions:     unknown, pass the parents with --parent");
+    }
+    if let Some(table) = partitions {
+        println!("Partitions ({:?}):", table.kind);
+        for p in &table.partitions {
+            println!(
+                "  {:>3}  {:>12}  {:>10}  {}",
+                p.number,
+                p.first_byte,
+                format_size(p.len),
+                p.type_name().unwrap_or("Unknown")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn map(cli: &Cli, path: &Path) -> anyhow::Result<()> {
+    let disk = open(path)?;
+    let block_size = disk.block_size as u64;
+
+    // Runs of blocks that are contiguous both on the disk and in the image
+    let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+    for (index, offset) in disk.block_offsets.iter().enumerate() {
+        let Some(file_offset) = *offset else {
+            continue;
+        };
+        let start = index as u64 * block_size;
+        let len = std::cmp::min(
+            block_size,
+            disk.header.disk_size.get().saturating_sub(start),
+        );
+        match runs.last_mut() {
+            Some((run_start, run_len, run_file))
+                if *run_start + *run_len == start && *run_file + *run_len == file_offset =>
+            {
+                *run_len += len;
+            }
+            _ => runs.push((start, len, file_offset)),
+        }
+    }
+
+    if cli.json {
+        let runs: Vec<_> = runs
+            .iter()
+            .map(|&(start, len, file_offset)| {
+                json!({ "offset": start, "length": len, "file_offset": file_offset })
+            })
+            .collect();
+        println!("{:#}", serde_json::Value::from(runs));
+        return Ok(());
+    }
+
+    println!("{:<20}{:<20}Mapped to", "Offset", "Length");
+    for (start, len, file_offset) in runs {
+        println!("{start:<#20x}{len:<#20x}{file_offset:#x}");
+    }
+    Ok(())
+}
+
+fn cat(path: &Path, parents: &[PathBuf], partition: Option<usize>) -> anyhow::Result<()> {
+    let disk = open_readable(path, parents)?;
+    let mut stdout = std::io::stdout().lock();
+    let result = match partition {
+        Some(number) => {
+            let part = disk
+                .partitions()?
+                .into_iter()
+                .find(|p| p.number == number)
+                .ok_or_else(|| anyhow::anyhow!("Partition {number} not found"))?;
+            std::io::copy(&mut part.slice(&disk), &mut stdout)
+        }
+        None => {
+            let mut disk = disk;
+            std::io::copy(&mut disk, &mut stdout)
+        }
+    };
+
+    match result.and_then(|_| stdout.flush()) {
+        // The reader went away, eg. `vdi cat disk.vdi | head`
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.2} {}", UNITS[unit])
+    }
+}
+
+/// Parses a size with an optional binary suffix, eg. `512M` or `20G`
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, shift) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("Unknown size suffix '{c}'")),
+            };
+            (&s[..i], shift)
+        }
+        _ => (s, 0),
+    };
+    let number: u64 = number.parse().map_err(|e| format!("{e}"))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| "Size is too large".to_string())
+}
@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use positioned_io2::WriteAt;
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+use vdi::overlay::Overlay;
+
+const BLOCK_SIZE: u32 = 64 * 1024;
+const DISK_SIZE: u64 = 16 * BLOCK_SIZE as u64;
+
+/// Writes a base image holding an MBR with one partition and data in its second block, and a
+/// differencing image of it with data in its third block. Returns their paths.
+fn write_chain(dir: &Path) -> (PathBuf, PathBuf) {
+    let base_path = dir.join("base.vdi");
+    let header = VdiHeader::new(DISK_SIZE, BLOCK_SIZE).unwrap();
+    let mut base =
+        VdiDisk::create(Box::new(File::create_new(&base_path).unwrap()), header).unwrap();
+
+    let mut mbr = [0u8; 512];
+    mbr[446 + 4] = 0x83;
+    mbr[446 + 8..446 + 12].copy_from_slice(&128u32.to_le_bytes());
+    mbr[446 + 12..446 + 16].copy_from_slice(&1024u32.to_le_bytes());
+    mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+    base.write_all_at(0, &mbr).unwrap();
+    base.write_all_at(BLOCK_SIZE as u64, &[0x11; 100]).unwrap();
+    base.flush().unwrap();
+    drop(base);
+
+    let child_path = dir.join("child.vdi");
+    let base = VdiDisk::open(Box::new(File::open(&base_path).unwrap())).unwrap();
+    let mut overlay = Overlay::new(base);
+    overlay
+        .write_all_at(2 * BLOCK_SIZE as u64, &[0x22; 100])
+        .unwrap();
+    overlay
+        .export(Box::new(File::create_new(&child_path).unwrap()))
+        .unwrap();
+    (base_path, child_path)
+}
+
+/// Runs the CLI with `args`, returning its stdout
+fn vdi(args: &[&Path]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_vdi"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "vdi {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn info_shows_differencing_images_without_their_parents() {
+    let dir = tempfile::tempdir().unwrap();
+    let (base, child) = write_chain(dir.path());
+
+    let output = vdi(&["info".as_ref(), &child]);
+    assert!(output.contains("Type:           differencing"), "{output}");
+    assert!(
+        output.contains("Allocated:      1 of 16 blocks"),
+        "{output}"
+    );
+    assert!(output.contains("Partitions:     unknown"), "{output}");
+
+    let output = vdi(&["info".as_ref(), "--stats".as_ref(), &child]);
+    assert!(
+        output.contains("Unallocated:    15 free, 0 zero blocks"),
+        "{output}"
+    );
+
+    let output = vdi(&["info".as_ref(), &child, "--parent".as_ref(), &base]);
+    assert!(!output.contains("Partitions:     unknown"), "{output}");
+    assert!(output.contains("Partitions (Mbr):"), "{output}");
+}
+
+#[test]
+fn info_json_describes_the_chain() {
+    let dir = tempfile::tempdir().unwrap();
+    let (base, child) = write_chain(dir.path());
+    let child_disk = VdiDisk::open(Box::new(File::open(&child).unwrap())).unwrap();
+
+    let output = vdi(&[
+        "--json".as_ref(),
+        "info".as_ref(),
+        "--stats".as_ref(),
+        &child,
+        "--parent".as_ref(),
+        &base,
+    ]);
+    let info: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(info["path"], child.to_str().unwrap());
+    assert_eq!(info["image_type"], "differencing");
+    assert_eq!(
+        info["uuid_image"],
+        VdiHeader::uuid_from_disk(child_disk.header.uuid_image).to_string()
+    );
+    assert_eq!(info["disk_size"], DISK_SIZE);
+    assert_eq!(info["block_size"], BLOCK_SIZE);
+    assert_eq!(info["blocks_in_image"], 16);
+    assert_eq!(info["blocks_allocated"], 1);
+    assert_eq!(info["stats"]["allocated_blocks"], 1);
+    assert_eq!(info["stats"]["free_blocks"], 15);
+
+    let partitions = info["partitions"].as_array().unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0]["number"], 1);
+    assert_eq!(partitions[0]["offset"], 128 * 512);
+    assert_eq!(partitions[0]["size"], 1024 * 512);
+    assert_eq!(partitions[0]["name"], serde_json::Value::Null);
+
+    // Without the parent the partitions are unknown
+    let output = vdi(&["info".as_ref(), &child, "--json".as_ref()]);
+    let info: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(info["partitions"], serde_json::Value::Null);
+    assert!(info.get("stats").is_none());
+}
+
+#[test]
+fn map_and_check_json_describe_the_image() {
+    let dir = tempfile::tempdir().unwrap();
+    let (base, _) = write_chain(dir.path());
+    let disk = VdiDisk::open(Box::new(File::open(&base).unwrap())).unwrap();
+
+    let output = vdi(&["--json".as_ref(), "map".as_ref(), &base]);
+    let map: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        map,
+        serde_json::json!([{
+            "offset": 0,
+            "length": 2 * BLOCK_SIZE,
+            "file_offset": disk.block_offsets[0].unwrap(),
+        }])
+    );
+
+    let output = vdi(&["--json".as_ref(), "check".as_ref(), &base]);
+    let check: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(check["ok"], true);
+    assert_eq!(check["problems"], serde_json::json!([]));
+}
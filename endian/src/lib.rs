@@ -0,0 +1,68 @@
+//! Little-endian integers for on-disk structures, correct regardless of the host byte order
+
+use bytemuck::{Pod, Zeroable};
+
+macro_rules! little_endian {
+    ($name:ident, $native:ty) => {
+        #[doc = concat!("`", stringify!($native), "` stored in little-endian byte order")]
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Pod, Zeroable)]
+        pub struct $name([u8; std::mem::size_of::<$native>()]);
+
+        impl $name {
+            pub const fn new(value: $native) -> Self {
+                Self(value.to_le_bytes())
+            }
+
+            pub const fn get(self) -> $native {
+                <$native>::from_le_bytes(self.0)
+            }
+
+            pub fn set(&mut self, value: $native) {
+                *self = Self::new(value);
+            }
+        }
+
+        impl From<$native> for $name {
+            fn from(value: $native) -> Self {
+                Self::new(value)
+            }
+        }
+
+        impl From<$name> for $native {
+            fn from(value: $name) -> Self {
+                value.get()
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Debug::fmt(&self.get(), f)
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Display::fmt(&self.get(), f)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                self.get().serialize(serializer)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                <$native>::deserialize(deserializer).map(Self::new)
+            }
+        }
+    };
+}
+
+little_endian!(U16Le, u16);
+little_endian!(U32Le, u32);
+little_endian!(U64Le, u64);
@@ -1,7 +1,8 @@
 use std::io::Read;
+use std::ops::ControlFlow;
 
 use ext4::Ext4Reader;
-use positioned_io2::ReadAt;
+use ext4::structs::DirectoryEntry;
 use vdi::VdiDisk;
 
 fn main() -> anyhow::Result<()> {
@@ -10,22 +11,21 @@ fn main() -> anyhow::Result<()> {
     };
 
     let file = std::fs::File::open(&path)?;
-    let mut disk = VdiDisk::open(Box::new(file))?;
+    let disk = VdiDisk::open(Box::new(file))?;
     println!("VDI header: {:X?}", disk.header);
 
-    let partitions = bootsector::list_partitions(&mut disk, &bootsector::Options::default())?;
+    let partitions = disk.partitions()?;
 
     for part in partitions {
-        let disk = disk.slice(part.first_byte..part.first_byte + part.len);
-        let ext4 = match Ext4Reader::new(disk) {
+        let ext4 = match Ext4Reader::new(part.slice(&disk)) {
             Ok(ext4) => ext4,
             Err(e) => {
-                eprintln!("Failed to open partition {}: {}", part.id, e);
+                eprintln!("Failed to open partition {}: {}", part.number, e);
                 continue;
             }
         };
 
-        traverse_directory(&ext4, unix_path::Path::new("/"), 0)?;
+        ext4.walk("/", print_entry, None)?;
     }
 
     let mut owned_slice = disk.slice_owned(0..4096)?;
@@ -36,33 +36,21 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn traverse_directory<R: ReadAt>(
-    reader: &Ext4Reader<R>,
-    path: &unix_path::Path,
-    indent: usize,
-) -> anyhow::Result<()> {
-    let indent_str = "  ".repeat(indent);
-    let entries = reader.read_dir(path)?;
-
-    for entry in entries {
-        let full_path = path.join(&entry.name);
-        let file_type = if entry.is_dir {
-            "DIR"
-        } else if entry.is_file {
-            "FILE"
-        } else {
-            "OTHER"
-        };
-
-        println!(
-            "{}{} {} ({} bytes)",
-            indent_str, file_type, entry.name, entry.size
-        );
-
-        if entry.is_dir {
-            traverse_directory(reader, &full_path, indent + 1)?;
-        }
-    }
+fn print_entry(entry: &DirectoryEntry, depth: usize) -> ControlFlow<()> {
+    let file_type = if entry.is_dir {
+        "DIR"
+    } else if entry.is_file {
+        "FILE"
+    } else {
+        "OTHER"
+    };
 
-    Ok(())
+    println!(
+        "{}{} {} ({} bytes)",
+        "  ".repeat(depth),
+        file_type,
+        entry.name,
+        entry.size
+    );
+    ControlFlow::Continue(())
 }
@@ -0,0 +1,58 @@
+use vdi::VdiDisk;
+use vdi::lock::LockMode;
+use vdi::nbd::{NbdOptions, NbdServer};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let writable = args.iter().any(|arg| arg == "--rw");
+    let positional: Vec<&String> = args[1..].iter().filter(|arg| *arg != "--rw").collect();
+    let [path, rest @ ..] = positional.as_slice() else {
+        anyhow::bail!(
+            "Usage: {} [--rw] <image> [<address>|<socket path>] [<partition>]",
+            args[0]
+        );
+    };
+    let address = rest.first().map_or("127.0.0.1:10809", |s| s.as_str());
+    let partition: Option<usize> = rest.get(1).map(|s| s.parse()).transpose()?;
+
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)?;
+    let mode = if writable {
+        LockMode::Exclusive
+    } else {
+        LockMode::Shared
+    };
+    let disk = VdiDisk::open_locked(file, mode)?;
+
+    let options = NbdOptions {
+        read_only: !writable,
+        ..Default::default()
+    };
+
+    if let Some(number) = partition {
+        let Some(part) = disk.partitions()?.into_iter().find(|p| p.number == number) else {
+            anyhow::bail!("Partition {number} not found");
+        };
+        let slice = disk.slice_owned(part.range())?;
+        serve(NbdServer::new(slice, options), address)
+    } else {
+        serve(NbdServer::new(disk, options), address)
+    }
+}
+
+fn serve<E: vdi::nbd::Export>(mut server: NbdServer<E>, address: &str) -> anyhow::Result<()> {
+    println!("Serving on {address}");
+    #[cfg(unix)]
+    if address.contains('/') {
+        server.serve_unix(address, |peer, e| {
+            eprintln!("NBD client {peer:?} failed: {e}")
+        })?;
+        return Ok(());
+    }
+    server.serve_tcp(address, |peer, e| {
+        eprintln!("NBD client {peer} failed: {e}")
+    })?;
+    Ok(())
+}
@@ -7,9 +7,13 @@ use crate::{
 };
 use positioned_io2::ReadAt;
 use std::io::{Read, Seek};
+use std::ops::ControlFlow;
 use thiserror::Error;
 use unix_path::{Path, PathBuf};
+use vdi_progress::{ProgressHandler, Tracker};
 
+pub use vdi_endian as endian;
+pub use vdi_progress as progress;
 pub mod structs;
 mod util;
 
@@ -27,10 +31,14 @@ pub enum Ext4Error {
     FileNotFound(String),
     #[error("Invalid directory entry")]
     InvalidDirectoryEntry,
+    #[error("Operation was cancelled")]
+    Cancelled(#[from] progress::Cancelled),
 }
 
 pub type Result<T> = std::result::Result<T, Ext4Error>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Metadata {
     pub is_file: bool,
     pub is_dir: bool,
@@ -142,15 +150,16 @@ pub struct Ext4Reader<R: ReadAt> {
 impl<R: ReadAt> Ext4Reader<R> {
     pub fn new(mut reader: R) -> Result<Self> {
         let superblock = Self::read_superblock(&mut reader)?;
-        let block_size = if superblock.s_log_block_size < 32 {
-            1024u64 << superblock.s_log_block_size
+        let block_size = if superblock.s_log_block_size.get() < 32 {
+            1024u64 << superblock.s_log_block_size.get()
         } else {
             1024u64
         };
 
         let group_count = superblock
             .s_blocks_count_lo
-            .div_ceil(superblock.s_blocks_per_group);
+            .get()
+            .div_ceil(superblock.s_blocks_per_group.get());
         let group_descriptors =
             Self::read_group_descriptors(&mut reader, group_count as usize, block_size)?;
 
@@ -162,9 +171,13 @@ impl<R: ReadAt> Ext4Reader<R> {
         })
     }
 
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
     fn read_superblock(reader: &mut R) -> Result<Superblock> {
         let s = reader.read_pod_owned::<Superblock>(1024)?;
-        if s.s_magic != EXT4_SUPER_MAGIC {
+        if s.s_magic.get() != EXT4_SUPER_MAGIC {
             return Err(Ext4Error::InvalidSuperblock);
         }
 
@@ -187,17 +200,17 @@ impl<R: ReadAt> Ext4Reader<R> {
             return Err(Ext4Error::InvalidInode(inode_num));
         }
 
-        let group = (inode_num - 1) / self.superblock.s_inodes_per_group;
-        let index = (inode_num - 1) % self.superblock.s_inodes_per_group;
+        let group = (inode_num - 1) / self.superblock.s_inodes_per_group.get();
+        let index = (inode_num - 1) % self.superblock.s_inodes_per_group.get();
 
         if group as usize >= self.group_descriptors.len() {
             return Err(Ext4Error::InvalidInode(inode_num));
         }
 
         let group_desc = &self.group_descriptors[group as usize];
-        let inode_table_block = group_desc.bg_inode_table_lo as u64;
+        let inode_table_block = group_desc.bg_inode_table_lo.get() as u64;
         let inode_offset = inode_table_block * self.block_size
-            + index as u64 * self.superblock.s_inode_size as u64;
+            + index as u64 * self.superblock.s_inode_size.get() as u64;
 
         let inode = self.reader.read_pod_owned::<Inode>(inode_offset)?;
 
@@ -206,15 +219,15 @@ impl<R: ReadAt> Ext4Reader<R> {
 
     fn read_directory_entries(&self, inode: &Inode) -> Result<Vec<DirEntry>> {
         let mut entries = Vec::new();
-        let size = ((inode.i_size_high as u64) << 32) | inode.i_size_lo as u64;
+        let size = ((inode.i_size_high.get() as u64) << 32) | inode.i_size_lo.get() as u64;
 
-        if inode.i_flags & EXT4_EXTENTS_FL != 0 {
+        if inode.i_flags.get() & EXT4_EXTENTS_FL != 0 {
             let blocks = self.read_extent_blocks(inode)?;
             for block_num in blocks {
                 self.read_directory_block(block_num, size, &mut entries)?;
             }
         } else {
-            for &block_num in &inode.i_block[0..12] {
+            for block_num in inode.i_block[0..12].iter().map(|b| b.get()) {
                 if block_num == 0 {
                     break;
                 }
@@ -227,7 +240,8 @@ impl<R: ReadAt> Ext4Reader<R> {
     }
 
     fn read_extent_blocks(&self, inode: &Inode) -> Result<Vec<u32>> {
-        self.read_extent_blocks_recursive(&inode.i_block)
+        let extent_data: Vec<u32> = inode.i_block.iter().map(|b| b.get()).collect();
+        self.read_extent_blocks_recursive(&extent_data)
     }
 
     fn read_extent_blocks_recursive(&self, extent_data: &[u32]) -> Result<Vec<u32>> {
@@ -351,7 +365,7 @@ impl<R: ReadAt> Ext4Reader<R> {
         let inode_num = self.find_inode_by_path(path)?;
         let inode = self.read_inode(inode_num)?;
 
-        if (inode.i_mode & 0xF000) != 0x4000 {
+        if (inode.i_mode.get() & 0xF000) != 0x4000 {
             return Err(Ext4Error::FileNotFound(format!(
                 "{} is not a directory",
                 path.display()
@@ -378,7 +392,8 @@ impl<R: ReadAt> Ext4Reader<R> {
             let size = if is_file {
                 match self.read_inode(entry.inode) {
                     Ok(file_inode) => {
-                        ((file_inode.i_size_high as u64) << 32) | file_inode.i_size_lo as u64
+                        ((file_inode.i_size_high.get() as u64) << 32)
+                            | file_inode.i_size_lo.get() as u64
                     }
                     Err(_) => 0,
                 }
@@ -403,19 +418,87 @@ impl<R: ReadAt> Ext4Reader<R> {
         })
     }
 
+    /// Visits the entries below the directory `path` depth first, along with their depth below
+    /// it. Returning [`ControlFlow::Break`] from `visit` ends the walk early.
+    ///
+    /// The walk is reported to `progress`, and fails with [`Ext4Error::Cancelled`] if the handler
+    /// cancels it. Its total is the
+    /// space in use on the filesystem, each entry advancing it by its size in whole blocks. Every
+    /// file counts once towards the skipped blocks, as its contents are not read. The total is
+    /// reached once the walk completes.
+    pub fn walk<P, F>(
+        &self,
+        path: P,
+        mut visit: F,
+        mut progress: Option<&mut (dyn ProgressHandler + '_)>,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&DirectoryEntry, usize) -> ControlFlow<()>,
+    {
+        let sb = &self.superblock;
+        let used_blocks = sb
+            .s_blocks_count_lo
+            .get()
+            .saturating_sub(sb.s_free_blocks_count_lo.get());
+        let mut tracker = Tracker::new(used_blocks as u64 * self.block_size);
+
+        let walked =
+            self.walk_recursive(path.as_ref(), 0, &mut visit, &mut tracker, &mut progress)?;
+        if walked.is_break() {
+            return Ok(());
+        }
+        let total = tracker.progress().bytes_total;
+        let rest = total.saturating_sub(tracker.progress().bytes_processed);
+        tracker.advance(rest, false, progress)?;
+        Ok(())
+    }
+
+    fn walk_recursive(
+        &self,
+        path: &Path,
+        depth: usize,
+        visit: &mut dyn FnMut(&DirectoryEntry, usize) -> ControlFlow<()>,
+        tracker: &mut Tracker,
+        progress: &mut Option<&mut (dyn ProgressHandler + '_)>,
+    ) -> Result<ControlFlow<()>> {
+        for entry in self.read_dir(path)? {
+            if visit(&entry, depth).is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+
+            // Capped, as hard links and sparse files are counted in full
+            let progress_so_far = tracker.progress();
+            let left = progress_so_far
+                .bytes_total
+                .saturating_sub(progress_so_far.bytes_processed);
+            let size = entry.size.next_multiple_of(self.block_size).min(left);
+            tracker.advance(size, !entry.is_dir, progress.as_deref_mut())?;
+
+            if entry.is_dir
+                && self
+                    .walk_recursive(&entry.path, depth + 1, visit, tracker, progress)?
+                    .is_break()
+            {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
     pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Ext4FileReader<'_, R>> {
         let path = path.as_ref();
         let inode_num = self.find_inode_by_path(path)?;
         let inode = self.read_inode(inode_num)?;
 
-        if (inode.i_mode & 0xF000) != 0x8000 {
+        if (inode.i_mode.get() & 0xF000) != 0x8000 {
             return Err(Ext4Error::FileNotFound(format!(
                 "{} is not a regular file",
                 path.display()
             )));
         }
 
-        let size = ((inode.i_size_high as u64) << 32) | inode.i_size_lo as u64;
+        let size = ((inode.i_size_high.get() as u64) << 32) | inode.i_size_lo.get() as u64;
 
         Ok(Ext4FileReader {
             reader: self,
@@ -434,15 +517,15 @@ impl<R: ReadAt> Ext4Reader<R> {
         let inode_num = self.find_inode_by_path(path).ok()?;
         let inode = self.read_inode(inode_num).ok()?;
 
-        let is_file = (inode.i_mode & 0xF000) == 0x8000;
-        let is_dir = (inode.i_mode & 0xF000) == 0x4000;
-        let size = ((inode.i_size_high as u64) << 32) | inode.i_size_lo as u64;
+        let is_file = (inode.i_mode.get() & 0xF000) == 0x8000;
+        let is_dir = (inode.i_mode.get() & 0xF000) == 0x4000;
+        let size = ((inode.i_size_high.get() as u64) << 32) | inode.i_size_lo.get() as u64;
 
         Some(Metadata {
             is_file,
             is_dir,
             size,
-            mode: inode.i_mode,
+            mode: inode.i_mode.get(),
         })
     }
 
@@ -463,7 +546,7 @@ impl<R: ReadAt> Ext4Reader<R> {
         for component in components {
             let inode = self.read_inode(current_inode)?;
 
-            if (inode.i_mode & 0xF000) != 0x4000 {
+            if (inode.i_mode.get() & 0xF000) != 0x4000 {
                 return Err(Ext4Error::FileNotFound(format!(
                     "Path component is not a directory: {}",
                     component
@@ -493,7 +576,7 @@ impl<R: ReadAt> Ext4Reader<R> {
     }
 
     fn read_file_data_range(&self, inode: &Inode, start: u64, length: usize) -> Result<Vec<u8>> {
-        let file_size = ((inode.i_size_high as u64) << 32) | inode.i_size_lo as u64;
+        let file_size = ((inode.i_size_high.get() as u64) << 32) | inode.i_size_lo.get() as u64;
 
         if start >= file_size {
             return Ok(Vec::new());
@@ -507,7 +590,7 @@ impl<R: ReadAt> Ext4Reader<R> {
         let start_offset = start % self.block_size;
         let mut remaining = actual_length;
 
-        if inode.i_flags & EXT4_EXTENTS_FL != 0 {
+        if inode.i_flags.get() & EXT4_EXTENTS_FL != 0 {
             let blocks = self.read_extent_blocks(inode)?;
 
             for (block_idx, &block_num) in blocks.iter().enumerate() {
@@ -538,7 +621,7 @@ impl<R: ReadAt> Ext4Reader<R> {
                 remaining -= read_size;
             }
         } else {
-            for (block_idx, &block_num) in inode.i_block[0..12].iter().enumerate() {
+            for (block_idx, block_num) in inode.i_block[0..12].iter().map(|b| b.get()).enumerate() {
                 if block_num == 0 {
                     break;
                 }
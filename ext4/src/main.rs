@@ -1,7 +1,7 @@
-use ext4::{Ext4Reader, Result};
-use positioned_io2::ReadAt;
+use ext4::{Ext4Reader, Result, structs::DirectoryEntry};
 use std::env;
 use std::fs::File;
+use std::ops::ControlFlow;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
@@ -16,39 +16,26 @@ fn main() -> Result<()> {
     let reader = Ext4Reader::new(File::open(image_path)?)?;
 
     println!("\nRoot directory contents:");
-    let root_path = unix_path::Path::new("/");
-    traverse_directory(&reader, root_path, 0)?;
+    reader.walk("/", print_entry, None)?;
 
     Ok(())
 }
 
-fn traverse_directory<R: ReadAt>(
-    reader: &Ext4Reader<R>,
-    path: &unix_path::Path,
-    indent: usize,
-) -> Result<()> {
-    let indent_str = "  ".repeat(indent);
-    let entries = reader.read_dir(path)?;
-
-    for entry in entries {
-        let full_path = path.join(&entry.name);
-        let file_type = if entry.is_dir {
-            "DIR"
-        } else if entry.is_file {
-            "FILE"
-        } else {
-            "OTHER"
-        };
-
-        println!(
-            "{}{} {} ({} bytes)",
-            indent_str, file_type, entry.name, entry.size
-        );
-
-        if entry.is_dir {
-            traverse_directory(reader, &full_path, indent + 1)?;
-        }
-    }
-
-    Ok(())
+fn print_entry(entry: &DirectoryEntry, depth: usize) -> ControlFlow<()> {
+    let file_type = if entry.is_dir {
+        "DIR"
+    } else if entry.is_file {
+        "FILE"
+    } else {
+        "OTHER"
+    };
+
+    println!(
+        "{}{} {} ({} bytes)",
+        "  ".repeat(depth),
+        file_type,
+        entry.name,
+        entry.size
+    );
+    ControlFlow::Continue(())
 }
@@ -1,6 +1,8 @@
 use bytemuck::{Pod, Zeroable};
 use unix_path::PathBuf;
 
+use crate::endian::{U16Le, U32Le};
+
 pub const EXT4_SUPER_MAGIC: u16 = 0xEF53;
 pub const EXT4_ROOT_INO: u32 = 2;
 pub const EXT4_FT_REG_FILE: u8 = 1;
@@ -15,83 +17,84 @@ pub const EXT4_N_BLOCKS: usize = EXT4_TIND_BLOCK + 1;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Superblock {
-    pub s_inodes_count: u32,
-    pub s_blocks_count_lo: u32,
-    pub s_r_blocks_count_lo: u32,
-    pub s_free_blocks_count_lo: u32,
-    pub s_free_inodes_count: u32,
-    pub s_first_data_block: u32,
-    pub s_log_block_size: u32,
-    pub s_obso_log_frag_size: u32,
-    pub s_blocks_per_group: u32,
-    pub s_obso_frags_per_group: u32,
-    pub s_inodes_per_group: u32,
-    pub s_mtime: u32,
-    pub s_wtime: u32,
-    pub s_mnt_count: u16,
-    pub s_max_mnt_count: u16,
-    pub s_magic: u16,
-    pub s_state: u16,
-    pub s_errors: u16,
-    pub s_minor_rev_level: u16,
-    pub s_lastcheck: u32,
-    pub s_checkinterval: u32,
-    pub s_creator_os: u32,
-    pub s_rev_level: u32,
-    pub s_def_resuid: u16,
-    pub s_def_resgid: u16,
-    pub s_first_ino: u32,
-    pub s_inode_size: u16,
-    pub s_block_group_nr: u16,
-    pub s_feature_compat: u32,
-    pub s_feature_incompat: u32,
-    pub s_feature_ro_compat: u32,
+    pub s_inodes_count: U32Le,
+    pub s_blocks_count_lo: U32Le,
+    pub s_r_blocks_count_lo: U32Le,
+    pub s_free_blocks_count_lo: U32Le,
+    pub s_free_inodes_count: U32Le,
+    pub s_first_data_block: U32Le,
+    pub s_log_block_size: U32Le,
+    pub s_obso_log_frag_size: U32Le,
+    pub s_blocks_per_group: U32Le,
+    pub s_obso_frags_per_group: U32Le,
+    pub s_inodes_per_group: U32Le,
+    pub s_mtime: U32Le,
+    pub s_wtime: U32Le,
+    pub s_mnt_count: U16Le,
+    pub s_max_mnt_count: U16Le,
+    pub s_magic: U16Le,
+    pub s_state: U16Le,
+    pub s_errors: U16Le,
+    pub s_minor_rev_level: U16Le,
+    pub s_lastcheck: U32Le,
+    pub s_checkinterval: U32Le,
+    pub s_creator_os: U32Le,
+    pub s_rev_level: U32Le,
+    pub s_def_resuid: U16Le,
+    pub s_def_resgid: U16Le,
+    pub s_first_ino: U32Le,
+    pub s_inode_size: U16Le,
+    pub s_block_group_nr: U16Le,
+    pub s_feature_compat: U32Le,
+    pub s_feature_incompat: U32Le,
+    pub s_feature_ro_compat: U32Le,
 }
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct GroupDescriptor {
-    pub bg_block_bitmap_lo: u32,
-    pub bg_inode_bitmap_lo: u32,
-    pub bg_inode_table_lo: u32,
-    pub bg_free_blocks_count_lo: u16,
-    pub bg_free_inodes_count_lo: u16,
-    pub bg_used_dirs_count_lo: u16,
-    pub bg_flags: u16,
-    pub bg_reserved: [u32; 2],
-    pub bg_itable_unused_lo: u16,
-    pub bg_checksum: u16,
-    pub bg_block_bitmap_hi: u32,
-    pub bg_inode_bitmap_hi: u32,
-    pub bg_inode_table_hi: u32,
-    pub bg_free_blocks_count_hi: u16,
-    pub bg_free_inodes_count_hi: u16,
-    pub bg_used_dirs_count_hi: u16,
-    pub bg_itable_unused_hi: u16,
-    pub bg_reserved2: [u32; 3],
+    pub bg_block_bitmap_lo: U32Le,
+    pub bg_inode_bitmap_lo: U32Le,
+    pub bg_inode_table_lo: U32Le,
+    pub bg_free_blocks_count_lo: U16Le,
+    pub bg_free_inodes_count_lo: U16Le,
+    pub bg_used_dirs_count_lo: U16Le,
+    pub bg_flags: U16Le,
+    pub bg_reserved: [U32Le; 2],
+    pub bg_itable_unused_lo: U16Le,
+    pub bg_checksum: U16Le,
+    pub bg_block_bitmap_hi: U32Le,
+    pub bg_inode_bitmap_hi: U32Le,
+    pub bg_inode_table_hi: U32Le,
+    pub bg_free_blocks_count_hi: U16Le,
+    pub bg_free_inodes_count_hi: U16Le,
+    pub bg_used_dirs_count_hi: U16Le,
+    pub bg_itable_unused_hi: U16Le,
+    pub bg_reserved2: [U32Le; 3],
 }
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct Inode {
-    pub i_mode: u16,
-    pub i_uid: u16,
-    pub i_size_lo: u32,
-    pub i_atime: u32,
-    pub i_ctime: u32,
-    pub i_mtime: u32,
-    pub i_dtime: u32,
-    pub i_gid: u16,
-    pub i_links_count: u16,
-    pub i_blocks_lo: u32,
-    pub i_flags: u32,
-    pub osd1: u32,
-    pub i_block: [u32; EXT4_N_BLOCKS],
-    pub i_generation: u32,
-    pub i_file_acl_lo: u32,
-    pub i_size_high: u32,
-    pub i_obso_faddr: u32,
+    pub i_mode: U16Le,
+    pub i_uid: U16Le,
+    pub i_size_lo: U32Le,
+    pub i_atime: U32Le,
+    pub i_ctime: U32Le,
+    pub i_mtime: U32Le,
+    pub i_dtime: U32Le,
+    pub i_gid: U16Le,
+    pub i_links_count: U16Le,
+    pub i_blocks_lo: U32Le,
+    pub i_flags: U32Le,
+    pub osd1: U32Le,
+    pub i_block: [U32Le; EXT4_N_BLOCKS],
+    pub i_generation: U32Le,
+    pub i_file_acl_lo: U32Le,
+    pub i_size_high: U32Le,
+    pub i_obso_faddr: U32Le,
 }
 
 #[derive(Debug, Clone)]
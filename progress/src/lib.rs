@@ -0,0 +1,132 @@
+//! Progress reporting and cooperative cancellation of long-running operations.
+//!
+//! A handler, eg. set with `VdiDisk::set_progress_handler` or passed to `Ext4Reader::walk`, is
+//! called as bulk operations advance, and can cancel them by returning [`ControlFlow::Break`].
+//! The operation then fails with a [`Cancelled`] error, see [`is_cancelled`].
+
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+/// Progress of an operation, passed to [`ProgressHandler::progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes done so far, including the skipped ones
+    pub bytes_processed: u64,
+    pub bytes_total: u64,
+    /// Blocks passed over without being read or copied, eg. unallocated ones
+    pub blocks_skipped: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Done fraction of the operation, between 0 and 1
+    pub fn fraction(&self) -> f64 {
+        if self.bytes_total == 0 {
+            return 1.0;
+        }
+        self.bytes_processed as f64 / self.bytes_total as f64
+    }
+
+    /// Remaining time, extrapolated from the rate so far. `None` until something was processed.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.bytes_processed == 0 {
+            return None;
+        }
+        let remaining = self.bytes_total.saturating_sub(self.bytes_processed);
+        Some(
+            self.elapsed
+                .mul_f64(remaining as f64 / self.bytes_processed as f64),
+        )
+    }
+}
+
+pub trait ProgressHandler {
+    /// Called as the operation advances. Returning [`ControlFlow::Break`] cancels it.
+    fn progress(&mut self, progress: &Progress) -> ControlFlow<()>;
+}
+
+impl<F: FnMut(&Progress) -> ControlFlow<()>> ProgressHandler for F {
+    fn progress(&mut self, progress: &Progress) -> ControlFlow<()> {
+        self(progress)
+    }
+}
+
+/// Error of an operation cancelled by its [`ProgressHandler`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Operation was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl From<Cancelled> for std::io::Error {
+    fn from(cancelled: Cancelled) -> Self {
+        std::io::Error::other(cancelled)
+    }
+}
+
+/// Whether `error`, or one of its causes, is a [`Cancelled`] error. Operations returning
+/// [`std::io::Error`] wrap it in one.
+pub fn is_cancelled(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        let wrapped = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref());
+        if error.is::<Cancelled>() || wrapped.is_some_and(|e| e.is::<Cancelled>()) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Keeps the progress of an operation, for reporting it to a [`ProgressHandler`]
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    progress: Progress,
+    start: Instant,
+}
+
+impl Tracker {
+    pub fn new(bytes_total: u64) -> Self {
+        Self {
+            progress: Progress {
+                bytes_processed: 0,
+                bytes_total,
+                blocks_skipped: 0,
+                elapsed: Duration::ZERO,
+            },
+            start: Instant::now(),
+        }
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Records `bytes` as processed, as a skipped block if `skipped` is set, and reports the
+    /// progress to `handler`
+    pub fn advance(
+        &mut self,
+        bytes: u64,
+        skipped: bool,
+        handler: Option<&mut (dyn ProgressHandler + '_)>,
+    ) -> Result<(), Cancelled> {
+        self.progress.bytes_processed += bytes;
+        self.progress.blocks_skipped += skipped as u64;
+        let Some(handler) = handler else {
+            return Ok(());
+        };
+
+        self.progress.elapsed = self.start.elapsed();
+        match handler.progress(&self.progress) {
+            ControlFlow::Continue(()) => Ok(()),
+            ControlFlow::Break(()) => Err(Cancelled),
+        }
+    }
+}
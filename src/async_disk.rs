@@ -0,0 +1,215 @@
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+
+use crate::header::VdiHeader;
+
+/// Async counterpart of [`crate::VdiDisk`], reading from a [`tokio::fs::File`]
+pub struct AsyncVdiDisk {
+    pub header: VdiHeader,
+    pub block_size: usize,
+    /// Absolute file offsets of each block relative to the start of the vdi file
+    pub block_offsets: Vec<Option<u64>>,
+
+    file: File,
+    position: u64,
+    /// Position of the underlying file cursor, if known
+    file_position: Option<u64>,
+    state: ReadState,
+}
+
+enum ReadState {
+    Idle,
+    Seeking(u64),
+    Reading,
+}
+
+impl AsyncVdiDisk {
+    pub async fn open(mut file: File) -> anyhow::Result<Self> {
+        let mut header_raw = vec![0u8; std::mem::size_of::<VdiHeader>()];
+        file.seek(SeekFrom::Start(0)).await?;
+        file.read_exact(&mut header_raw).await?;
+        let header = bytemuck::pod_read_unaligned::<VdiHeader>(&header_raw);
+        header.validate_standalone()?;
+
+        let mut block_offsets_raw = vec![0u8; header.block_map_size()];
+        file.seek(SeekFrom::Start(header.block_offsets_offset.get() as u64))
+            .await?;
+        file.read_exact(&mut block_offsets_raw).await?;
+        let block_offsets = header.parse_block_map(&block_offsets_raw);
+
+        Ok(Self {
+            header,
+            block_size: header.block_size.get() as usize,
+            block_offsets,
+            file,
+            position: 0,
+            file_position: None,
+            state: ReadState::Idle,
+        })
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Reads from logical position `pos` without affecting the stream position
+    pub async fn read_at(&mut self, mut pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        // The file cursor is moved around below, the stream has to seek again afterwards
+        self.file_position = None;
+
+        // The last block can extend past the end of the disk
+        let len = std::cmp::min(
+            buf.len() as u64,
+            self.header.disk_size.get().saturating_sub(pos),
+        );
+        let buf = &mut buf[..len as usize];
+        let mut total_read = 0;
+        while total_read < buf.len() {
+            let block_index = (pos / self.block_size as u64) as usize;
+            let block_offset = (pos % self.block_size as u64) as usize;
+            if block_index >= self.block_offsets.len() {
+                break; // EOF
+            }
+
+            let to_read = std::cmp::min(buf.len() - total_read, self.block_size - block_offset);
+
+            if let Some(file_offset) = self.block_offsets[block_index] {
+                self.file
+                    .seek(SeekFrom::Start(file_offset + block_offset as u64))
+                    .await?;
+                let n = self
+                    .file
+                    .read(&mut buf[total_read..total_read + to_read])
+                    .await?;
+                if n == 0 {
+                    break; // EOF
+                }
+                total_read += n;
+                pos += n as u64;
+            } else {
+                // Unallocated block
+                buf[total_read..total_read + to_read].fill(0);
+                total_read += to_read;
+                pos += to_read as u64;
+            }
+        }
+        Ok(total_read)
+    }
+
+    pub async fn read_exact_at(&mut self, mut pos: u64, mut buf: &mut [u8]) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(pos, buf).await? {
+                0 => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ));
+                }
+                n => {
+                    buf = &mut buf[n..];
+                    pos += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for AsyncVdiDisk {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let block_index = (this.position / this.block_size as u64) as usize;
+            let block_offset = (this.position % this.block_size as u64) as usize;
+            let disk_left = this.header.disk_size.get().saturating_sub(this.position);
+            if buf.remaining() == 0 || disk_left == 0 || block_index >= this.block_offsets.len() {
+                return Poll::Ready(Ok(())); // EOF
+            }
+
+            let to_read = std::cmp::min(buf.remaining(), this.block_size - block_offset);
+            let to_read = std::cmp::min(to_read as u64, disk_left) as usize;
+
+            let Some(file_offset) = this.block_offsets[block_index] else {
+                // Unallocated block
+                buf.initialize_unfilled_to(to_read).fill(0);
+                buf.advance(to_read);
+                this.position += to_read as u64;
+                return Poll::Ready(Ok(()));
+            };
+            let file_offset = file_offset + block_offset as u64;
+
+            match this.state {
+                ReadState::Idle => {
+                    if this.file_position == Some(file_offset) {
+                        this.state = ReadState::Reading;
+                    } else {
+                        Pin::new(&mut this.file).start_seek(SeekFrom::Start(file_offset))?;
+                        this.state = ReadState::Seeking(file_offset);
+                    }
+                }
+                ReadState::Seeking(target) => {
+                    let result = ready!(Pin::new(&mut this.file).poll_complete(cx));
+                    this.state = ReadState::Idle;
+                    this.file_position = Some(result?);
+                    debug_assert_eq!(this.file_position, Some(target));
+                }
+                ReadState::Reading => {
+                    let mut chunk = ReadBuf::new(buf.initialize_unfilled_to(to_read));
+                    let result = ready!(Pin::new(&mut this.file).poll_read(cx, &mut chunk));
+                    this.state = ReadState::Idle;
+                    if let Err(e) = result {
+                        this.file_position = None;
+                        return Poll::Ready(Err(e));
+                    }
+
+                    let n = chunk.filled().len();
+                    buf.advance(n);
+                    this.position += n as u64;
+                    this.file_position = Some(file_offset + n as u64);
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for AsyncVdiDisk {
+    fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        if !matches!(this.state, ReadState::Idle) {
+            return Err(std::io::Error::other(
+                "other read operation is pending, call poll_read before start_seek",
+            ));
+        }
+
+        let disk_size = this.header.disk_size.get();
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => disk_size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        };
+
+        match new_pos {
+            Some(new_pos) if new_pos <= disk_size => {
+                this.position = new_pos;
+                Ok(())
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Tried to seek beyond end of the disk",
+            )),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
@@ -0,0 +1,98 @@
+use positioned_io2::{ReadAt, WriteAt};
+
+/// Writable storage a `VdiDisk` can be opened on, eg. a [`std::fs::File`] or a `Vec<u8>`
+pub trait Storage: ReadAt + WriteAt {
+    /// Truncates or extends the storage to `len` bytes
+    fn set_len(&mut self, len: u64) -> std::io::Result<()>;
+
+    /// Waits until everything written so far is durably stored, used to order metadata updates
+    /// after the data they refer to
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Storage for std::fs::File {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        std::fs::File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl Storage for Vec<u8> {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::OutOfMemory, "Length exceeds memory")
+        })?;
+        self.resize(len, 0);
+        Ok(())
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for &mut S {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        (**self).sync()
+    }
+}
+
+/// Reads the block stored at `pos` into `buf`. A block cut off by the end of the file, eg. by an
+/// interrupted write, reads as zeros past it.
+pub(crate) fn read_block(reader: &impl ReadAt, pos: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read_at(pos + filled as u64, &mut buf[filled..]) {
+            Ok(0) => break, // EOF
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buf[filled..].fill(0);
+    Ok(())
+}
+
+/// Storage a `VdiDisk` reads its header, block map and block data from
+pub(crate) enum Backend {
+    Reader(Box<dyn ReadAt>),
+    Writer(Box<dyn Storage>),
+    #[cfg(feature = "mmap")]
+    Mmap {
+        map: memmap2::Mmap,
+        /// Shared view handed out for unallocated blocks
+        zero_block: Box<[u8]>,
+    },
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    Uring(Box<crate::uring::Ring>),
+}
+
+impl Backend {
+    pub fn writer(&mut self) -> std::io::Result<&mut dyn Storage> {
+        match self {
+            Backend::Writer(writer) => Ok(writer.as_mut()),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "VdiDisk was opened read-only",
+            )),
+        }
+    }
+}
+
+impl ReadAt for Backend {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Backend::Reader(reader) => reader.read_at(pos, buf),
+            Backend::Writer(writer) => writer.read_at(pos, buf),
+            #[cfg(feature = "mmap")]
+            Backend::Mmap { map, .. } => (&map[..]).read_at(pos, buf),
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            Backend::Uring(ring) => ring.read_at(pos, buf),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// Maximum amount of page data kept in memory, in bytes
+    pub capacity: usize,
+    /// Size of a single cached page. Must be a power of two that divides the VDI block size
+    pub page_size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 16 * 1024 * 1024,
+            page_size: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Number of pages currently held by the cache
+    pub pages: usize,
+}
+
+/// Size-bounded LRU cache of disk pages, keyed by logical page index
+pub(crate) struct BlockCache {
+    config: CacheConfig,
+    pages: HashMap<u64, CachedPage>,
+    /// Last-use tick -> page index, oldest first
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+struct CachedPage {
+    data: Box<[u8]>,
+    /// Bytes of `data` that were read, short for a page cut off by the end of the file
+    len: usize,
+    last_used: u64,
+}
+
+impl BlockCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.config.page_size
+    }
+
+    fn max_pages(&self) -> usize {
+        (self.config.capacity / self.config.page_size).max(1)
+    }
+
+    /// Returns the cached page, calling `fill` to load it on a miss. `fill` returns the number of
+    /// bytes it read, the page is cut to that length.
+    pub fn get_or_load(
+        &mut self,
+        page: u64,
+        fill: impl FnOnce(&mut [u8]) -> std::io::Result<usize>,
+    ) -> std::io::Result<&[u8]> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.pages.get_mut(&page) {
+            self.stats.hits += 1;
+            self.lru.remove(&entry.last_used);
+            self.lru.insert(tick, page);
+            entry.last_used = tick;
+            let entry = &self.pages[&page];
+            return Ok(&entry.data[..entry.len]);
+        }
+
+        self.stats.misses += 1;
+        let mut data = vec![0u8; self.config.page_size].into_boxed_slice();
+        let len = fill(&mut data)?;
+
+        while self.pages.len() >= self.max_pages() {
+            let Some((_, evicted)) = self.lru.pop_first() else {
+                break;
+            };
+            self.pages.remove(&evicted);
+            self.stats.evictions += 1;
+        }
+
+        self.lru.insert(tick, page);
+        self.pages.insert(
+            page,
+            CachedPage {
+                data,
+                len,
+                last_used: tick,
+            },
+        );
+        Ok(&self.pages[&page].data[..len])
+    }
+
+    /// Drops all pages overlapping the given logical byte range
+    pub fn invalidate_range(&mut self, range: std::ops::Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let page_size = self.config.page_size as u64;
+        let pages = range.start / page_size..=(range.end - 1) / page_size;
+        for page in pages {
+            if let Some(entry) = self.pages.remove(&page) {
+                self.lru.remove(&entry.last_used);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.lru.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            pages: self.pages.len(),
+            ..self.stats
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! VirtualBox disk encryption.
+//!
+//! Encrypted media carry a key store in their `CRYPT/KeyStore` property, holding the data
+//! encryption key (DEK) wrapped with a key derived from the password. The disk contents are
+//! encrypted sector by sector with AES-XTS, using the sector number as the tweak.
+
+use aes::cipher::consts::U16;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, BlockSizeUser, KeyInit};
+use base64::Engine;
+
+/// Size of the units the disk contents are encrypted in
+pub const SECTOR_SIZE: u64 = 512;
+
+const MAGIC: u32 = 0x454e_4353; // 'SCNE'
+const VERSION: u16 = 0x0100;
+const KEY_STORE_LEN: usize = 250;
+
+/// Decoded `CRYPT/KeyStore` property of an encrypted medium
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStore {
+    /// Cipher of the disk contents, eg. `AES-XTS256-PLAIN64`
+    pub cipher: String,
+    /// Function deriving the key from the password, eg. `PBKDF2-SHA256`
+    pub kdf: String,
+    key_len: usize,
+    dek_digest: Vec<u8>,
+    dek_digest_salt: [u8; 32],
+    dek_digest_iterations: u32,
+    dek_salt: [u8; 32],
+    dek_iterations: u32,
+    dek_encrypted: Vec<u8>,
+}
+
+impl KeyStore {
+    /// Parses the base64 encoded key store, as stored in the `.vbox` file
+    pub fn from_base64(text: &str) -> anyhow::Result<Self> {
+        let raw = base64::engine::general_purpose::STANDARD.decode(text.trim())?;
+        Self::parse(&raw)
+    }
+
+    pub fn parse(raw: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(raw.len() >= KEY_STORE_LEN, "Key store is truncated");
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+        let string_at = |offset: usize| {
+            let field = &raw[offset..offset + 32];
+            let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..len]).into_owned()
+        };
+
+        anyhow::ensure!(u32_at(0) == MAGIC, "Invalid key store magic");
+        let version = u16::from_le_bytes([raw[4], raw[5]]);
+        anyhow::ensure!(
+            version == VERSION,
+            "Unsupported key store version {version:#x}"
+        );
+
+        let key_len = u32_at(70) as usize;
+        let digest_len = u32_at(106) as usize;
+        let encrypted_len = u32_at(182) as usize;
+        anyhow::ensure!(
+            digest_len <= 32 && encrypted_len <= 64 && key_len == encrypted_len,
+            "Malformed key store"
+        );
+
+        Ok(Self {
+            cipher: string_at(6),
+            kdf: string_at(38),
+            key_len,
+            dek_digest: raw[74..74 + digest_len].to_vec(),
+            dek_digest_salt: raw[110..142].try_into().unwrap(),
+            dek_digest_iterations: u32_at(142),
+            dek_salt: raw[146..178].try_into().unwrap(),
+            dek_iterations: u32_at(178),
+            dek_encrypted: raw[186..186 + encrypted_len].to_vec(),
+        })
+    }
+
+    /// Unwraps the data encryption key with `password`. Fails if the password is wrong.
+    pub fn unlock(&self, password: &str) -> anyhow::Result<SectorCipher> {
+        let mut key = vec![0u8; self.key_len];
+        self.derive(
+            password.as_bytes(),
+            &self.dek_salt,
+            self.dek_iterations,
+            &mut key,
+        )?;
+
+        // The key is wrapped with the disk cipher itself, using a zero tweak
+        let mut dek = self.dek_encrypted.clone();
+        SectorCipher::new(&self.cipher, &key)?.decrypt(0, &mut dek);
+
+        let mut digest = vec![0u8; self.dek_digest.len()];
+        self.derive(
+            &dek,
+            &self.dek_digest_salt,
+            self.dek_digest_iterations,
+            &mut digest,
+        )?;
+        anyhow::ensure!(digest == self.dek_digest, "Wrong password");
+        SectorCipher::new(&self.cipher, &dek)
+    }
+
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        iterations: u32,
+        out: &mut [u8],
+    ) -> anyhow::Result<()> {
+        match self.kdf.as_str() {
+            "PBKDF2-SHA1" => pbkdf2::pbkdf2_hmac::<sha1::Sha1>(password, salt, iterations, out),
+            "PBKDF2-SHA256" => pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password, salt, iterations, out),
+            "PBKDF2-SHA512" => pbkdf2::pbkdf2_hmac::<sha2::Sha512>(password, salt, iterations, out),
+            other => anyhow::bail!("Unsupported key derivation function {other}"),
+        }
+        Ok(())
+    }
+}
+
+/// AES-XTS cipher of the disk contents
+#[derive(Clone)]
+pub struct SectorCipher(Keys);
+
+/// Data key and tweak key
+#[derive(Clone)]
+enum Keys {
+    Aes128(Box<[aes::Aes128; 2]>),
+    Aes256(Box<[aes::Aes256; 2]>),
+}
+
+impl SectorCipher {
+    /// `key` holds the data key followed by the tweak key, 32 bytes for `AES-XTS128-PLAIN64`
+    /// and 64 bytes for `AES-XTS256-PLAIN64`
+    pub fn new(cipher: &str, key: &[u8]) -> anyhow::Result<Self> {
+        let invalid = |_| anyhow::anyhow!("Invalid key length for {cipher}");
+        let (data, tweak) = key.split_at(key.len() / 2);
+        let keys = match cipher {
+            "AES-XTS128-PLAIN64" if key.len() == 32 => Keys::Aes128(Box::new([
+                aes::Aes128::new_from_slice(data).map_err(invalid)?,
+                aes::Aes128::new_from_slice(tweak).map_err(invalid)?,
+            ])),
+            "AES-XTS256-PLAIN64" if key.len() == 64 => Keys::Aes256(Box::new([
+                aes::Aes256::new_from_slice(data).map_err(invalid)?,
+                aes::Aes256::new_from_slice(tweak).map_err(invalid)?,
+            ])),
+            "AES-XTS128-PLAIN64" | "AES-XTS256-PLAIN64" => {
+                anyhow::bail!("Invalid key length for {cipher}")
+            }
+            other => anyhow::bail!("Unsupported cipher {other}"),
+        };
+        Ok(Self(keys))
+    }
+
+    /// Decrypts whole sectors in place, `first_sector` being the number of the first one
+    pub fn decrypt(&self, first_sector: u64, data: &mut [u8]) {
+        match &self.0 {
+            Keys::Aes128(keys) => xts(&keys[0], &keys[1], first_sector, data, false),
+            Keys::Aes256(keys) => xts(&keys[0], &keys[1], first_sector, data, false),
+        }
+    }
+
+    /// Encrypts whole sectors in place, `first_sector` being the number of the first one
+    pub fn encrypt(&self, first_sector: u64, data: &mut [u8]) {
+        match &self.0 {
+            Keys::Aes128(keys) => xts(&keys[0], &keys[1], first_sector, data, true),
+            Keys::Aes256(keys) => xts(&keys[0], &keys[1], first_sector, data, true),
+        }
+    }
+}
+
+impl std::fmt::Debug for SectorCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Keys::Aes128(_) => f.write_str("SectorCipher(AES-XTS128)"),
+            Keys::Aes256(_) => f.write_str("SectorCipher(AES-XTS256)"),
+        }
+    }
+}
+
+/// XTS over consecutive sectors. A trailing partial sector is processed on its own, its length
+/// must still be a multiple of the AES block size.
+fn xts<C: BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>>(
+    key: &C,
+    tweak_key: &C,
+    first_sector: u64,
+    data: &mut [u8],
+    encrypt: bool,
+) {
+    for (sector, chunk) in (first_sector..).zip(data.chunks_mut(SECTOR_SIZE as usize)) {
+        let mut tweak = GenericArray::from((sector as u128).to_le_bytes());
+        tweak_key.encrypt_block(&mut tweak);
+        let mut tweak = u128::from_le_bytes(tweak.into());
+
+        for block in chunk.chunks_exact_mut(16) {
+            let mask = tweak.to_le_bytes();
+            block.iter_mut().zip(mask).for_each(|(b, m)| *b ^= m);
+            let block_array = GenericArray::from_mut_slice(block);
+            if encrypt {
+                key.encrypt_block(block_array);
+            } else {
+                key.decrypt_block(block_array);
+            }
+            block.iter_mut().zip(mask).for_each(|(b, m)| *b ^= m);
+
+            // Multiply the tweak by x in GF(2^128)
+            tweak = (tweak << 1) ^ ((tweak >> 127) * 0x87);
+        }
+    }
+}
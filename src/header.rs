@@ -1,28 +1,30 @@
 use bytemuck::{Pod, Zeroable};
 use uuid::Uuid;
 
+use crate::endian::{U32Le, U64Le};
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct VdiHeader {
     pub text: [u8; 0x40],
-    pub signature: u32,
-    pub version: u32,
-    pub header_size: u32,
-    pub image_type: u32,
-    pub image_flags: u32,
+    pub signature: U32Le,
+    pub version: U32Le,
+    pub header_size: U32Le,
+    pub image_type: U32Le,
+    pub image_flags: U32Le,
     pub description: [u8; 0x100],
-    pub block_offsets_offset: u32,
-    pub data_offset: u32,
-    pub cylinders: u32, // disk geometry, unused here
-    pub heads: u32,     // disk geometry, unused here
-    pub sectors: u32,   // disk geometry, unused here
-    pub sector_size: u32,
-    pub unused1: u32,
-    pub disk_size: u64,
-    pub block_size: u32,
-    pub block_extra: u32, // unused here
-    pub blocks_in_image: u32,
-    pub blocks_allocated: u32,
+    pub block_offsets_offset: U32Le,
+    pub data_offset: U32Le,
+    pub cylinders: U32Le, // disk geometry, unused here
+    pub heads: U32Le,     // disk geometry, unused here
+    pub sectors: U32Le,   // disk geometry, unused here
+    pub sector_size: U32Le,
+    pub unused1: U32Le,
+    pub disk_size: U64Le,
+    pub block_size: U32Le,
+    pub block_extra: U32Le, // unused here
+    pub blocks_in_image: U32Le,
+    pub blocks_allocated: U32Le,
     pub uuid_image: Uuid,
     pub uuid_last_snap: Uuid,
     pub uuid_link: Uuid,
@@ -32,4 +34,124 @@ pub struct VdiHeader {
 impl VdiHeader {
     pub const VERSION: u32 = 0x00010001;
     pub const SIGNATURE: u32 = 0xBEDA107F;
+    /// Block map entry of a block that has never been written
+    pub const BLOCK_FREE: u32 = u32::MAX;
+    /// Block map entry of a block that has been discarded and reads as zeros
+    pub const BLOCK_ZERO: u32 = u32::MAX - 1;
+    /// Image type of images storing their blocks as they are written
+    pub const TYPE_DYNAMIC: u32 = 1;
+    /// Image type of images storing the changes to a parent image
+    pub const TYPE_DIFFERENCING: u32 = 4;
+    /// Default block size of images created by VirtualBox
+    pub const DEFAULT_BLOCK_SIZE: u32 = 1024 * 1024;
+    /// Alignment of the block map and block data in images created by VirtualBox
+    const DATA_ALIGN: u32 = 1024 * 1024;
+
+    /// Creates the header of an empty dynamic image with fresh UUIDs
+    pub fn new(disk_size: u64, block_size: u32) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            block_size.is_power_of_two() && block_size >= 512,
+            "Block size must be a power of two of at least 512 bytes"
+        );
+        let blocks_in_image = u32::try_from(disk_size.div_ceil(block_size as u64))
+            .ok()
+            .filter(|&blocks| blocks < Self::BLOCK_ZERO)
+            .ok_or_else(|| anyhow::anyhow!("Disk size is too large for the block size"))?;
+
+        let mut header = Self::zeroed();
+        let text = b"<<< Oracle VM VirtualBox Disk Image >>>\n";
+        header.text[..text.len()].copy_from_slice(text);
+        header.signature.set(Self::SIGNATURE);
+        header.version.set(Self::VERSION);
+        header.header_size.set(0x190);
+        header.image_type.set(Self::TYPE_DYNAMIC);
+        header.sector_size.set(512);
+        header.disk_size.set(disk_size);
+        header.block_size.set(block_size);
+        header.blocks_in_image.set(blocks_in_image);
+        header.uuid_image = Self::uuid_to_disk(Uuid::new_v4());
+        header.uuid_last_snap = Self::uuid_to_disk(Uuid::new_v4());
+
+        header.block_offsets_offset.set(Self::DATA_ALIGN);
+        header.data_offset.set(u32::try_from(
+            header
+                .block_map_end()
+                .next_multiple_of(Self::DATA_ALIGN as u64),
+        )?);
+        Ok(header)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.version.get() == Self::VERSION,
+            "Unsupported VDI version"
+        );
+        anyhow::ensure!(
+            self.signature.get() == Self::SIGNATURE,
+            "Invalid VDI signature"
+        );
+        anyhow::ensure!(
+            matches!(
+                self.image_type.get(),
+                Self::TYPE_DYNAMIC | Self::TYPE_DIFFERENCING
+            ),
+            "Only dynamic and differencing VDI images are supported"
+        );
+        Ok(())
+    }
+
+    /// Like [`VdiHeader::validate`], but also rejects differencing images, for readers that can
+    /// not attach the parent their unallocated blocks read from
+    pub fn validate_standalone(&self) -> anyhow::Result<()> {
+        self.validate()?;
+        anyhow::ensure!(
+            self.image_type.get() != Self::TYPE_DIFFERENCING,
+            "Differencing images can only be read with their parent, see VdiDisk::set_parent"
+        );
+        Ok(())
+    }
+
+    /// Size of the on-disk block map in bytes
+    pub fn block_map_size(&self) -> usize {
+        self.blocks_in_image.get() as usize * 4
+    }
+
+    /// End of the block map in the file
+    pub(crate) fn block_map_end(&self) -> u64 {
+        self.block_offsets_offset.get() as u64 + self.block_map_size() as u64
+    }
+
+    /// Converts a UUID field from VirtualBox' mixed-endian on-disk layout
+    pub fn uuid_from_disk(raw: Uuid) -> Uuid {
+        Uuid::from_bytes_le(*raw.as_bytes())
+    }
+
+    /// Converts a UUID to VirtualBox' mixed-endian on-disk layout
+    pub fn uuid_to_disk(uuid: Uuid) -> Uuid {
+        Uuid::from_bytes(uuid.to_bytes_le())
+    }
+
+    /// Decodes the raw on-disk block map into its entries
+    pub(crate) fn block_map_entries(raw: &[u8]) -> impl Iterator<Item = u32> + '_ {
+        raw.chunks_exact(4).map(|chunk| {
+            u32::from_le_bytes(
+                chunk
+                    .try_into()
+                    .expect("unreachable: chunk is exactly 4 bytes"),
+            )
+        })
+    }
+
+    /// Converts the raw on-disk block map into absolute file offsets of each block
+    pub(crate) fn parse_block_map(&self, raw: &[u8]) -> Vec<Option<u64>> {
+        Self::block_map_entries(raw)
+            .map(|loc| {
+                if loc >= Self::BLOCK_ZERO {
+                    None
+                } else {
+                    Some(self.data_offset.get() as u64 + loc as u64 * self.block_size.get() as u64)
+                }
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,233 @@
+use uuid::Uuid;
+
+use crate::VdiDisk;
+use crate::header::VdiHeader;
+use crate::progress::Tracker;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ImageType {
+    Dynamic,
+    Fixed,
+    Undo,
+    Differencing,
+    Unknown(u32),
+}
+
+impl From<u32> for ImageType {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => ImageType::Dynamic,
+            2 => ImageType::Fixed,
+            3 => ImageType::Undo,
+            4 => ImageType::Differencing,
+            other => ImageType::Unknown(other),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageType::Dynamic => f.write_str("dynamic"),
+            ImageType::Fixed => f.write_str("fixed"),
+            ImageType::Undo => f.write_str("undo"),
+            ImageType::Differencing => f.write_str("differencing"),
+            ImageType::Unknown(value) => write!(f, "unknown ({value})"),
+        }
+    }
+}
+
+/// Legacy CHS geometry stored in the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Geometry {
+    pub cylinders: u32,
+    pub heads: u32,
+    pub sectors: u32,
+    pub sector_size: u32,
+}
+
+/// Decoded summary of a VDI header, eg. for display or inventory purposes
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VdiInfo {
+    /// Banner at the start of the file, usually `<<< Oracle VM VirtualBox Disk Image >>>`
+    pub text: String,
+    pub description: String,
+    pub version_major: u16,
+    pub version_minor: u16,
+    pub image_type: ImageType,
+    pub image_flags: u32,
+    pub uuid_image: Uuid,
+    pub uuid_last_snap: Uuid,
+    pub uuid_link: Uuid,
+    /// Image this one is a differencing image of, from `uuid_link`
+    pub uuid_parent: Option<Uuid>,
+    /// Last snapshot UUID of the parent when this image was created
+    pub uuid_parent_modification: Uuid,
+    pub geometry: Geometry,
+    pub disk_size: u64,
+    pub block_size: u32,
+    pub blocks_in_image: u32,
+    pub blocks_allocated: u32,
+    /// Bytes of block data stored in the image
+    pub allocated_size: u64,
+    /// Fraction of the blocks that are allocated, between 0 and 1
+    pub allocated_ratio: f64,
+}
+
+impl VdiHeader {
+    pub fn info(&self) -> VdiInfo {
+        let parent = VdiHeader::uuid_from_disk(self.uuid_link);
+        VdiInfo {
+            text: decode_text(&self.text),
+            description: decode_text(&self.description),
+            version_major: (self.version.get() >> 16) as u16,
+            version_minor: self.version.get() as u16,
+            image_type: self.image_type.get().into(),
+            image_flags: self.image_flags.get(),
+            uuid_image: VdiHeader::uuid_from_disk(self.uuid_image),
+            uuid_last_snap: VdiHeader::uuid_from_disk(self.uuid_last_snap),
+            uuid_link: VdiHeader::uuid_from_disk(self.uuid_link),
+            uuid_parent: (self.image_type.get() == VdiHeader::TYPE_DIFFERENCING).then_some(parent),
+            uuid_parent_modification: VdiHeader::uuid_from_disk(self.uuid_parent),
+            geometry: Geometry {
+                cylinders: self.cylinders.get(),
+                heads: self.heads.get(),
+                sectors: self.sectors.get(),
+                sector_size: self.sector_size.get(),
+            },
+            disk_size: self.disk_size.get(),
+            block_size: self.block_size.get(),
+            blocks_in_image: self.blocks_in_image.get(),
+            blocks_allocated: self.blocks_allocated.get(),
+            allocated_size: self.blocks_allocated.get() as u64 * self.block_size.get() as u64,
+            allocated_ratio: if self.blocks_in_image.get() == 0 {
+                0.0
+            } else {
+                self.blocks_allocated.get() as f64 / self.blocks_in_image.get() as f64
+            },
+        }
+    }
+}
+
+impl VdiDisk {
+    pub fn info(&self) -> VdiInfo {
+        self.header.info()
+    }
+}
+
+/// Allocation and fragmentation of an image, to decide whether it is worth compacting or
+/// repacking
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VdiStats {
+    /// Blocks with data stored in the image
+    pub allocated_blocks: u32,
+    /// Blocks never written, which read as zeros or from the parent of a differencing image
+    pub free_blocks: u32,
+    /// Blocks marked as zero, eg. after being discarded
+    pub zero_blocks: u32,
+    /// Allocated blocks containing only zeros, which compaction releases
+    pub zeroed_allocated_blocks: u32,
+    /// Fraction of the allocated blocks containing only zeros, between 0 and 1
+    pub zeroed_allocated_ratio: f64,
+    /// Allocated blocks not stored where they would be if the image was in logical order
+    pub out_of_order_blocks: u32,
+    /// Fraction of the allocated blocks out of order, between 0 and 1
+    pub out_of_order_ratio: f64,
+    /// Runs of blocks contiguous both on the disk and in the image, as listed by `vdi map`
+    pub fragments: u32,
+    /// Block slots counted as allocated by the header but not referenced by the block map
+    pub unused_slots: u32,
+    /// Bytes of the file past the last allocated block
+    pub tail_bytes: u64,
+    /// Bytes [`VdiDisk::compact`] would reclaim
+    pub reclaimable_bytes: u64,
+}
+
+impl VdiDisk {
+    /// Computes allocation statistics of the image, `file_len` being the size of the image file.
+    ///
+    /// This reads every allocated block to find those containing only zeros, reporting the
+    /// progress to the handler set with [`VdiDisk::set_progress_handler`].
+    pub fn stats(&self, file_len: u64) -> std::io::Result<VdiStats> {
+        let header = &self.header;
+        let block_size = header.block_size.get() as u64;
+        let data_offset = header.data_offset.get() as u64;
+
+        let (mut free_blocks, mut zero_blocks) = (0, 0);
+        for entry in self.read_block_map()? {
+            match entry {
+                VdiHeader::BLOCK_FREE => free_blocks += 1,
+                VdiHeader::BLOCK_ZERO => zero_blocks += 1,
+                _ => {}
+            }
+        }
+
+        let mut allocated_blocks = 0;
+        let mut zeroed_allocated_blocks = 0;
+        let mut out_of_order_blocks = 0;
+        let mut fragments = 0;
+        let mut previous: Option<(usize, u64)> = None;
+        let mut slots_used = vec![false; header.blocks_allocated.get() as usize];
+        let mut tracker = Tracker::new(self.block_offsets.len() as u64 * block_size);
+        self.scan_blocks(|index, block| {
+            let (Some(offset), Some(block)) = (self.block_offsets[index], block) else {
+                return self.advance(&mut tracker, block_size, true);
+            };
+
+            let slot = (offset - data_offset) / block_size;
+            if slot != allocated_blocks as u64 {
+                out_of_order_blocks += 1;
+            }
+            if previous != Some((index.wrapping_sub(1), offset.wrapping_sub(block_size))) {
+                fragments += 1;
+            }
+            previous = Some((index, offset));
+            if let Some(used) = slots_used.get_mut(slot as usize) {
+                *used = true;
+            }
+
+            if block.iter().all(|&b| b == 0) {
+                zeroed_allocated_blocks += 1;
+            }
+            allocated_blocks += 1;
+            self.advance(&mut tracker, block_size, false)
+        })?;
+
+        let unused_slots = slots_used.iter().filter(|&&used| !used).count() as u32;
+        let data_end = data_offset + header.blocks_allocated.get() as u64 * block_size;
+        let tail_bytes = file_len.saturating_sub(data_end);
+        let ratio = |count: u32| {
+            if allocated_blocks == 0 {
+                0.0
+            } else {
+                count as f64 / allocated_blocks as f64
+            }
+        };
+
+        Ok(VdiStats {
+            allocated_blocks,
+            free_blocks,
+            zero_blocks,
+            zeroed_allocated_blocks,
+            zeroed_allocated_ratio: ratio(zeroed_allocated_blocks),
+            out_of_order_blocks,
+            out_of_order_ratio: ratio(out_of_order_blocks),
+            fragments,
+            unused_slots,
+            tail_bytes,
+            reclaimable_bytes: (zeroed_allocated_blocks + unused_slots) as u64 * block_size
+                + tail_bytes,
+        })
+    }
+}
+
+/// Decodes a NUL-padded text field of the header
+fn decode_text(raw: &[u8]) -> String {
+    let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..len]).trim_end().to_string()
+}
@@ -0,0 +1,190 @@
+//! Side journal making multi-block metadata updates, like compaction or resizing, atomic.
+//!
+//! A transaction is written and synced to the journal before the image is touched. If the
+//! process dies while it is being applied, [`recover`] replays it on the next open. A journal
+//! whose record is incomplete is discarded, the image was not modified yet.
+
+use positioned_io2::ReadAt;
+
+use crate::backend::Storage;
+use crate::header::VdiHeader;
+
+const MAGIC: [u8; 8] = *b"VDIJRNL1";
+const HEADER_LEN: u64 = 24;
+/// Offset of the stage marker, which is updated in place and not covered by the checksum
+const STAGE_OFFSET: u64 = 20;
+/// The block moves are done and must not be repeated, their sources may be overwritten
+const STAGE_MOVED: u32 = 1;
+
+/// Metadata update of an image, applied in an order that can be replayed after a crash
+pub(crate) struct Transaction {
+    /// Block copies as (source, target) absolute file offsets. No target is also a source, so
+    /// the copies can be repeated.
+    pub moves: Vec<(u64, u64)>,
+    pub header: VdiHeader,
+    pub block_map: Vec<u32>,
+    /// Length the image is truncated to once the map is written
+    pub file_len: Option<u64>,
+}
+
+impl Transaction {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = bytemuck::bytes_of(&self.header).to_vec();
+        payload.extend_from_slice(&self.file_len.unwrap_or(0).to_le_bytes());
+        payload.extend_from_slice(&(self.moves.len() as u64).to_le_bytes());
+        for (source, target) in &self.moves {
+            payload.extend_from_slice(&source.to_le_bytes());
+            payload.extend_from_slice(&target.to_le_bytes());
+        }
+        payload.extend(self.block_map.iter().flat_map(|e| e.to_le_bytes()));
+
+        let mut record = MAGIC.to_vec();
+        record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&0u32.to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    /// Parses the payload of a journal record, `None` if it is malformed
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let header_len = std::mem::size_of::<VdiHeader>();
+        let header: VdiHeader = bytemuck::pod_read_unaligned(payload.get(..header_len)?);
+        let mut rest = &payload[header_len..];
+        let mut next_u64 = || {
+            let (value, tail) = rest.split_first_chunk::<8>()?;
+            rest = tail;
+            Some(u64::from_le_bytes(*value))
+        };
+
+        let file_len = Some(next_u64()?).filter(|&len| len != 0);
+        let count = next_u64()?;
+        let moves = (0..count)
+            .map(|_| Some((next_u64()?, next_u64()?)))
+            .collect::<Option<Vec<_>>>()?;
+        if rest.len() != header.blocks_in_image.get() as usize * 4 {
+            return None;
+        }
+
+        Some(Self {
+            moves,
+            header,
+            block_map: VdiHeader::block_map_entries(rest).collect(),
+            file_len,
+        })
+    }
+
+    /// Applies the transaction to `image`, recording its progress in `journal` if there is one.
+    /// `before_move` is called before each block move, an error from it stops the transaction
+    /// with only the earlier moves done.
+    pub fn apply(
+        &self,
+        image: &mut dyn Storage,
+        mut journal: Option<&mut dyn Storage>,
+        stage: u32,
+        before_move: &mut dyn FnMut() -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        if stage < STAGE_MOVED {
+            let mut block = vec![0u8; self.header.block_size.get() as usize];
+            for &(source, target) in &self.moves {
+                before_move()?;
+                image.read_exact_at(source, &mut block)?;
+                image.write_all_at(target, &block)?;
+            }
+            image.sync()?;
+            if let Some(journal) = journal.as_deref_mut() {
+                journal.write_all_at(STAGE_OFFSET, &STAGE_MOVED.to_le_bytes())?;
+                journal.sync()?;
+            }
+        }
+
+        let raw: Vec<u8> = self
+            .block_map
+            .iter()
+            .flat_map(|e| e.to_le_bytes())
+            .collect();
+        image.write_all_at(self.header.block_offsets_offset.get() as u64, &raw)?;
+        image.sync()?;
+        image.write_all_at(0, bytemuck::bytes_of(&self.header))?;
+        if let Some(len) = self.file_len {
+            image.set_len(len)?;
+        }
+        image.sync()?;
+
+        if let Some(journal) = journal {
+            clear(journal)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `transaction` to the journal, it must be applied with [`Transaction::apply`] next
+pub(crate) fn begin(journal: &mut dyn Storage, transaction: &Transaction) -> std::io::Result<()> {
+    journal.set_len(0)?;
+    journal.write_all_at(0, &transaction.encode())?;
+    journal.sync()
+}
+
+fn clear(journal: &mut dyn Storage) -> std::io::Result<()> {
+    journal.set_len(0)?;
+    journal.sync()
+}
+
+/// Finishes the transaction left in `journal` by an interrupted process, if any, and empties the
+/// journal. Returns whether a transaction was replayed.
+pub(crate) fn recover(image: &mut dyn Storage, journal: &mut dyn Storage) -> anyhow::Result<bool> {
+    let mut head = [0u8; HEADER_LEN as usize];
+    let mut read = 0;
+    while read < head.len() {
+        match journal.read_at(read as u64, &mut head[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    let transaction = if read == head.len() && head[..8] == MAGIC {
+        let len = u64::from_le_bytes(head[8..16].try_into().unwrap());
+        let crc = u32::from_le_bytes(head[16..20].try_into().unwrap());
+        let stage = u32::from_le_bytes(head[20..24].try_into().unwrap());
+
+        // A length beyond the end of the journal is a torn record, not something to allocate
+        let mut last = [0u8; 1];
+        let complete = match HEADER_LEN.checked_add(len) {
+            Some(end) => journal.read_at(end - 1, &mut last)? == 1,
+            None => false,
+        };
+
+        match usize::try_from(len) {
+            Ok(len) if complete => {
+                let mut payload = vec![0u8; len];
+                match journal.read_exact_at(HEADER_LEN, &mut payload) {
+                    Ok(()) if crc32fast::hash(&payload) == crc => {
+                        let transaction = Transaction::decode(&payload)
+                            .ok_or_else(|| anyhow::anyhow!("Malformed journal record"))?;
+                        Some((transaction, stage))
+                    }
+                    // The record was not completely written, the image is untouched
+                    Ok(()) => None,
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    match transaction {
+        Some((transaction, stage)) => {
+            transaction.apply(image, Some(journal), stage, &mut || Ok(()))?;
+            Ok(true)
+        }
+        None => {
+            if read > 0 {
+                clear(journal)?;
+            }
+            Ok(false)
+        }
+    }
+}
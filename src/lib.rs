@@ -1,12 +1,39 @@
-use positioned_io2::ReadAt;
+use positioned_io2::{ReadAt, WriteAt};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::{Read, Write};
+use std::sync::Mutex;
 use util::ReaderExt;
 
-use crate::header::VdiHeader;
+use crate::backend::Backend;
+pub use crate::backend::Storage;
+use crate::cache::{BlockCache, CacheConfig, CacheStats};
 
+#[cfg(feature = "tokio")]
+pub mod async_disk;
+mod backend;
+pub mod cache;
+#[cfg(feature = "crypt")]
+pub mod crypt;
+pub use vdi_endian as endian;
 pub mod header;
+pub mod info;
+mod journal;
+pub mod lock;
+pub mod nbd;
+#[cfg(feature = "ova")]
+pub mod ova;
+pub mod overlay;
+pub mod partitions;
+pub use vdi_progress as progress;
+pub mod segmented;
 pub mod slice;
+pub mod stream;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
 mod util;
+#[cfg(feature = "vbox")]
+pub mod vbox;
 
 pub struct VdiDisk {
     pub header: header::VdiHeader,
@@ -14,64 +41,1195 @@ pub struct VdiDisk {
     /// Absolute file offsets of each block relative to the start of the vdi file
     pub block_offsets: Vec<Option<u64>>,
 
-    reader: Box<dyn ReadAt>,
+    backend: Backend,
     position: u64,
+    cache: Option<Mutex<BlockCache>>,
+    journal: Option<Box<dyn Storage>>,
+    ordered_writes: bool,
+    lock_mode: Option<lock::LockMode>,
+    /// Image unallocated blocks are read from, for differencing images
+    parent: Option<Box<VdiDisk>>,
+    /// Blocks marked as zero in the block map, which read as zeros rather than from the parent
+    zero_blocks: Vec<bool>,
+    #[cfg(feature = "crypt")]
+    cipher: Option<crypt::SectorCipher>,
+    progress: RefCell<Option<Box<dyn progress::ProgressHandler>>>,
 }
 
 impl VdiDisk {
-    pub fn open<R: ReadAt + 'static>(mut reader: Box<R>) -> anyhow::Result<Self> {
-        let header = reader.read_pod_at::<header::VdiHeader>(0)?;
+    pub fn open<R: ReadAt + 'static>(reader: Box<R>) -> anyhow::Result<Self> {
+        Self::from_backend(Backend::Reader(reader))
+    }
+
+    /// Opens a VDI for reading and writing
+    pub fn open_writable<S: Storage + 'static>(storage: Box<S>) -> anyhow::Result<Self> {
+        Self::from_backend(Backend::Writer(storage))
+    }
+
+    /// Opens a VDI for reading and writing, using `journal` to make [`VdiDisk::compact`] and
+    /// [`VdiDisk::resize`] atomic.
+    ///
+    /// The journal is a small side file, eg. `disk.vdi.journal`, that is empty when no operation
+    /// is in progress. An operation interrupted by a crash is completed here, or rolled back if
+    /// it had not started modifying the image yet.
+    pub fn open_journaled<S: Storage + 'static, J: Storage + 'static>(
+        mut storage: Box<S>,
+        mut journal: Box<J>,
+    ) -> anyhow::Result<Self> {
+        journal::recover(storage.as_mut(), journal.as_mut())?;
+        let mut disk = Self::from_backend(Backend::Writer(storage))?;
+        disk.journal = Some(journal);
+        Ok(disk)
+    }
+
+    /// Creates an empty image described by `header` on `storage`, overwriting its contents.
+    /// See [`header::VdiHeader::new`].
+    pub fn create<S: Storage + 'static>(
+        mut storage: Box<S>,
+        header: header::VdiHeader,
+    ) -> anyhow::Result<Self> {
+        header.validate()?;
         anyhow::ensure!(
-            header.version == VdiHeader::VERSION,
-            "Unsupported VDI version"
+            header.block_offsets_offset.get() as usize >= std::mem::size_of::<header::VdiHeader>()
+                && header.block_map_end() <= header.data_offset.get() as u64,
+            "Block map overlaps the header or block data"
         );
+
+        storage.set_len(0)?;
+        storage.set_len(header.data_offset.get() as u64)?;
+        storage.write_all_at(0, bytemuck::bytes_of(&header))?;
+        let block_map = header::VdiHeader::BLOCK_FREE
+            .to_le_bytes()
+            .repeat(header.blocks_in_image.get() as usize);
+        storage.write_all_at(header.block_offsets_offset.get() as u64, &block_map)?;
+        storage.flush()?;
+
+        Self::open_writable(storage)
+    }
+
+    /// Locks `file` and opens it, read-only with a [`lock::LockMode::Shared`] lock or for writing
+    /// with a [`lock::LockMode::Exclusive`] one. Fails if another process holds a conflicting
+    /// lock, see [`lock::lock_file`].
+    pub fn open_locked(file: std::fs::File, mode: lock::LockMode) -> anyhow::Result<Self> {
+        lock::lock_file(&file, mode)?;
+        let mut disk = match mode {
+            lock::LockMode::Shared => Self::open(Box::new(file))?,
+            lock::LockMode::Exclusive => Self::open_writable(Box::new(file))?,
+        };
+        disk.lock_mode = Some(mode);
+        Ok(disk)
+    }
+
+    /// Lock held on the image file, if it was opened with [`VdiDisk::open_locked`]
+    pub fn lock_mode(&self) -> Option<lock::LockMode> {
+        self.lock_mode
+    }
+
+    /// Attaches the image a differencing image is based on, so its unallocated blocks are read
+    /// from it. `parent` may itself have a parent. Until it is attached, reading or writing those
+    /// blocks fails.
+    pub fn set_parent(&mut self, parent: VdiDisk) -> anyhow::Result<()> {
         anyhow::ensure!(
-            header.signature == VdiHeader::SIGNATURE,
-            "Invalid VDI signature"
+            self.header.image_type.get() == header::VdiHeader::TYPE_DIFFERENCING,
+            "Only differencing images have a parent"
         );
+        // VirtualBox links a differencing image to its parent through `uuid_link`
         anyhow::ensure!(
-            header.image_type == 1,
-            "Only dynamic VDI images are supported"
+            self.header.uuid_link == parent.header.uuid_image,
+            "Image {} is not the parent of this image",
+            header::VdiHeader::uuid_from_disk(parent.header.uuid_image)
         );
+        anyhow::ensure!(
+            parent.block_size == self.block_size,
+            "Parent image has a different block size"
+        );
+        self.parent = Some(Box::new(parent));
+        self.invalidate_cache();
+        Ok(())
+    }
 
-        let mut block_offsets_raw = vec![0u8; header.blocks_in_image as usize * 4];
-        reader.read_exact_at(header.block_offsets_offset as u64, &mut block_offsets_raw)?;
-        let block_offsets: Vec<Option<u64>> = block_offsets_raw
-            .chunks_exact(4)
-            .map(|chunk| {
-                let loc = u32::from_le_bytes(
-                    chunk
-                        .try_into()
-                        .expect("unreachable: chunk is exactly 4 bytes"),
-                );
-                if loc == u32::MAX {
-                    None
-                } else {
-                    Some(header.data_offset as u64 + loc as u64 * header.block_size as u64)
-                }
-            })
+    /// Image this differencing image is based on, see [`VdiDisk::set_parent`]
+    pub fn parent(&self) -> Option<&VdiDisk> {
+        self.parent.as_deref()
+    }
+
+    /// Whether block `index` is not stored in this differencing image and reads from the parent,
+    /// attached or not
+    fn reads_parent(&self, index: usize) -> bool {
+        self.header.image_type.get() == header::VdiHeader::TYPE_DIFFERENCING
+            && self.block_offsets[index].is_none()
+            && !self.zero_blocks[index]
+    }
+
+    /// Whether block `index` reads as zeros without being stored, in this image or its parents
+    pub(crate) fn is_hole(&self, index: usize) -> bool {
+        match self.parent.as_deref() {
+            Some(parent) if self.reads_parent(index) => {
+                index >= parent.block_offsets.len() || parent.is_hole(index)
+            }
+            // The contents are unknown until the parent is attached
+            None if self.reads_parent(index) => false,
+            _ => self.block_offsets[index].is_none(),
+        }
+    }
+
+    /// Reads `buf` at `pos` from the parent, past its end the disk reads as zeros. Fails if no
+    /// parent is attached.
+    fn read_parent(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let parent = self.parent.as_deref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Block is stored in the parent image, which is not attached",
+            )
+        })?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match parent.read_at(pos + filled as u64, &mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        buf[filled..].fill(0);
+        Ok(())
+    }
+
+    /// Decrypts the disk contents with `cipher`, see [`crypt::KeyStore::unlock`]. Unallocated
+    /// blocks still read as zeros, and copies made with [`VdiDisk::clone_to`] or
+    /// [`VdiDisk::repack_to`] are written decrypted. Encrypted disks can only be read.
+    #[cfg(feature = "crypt")]
+    pub fn set_encryption(&mut self, cipher: crypt::SectorCipher) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.is_writable(),
+            "Encrypted images can only be opened read-only"
+        );
+        anyhow::ensure!(
+            (self.block_size as u64).is_multiple_of(crypt::SECTOR_SIZE),
+            "Block size is not a multiple of the encryption sector size"
+        );
+        self.cipher = Some(cipher);
+        Ok(())
+    }
+
+    /// Whether the disk contents are decrypted, see [`VdiDisk::set_encryption`]
+    #[cfg(feature = "crypt")]
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Reports the progress of bulk operations to `handler`, which can cancel them. These are
+    /// [`VdiDisk::compact`], [`VdiDisk::resize`], [`VdiDisk::repack`], [`VdiDisk::repack_to`],
+    /// [`VdiDisk::clone_to`], [`VdiDisk::discard`], [`VdiDisk::for_each_block`] and
+    /// [`VdiDisk::stats`]. See [`progress`].
+    pub fn set_progress_handler(&mut self, handler: impl progress::ProgressHandler + 'static) {
+        *self.progress.get_mut() = Some(Box::new(handler));
+    }
+
+    /// Removes the handler set with [`VdiDisk::set_progress_handler`]
+    pub fn take_progress_handler(&mut self) -> Option<Box<dyn progress::ProgressHandler>> {
+        self.progress.get_mut().take()
+    }
+
+    /// Advances `tracker` and reports to the progress handler, fails if it cancels the operation
+    pub(crate) fn advance(
+        &self,
+        tracker: &mut progress::Tracker,
+        bytes: u64,
+        skipped: bool,
+    ) -> std::io::Result<()> {
+        let mut handler = self.progress.borrow_mut();
+        let handler = handler
+            .as_mut()
+            .map(|h| h.as_mut() as &mut dyn progress::ProgressHandler);
+        tracker.advance(bytes, skipped, handler)?;
+        Ok(())
+    }
+
+    pub fn is_writable(&self) -> bool {
+        matches!(self.backend, Backend::Writer(_))
+    }
+
+    /// Syncs the storage between writing the data of a new block, its block map entry and the
+    /// header, so a crash never leaves the map pointing at data that was not written.
+    ///
+    /// This is off by default, as it costs a few syncs for every allocated block.
+    pub fn set_ordered_writes(&mut self, enabled: bool) {
+        self.ordered_writes = enabled;
+    }
+
+    /// Waits for the previous writes to reach the storage when ordered writes are enabled
+    fn write_barrier(&mut self) -> std::io::Result<()> {
+        if self.ordered_writes {
+            self.backend.writer()?.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Opens a VDI through a read-only memory mapping of `file`
+    ///
+    /// # Safety
+    /// The file must not be modified, by this or any other process, while the disk is alive.
+    /// See [`memmap2::Mmap::map`].
+    #[cfg(feature = "mmap")]
+    pub unsafe fn open_mmap(file: &std::fs::File) -> anyhow::Result<Self> {
+        let map = unsafe { memmap2::Mmap::map(file)? };
+        let mut disk = Self::from_backend(Backend::Mmap {
+            map,
+            zero_block: Box::default(),
+        })?;
+        if let Backend::Mmap { zero_block, .. } = &mut disk.backend {
+            *zero_block = vec![0u8; disk.block_size].into_boxed_slice();
+        }
+        Ok(disk)
+    }
+
+    /// Opens a VDI read-only on an io_uring of `queue_depth` entries. Bulk operations reading
+    /// the whole image, such as [`VdiDisk::for_each_block`], [`VdiDisk::clone_to`] and
+    /// [`VdiDisk::stats`], then keep up to `queue_depth` block reads in flight.
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    pub fn open_uring(file: std::fs::File, queue_depth: u32) -> anyhow::Result<Self> {
+        Self::from_backend(Backend::Uring(Box::new(uring::Ring::new(
+            file,
+            queue_depth,
+        )?)))
+    }
+
+    fn from_backend(mut backend: Backend) -> anyhow::Result<Self> {
+        let header = backend.read_pod_at::<header::VdiHeader>(0)?;
+        header.validate()?;
+
+        let mut block_offsets_raw = vec![0u8; header.block_map_size()];
+        backend.read_exact_at(
+            header.block_offsets_offset.get() as u64,
+            &mut block_offsets_raw,
+        )?;
+        let block_offsets = header.parse_block_map(&block_offsets_raw);
+        let zero_blocks = header::VdiHeader::block_map_entries(&block_offsets_raw)
+            .map(|entry| entry == header::VdiHeader::BLOCK_ZERO)
             .collect();
 
-        Ok(Self {
+        let mut disk = Self {
             header,
-            block_size: header.block_size as usize,
+            block_size: header.block_size.get() as usize,
             block_offsets,
-            reader,
+            backend,
             position: 0,
+            cache: None,
+            journal: None,
+            ordered_writes: false,
+            lock_mode: None,
+            parent: None,
+            zero_blocks,
+            #[cfg(feature = "crypt")]
+            cipher: None,
+            progress: RefCell::new(None),
+        };
+
+        // The map is updated before the header, so an interrupted allocation can leave blocks
+        // the header does not count. Count them so they are not handed out twice.
+        if disk.is_writable() {
+            let used = header::VdiHeader::block_map_entries(&block_offsets_raw)
+                .filter(|&entry| entry < header::VdiHeader::BLOCK_ZERO)
+                .map(|slot| slot + 1)
+                .max()
+                .unwrap_or(0);
+            if used > disk.header.blocks_allocated.get() {
+                disk.header.blocks_allocated.set(used);
+                disk.write_header()?;
+            }
+        }
+        Ok(disk)
+    }
+
+    /// Allocates a new block at the end of the image for logical block `index`, and fills it with
+    /// `data` at `block_offset`. The rest of the block is zeroed, or copied from the parent.
+    fn allocate_block(
+        &mut self,
+        index: usize,
+        block_offset: usize,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let slot = self.header.blocks_allocated.get();
+        let file_offset = self.slot_offset(slot);
+
+        let mut block = vec![0u8; self.block_size];
+        if self.reads_parent(index) {
+            self.read_parent(index as u64 * self.block_size as u64, &mut block)?;
+        }
+        block[block_offset..block_offset + data.len()].copy_from_slice(data);
+
+        self.backend.writer()?.write_all_at(file_offset, &block)?;
+        self.write_barrier()?;
+        self.write_block_map_entry(index, slot)?;
+        self.write_barrier()?;
+
+        self.header
+            .blocks_allocated
+            .set(self.header.blocks_allocated.get() + 1);
+        self.block_offsets[index] = Some(file_offset);
+        self.zero_blocks[index] = false;
+        self.write_header()
+    }
+
+    /// Makes `range` read as zeros, releasing the blocks it fully covers like VirtualBox's
+    /// `--discard` does. The edges of the range in partially covered blocks are zero-filled.
+    ///
+    /// Like VirtualBox, the last block of the image is moved into each released slot and the
+    /// image is truncated. With `defer_shrink` the blocks are only marked as zero, leaving their
+    /// space for a later [`VdiDisk::compact`] to reclaim, which avoids copying blocks around when
+    /// discarding many ranges.
+    pub fn discard(
+        &mut self,
+        range: std::ops::Range<u64>,
+        defer_shrink: bool,
+    ) -> std::io::Result<()> {
+        self.backend.writer()?;
+        let disk_size = self.header.disk_size.get();
+        let range = range.start..std::cmp::min(range.end, disk_size);
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        let block_size = self.block_size as u64;
+        let first = range.start.div_ceil(block_size);
+        // The last block may be shorter than the others, it is covered up to the disk size
+        let end = if range.end == disk_size {
+            disk_size.div_ceil(block_size)
+        } else {
+            range.end / block_size
+        };
+        if first >= end {
+            return self.write_zeroes(range);
+        }
+        self.write_zeroes(range.start..first * block_size)?;
+        self.write_zeroes(std::cmp::min(end * block_size, range.end)..range.end)?;
+
+        // Block stored at each file offset, to find the one to move into a released slot
+        let mut owners: Option<HashMap<u64, usize>> = (!defer_shrink).then(|| {
+            let stored = self.block_offsets.iter().enumerate();
+            stored
+                .filter_map(|(index, offset)| Some(((*offset)?, index)))
+                .collect()
+        });
+        let mut released = false;
+        let mut tracker = progress::Tracker::new((end - first) * block_size);
+        let mut result = Ok(());
+        for index in first..end {
+            let allocated = self.deallocate_block(index as usize, owners.as_mut())?;
+            released |= allocated;
+            result = self.advance(&mut tracker, block_size, !allocated);
+            if result.is_err() {
+                break; // Cancelled, the blocks released so far still need the truncation
+            }
+        }
+        if released && !defer_shrink {
+            let end = self.slot_offset(self.header.blocks_allocated.get());
+            self.backend.writer()?.set_len(end)?;
+        }
+        result
+    }
+
+    fn write_zeroes(&mut self, range: std::ops::Range<u64>) -> std::io::Result<()> {
+        let zeros =
+            vec![0u8; std::cmp::min(range.end - range.start, self.block_size as u64) as usize];
+        let mut pos = range.start;
+        while pos < range.end {
+            let len = std::cmp::min(zeros.len() as u64, range.end - pos) as usize;
+            self.write_all_at(pos, &zeros[..len])?;
+            pos += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Marks block `index` as zero, returns whether it was allocated. With `owners`, the blocks
+    /// stored at each file offset, the last block of the image is moved into its slot so the
+    /// allocated blocks stay contiguous, otherwise the slot is left unused.
+    fn deallocate_block(
+        &mut self,
+        index: usize,
+        owners: Option<&mut HashMap<u64, usize>>,
+    ) -> std::io::Result<bool> {
+        let Some(file_offset) = self.block_offsets[index] else {
+            // Hide the data of the parent
+            if self.reads_parent(index) {
+                self.write_block_map_entry(index, header::VdiHeader::BLOCK_ZERO)?;
+                self.zero_blocks[index] = true;
+                self.invalidate_block(index);
+            }
+            return Ok(false);
+        };
+
+        // Release the slot first, so no crash leaves two entries referencing it
+        self.write_block_map_entry(index, header::VdiHeader::BLOCK_ZERO)?;
+        self.block_offsets[index] = None;
+        self.zero_blocks[index] = true;
+        // The cache is keyed by logical offsets, the moved block's contents did not change
+        self.invalidate_block(index);
+        let Some(owners) = owners else {
+            return Ok(true);
+        };
+        owners.remove(&file_offset);
+        self.write_barrier()?;
+
+        let last_slot = self.header.blocks_allocated.get() - 1;
+        let last_offset = self.slot_offset(last_slot);
+        if file_offset != last_offset
+            && let Some(moved) = owners.remove(&last_offset)
+        {
+            let mut block = vec![0u8; self.block_size];
+            self.backend.read_exact_at(last_offset, &mut block)?;
+            self.backend.writer()?.write_all_at(file_offset, &block)?;
+            self.write_barrier()?;
+
+            let slot = ((file_offset - self.header.data_offset.get() as u64)
+                / self.header.block_size.get() as u64) as u32;
+            self.write_block_map_entry(moved, slot)?;
+            self.block_offsets[moved] = Some(file_offset);
+            owners.insert(file_offset, moved);
+            self.write_barrier()?;
+        }
+
+        self.header
+            .blocks_allocated
+            .set(self.header.blocks_allocated.get() - 1);
+        self.write_header()?;
+        Ok(true)
+    }
+
+    fn invalidate_block(&self, index: usize) {
+        if let Some(cache) = &self.cache {
+            let start = index as u64 * self.block_size as u64;
+            lock_cache(cache).invalidate_range(start..start + self.block_size as u64);
+        }
+    }
+
+    /// Grows the disk to `disk_size` bytes, the new space reads as zeros.
+    ///
+    /// When the block map no longer fits in front of the block data, the first blocks of the
+    /// image are moved to its end to make room, like VirtualBox does. A crash during the moves
+    /// would leave the block map pointing at overwritten data, so this fails unless the disk was
+    /// opened with [`VdiDisk::open_journaled`]. The moves are reported to the progress handler,
+    /// cancelling them leaves the disk unchanged.
+    pub fn resize(&mut self, disk_size: u64) -> anyhow::Result<()> {
+        self.backend.writer()?;
+        anyhow::ensure!(
+            disk_size >= self.header.disk_size.get(),
+            "Shrinking images is not supported"
+        );
+
+        let block_size = self.header.block_size.get() as u64;
+        let blocks_in_image = u32::try_from(disk_size.div_ceil(block_size))
+            .ok()
+            .filter(|&blocks| blocks < header::VdiHeader::BLOCK_ZERO)
+            .ok_or_else(|| anyhow::anyhow!("Disk size is too large for the block size"))?;
+
+        let original = self.read_block_map()?;
+        let mut entries = original.clone();
+        entries.resize(blocks_in_image as usize, header::VdiHeader::BLOCK_FREE);
+        let mut header = self.header;
+        let mut moves = Vec::new();
+
+        let map_end = self.header.block_offsets_offset.get() as u64 + entries.len() as u64 * 4;
+        let data_offset = self.header.data_offset.get() as u64;
+        if map_end > data_offset {
+            let moved = (map_end - data_offset).div_ceil(block_size);
+            let moved = u32::try_from(moved)?;
+            let allocated = self.header.blocks_allocated.get();
+
+            // Slots past the moved ones keep their file offset, the moved ones are appended
+            let new_slot = |slot: u32| {
+                if slot >= moved {
+                    slot - moved
+                } else {
+                    allocated.saturating_sub(moved) + slot
+                }
+            };
+
+            for entry in entries.iter_mut() {
+                if *entry >= header::VdiHeader::BLOCK_ZERO {
+                    continue;
+                }
+                if *entry < moved {
+                    let target = self.slot_offset(new_slot(*entry) + moved);
+                    moves.push((self.slot_offset(*entry), target));
+                }
+                *entry = new_slot(*entry);
+            }
+
+            header
+                .data_offset
+                .set(u32::try_from(data_offset + moved as u64 * block_size)?);
+        }
+
+        header.disk_size.set(disk_size);
+        header.blocks_in_image.set(blocks_in_image);
+        // The copies made so far are past the end of the image, dropping them restores it
+        let unchanged = journal::Transaction {
+            moves: Vec::new(),
+            header: self.header,
+            block_map: original,
+            file_len: Some(self.slot_offset(self.header.blocks_allocated.get())),
+        };
+        self.commit(
+            journal::Transaction {
+                moves,
+                header,
+                block_map: entries,
+                file_len: None,
+            },
+            |_| unchanged,
+        )?;
+        Ok(())
+    }
+
+    /// Releases blocks that contain only zeros and closes the gaps they and any orphaned blocks
+    /// leave in the image, then truncates it. Returns the number of bytes reclaimed.
+    ///
+    /// Blocks are moved into slots the current block map still references, so closing gaps
+    /// fails unless the disk was opened with [`VdiDisk::open_journaled`]. Both the scan of the
+    /// blocks and their moves are reported to the progress handler. Cancelling the moves keeps
+    /// the blocks moved so far, the others stay where they are until the next compaction.
+    pub fn compact(&mut self) -> anyhow::Result<u64> {
+        self.backend.writer()?;
+
+        let mut entries = self.read_block_map()?;
+        let mut block = vec![0u8; self.block_size];
+        // Logical block stored in each file slot
+        let mut slots: Vec<Option<usize>> = vec![None; self.header.blocks_allocated.get() as usize];
+        let block_size = self.block_size as u64;
+        let mut tracker = progress::Tracker::new(entries.len() as u64 * block_size);
+        for (index, entry) in entries.iter_mut().enumerate() {
+            if *entry >= header::VdiHeader::BLOCK_ZERO {
+                self.advance(&mut tracker, block_size, true)?;
+                continue;
+            }
+
+            self.backend
+                .read_exact_at(self.slot_offset(*entry), &mut block)?;
+            self.advance(&mut tracker, block_size, false)?;
+            if block.iter().all(|&b| b == 0) {
+                *entry = header::VdiHeader::BLOCK_ZERO;
+                continue;
+            }
+
+            let slot = *entry as usize;
+            if slot >= slots.len() {
+                slots.resize(slot + 1, None);
+            }
+            anyhow::ensure!(
+                slots[slot].replace(index).is_none(),
+                "Block map references file block {slot} more than once"
+            );
+        }
+
+        // Fill the gaps with the last blocks of the image
+        let released = entries.clone();
+        let used = slots.iter().flatten().count();
+        let mut gaps = (0..used).filter(|&slot| slots[slot].is_none());
+        let mut moves = Vec::new();
+        let mut placed = Vec::new();
+        for (slot, &index) in slots.iter().enumerate().skip(used) {
+            let Some(index) = index else {
+                continue;
+            };
+            let gap = gaps
+                .next()
+                .expect("unreachable: fewer gaps than blocks past the used slots");
+
+            moves.push((self.slot_offset(slot as u32), self.slot_offset(gap as u32)));
+            entries[index] = gap as u32;
+            placed.push((index, gap as u32));
+        }
+
+        let mut header = self.header;
+        header.blocks_allocated.set(used as u32);
+        // Blocks not moved yet keep their slot, a later compaction moves them
+        let old_header = self.header;
+        let partial = |done: usize| {
+            let mut block_map = released;
+            for &(index, gap) in &placed[..done] {
+                block_map[index] = gap;
+            }
+            journal::Transaction {
+                moves: Vec::new(),
+                header: old_header,
+                block_map,
+                file_len: None,
+            }
+        };
+        self.commit(
+            journal::Transaction {
+                moves,
+                header,
+                block_map: entries,
+                file_len: Some(self.slot_offset(used as u32)),
+            },
+            partial,
+        )?;
+
+        Ok((slots.len() - used) as u64 * self.header.block_size.get() as u64)
+    }
+
+    /// Moves the allocated blocks so they are stored in logical order, making sequential reads
+    /// of the disk sequential in the image file. Unused slots are released on the way. Returns
+    /// the number of blocks that were out of place.
+    ///
+    /// Blocks are moved one at a time through a spare slot past the end of the image, and the
+    /// block map only ever points at fully copied data. See [`VdiDisk::set_ordered_writes`] to
+    /// make this hold across crashes.
+    pub fn repack(&mut self) -> anyhow::Result<u32> {
+        self.backend.writer()?;
+
+        let data_offset = self.header.data_offset.get() as u64;
+        let block_size = self.header.block_size.get() as u64;
+        // Logical block stored in each file slot
+        let mut slots: Vec<Option<usize>> = vec![None; self.header.blocks_allocated.get() as usize];
+        let mut order = Vec::new();
+        for (index, offset) in self.block_offsets.iter().enumerate() {
+            let Some(offset) = *offset else {
+                continue;
+            };
+            let slot = ((offset - data_offset) / block_size) as usize;
+            if slot >= slots.len() {
+                slots.resize(slot + 1, None);
+            }
+            anyhow::ensure!(
+                slots[slot].replace(index).is_none(),
+                "Block map references file block {slot} more than once"
+            );
+            order.push((index, slot));
+        }
+
+        // Count the spare slot, so the header covers every block the map may point at
+        slots.push(None);
+        self.header.blocks_allocated.set(slots.len() as u32);
+        self.write_header()?;
+        self.write_barrier()?;
+
+        // May contain slots that were filled since, they are skipped
+        let mut free: Vec<usize> = (0..slots.len()).filter(|&s| slots[s].is_none()).collect();
+        let mut block = vec![0u8; self.block_size];
+        let mut moved = 0;
+        let mut tracker = progress::Tracker::new(order.len() as u64 * block_size);
+        for (target, &(index, _)) in order.iter().enumerate() {
+            let current =
+                ((self.block_offsets[index].unwrap() - data_offset) / block_size) as usize;
+            if let Err(e) = self.advance(&mut tracker, block_size, current == target) {
+                // The image is consistent between moves, the slots left unused are released
+                // by a later compaction
+                self.backend.writer()?.flush()?;
+                return Err(e.into());
+            }
+            if current == target {
+                continue;
+            }
+
+            if let Some(other) = slots[target] {
+                let spare = loop {
+                    let slot = free
+                        .pop()
+                        .expect("unreachable: a slot is always free while blocks are out of place");
+                    if slots[slot].is_none() {
+                        break slot;
+                    }
+                };
+                self.move_block(other, target, spare, &mut block)?;
+                slots[spare] = Some(other);
+            }
+            self.move_block(index, current, target, &mut block)?;
+            slots[target] = Some(index);
+            slots[current] = None;
+            free.push(current);
+            moved += 1;
+        }
+
+        let used = order.len();
+        self.header.blocks_allocated.set(used as u32);
+        self.write_header()?;
+        let end = self.slot_offset(used as u32);
+        self.backend.writer()?.set_len(end)?;
+        self.backend.writer()?.flush()?;
+        Ok(moved)
+    }
+
+    /// Copies logical block `index` from file slot `from` to `to` and points the map at the copy
+    fn move_block(
+        &mut self,
+        index: usize,
+        from: usize,
+        to: usize,
+        block: &mut [u8],
+    ) -> std::io::Result<()> {
+        self.backend
+            .read_exact_at(self.slot_offset(from as u32), block)?;
+        let target = self.slot_offset(to as u32);
+        self.backend.writer()?.write_all_at(target, block)?;
+        self.write_barrier()?;
+        self.write_block_map_entry(index, to as u32)?;
+        self.block_offsets[index] = Some(target);
+        self.write_barrier()
+    }
+
+    /// Writes a copy of the image to `storage` with its blocks in logical order, and opens it.
+    /// Unlike [`VdiDisk::repack`], this leaves the image untouched and can be used on a read-only
+    /// disk.
+    pub fn repack_to<S: Storage + 'static>(&self, mut storage: Box<S>) -> anyhow::Result<Self> {
+        self.copy_to(storage.as_mut(), self.header, false, false)?;
+        Self::open_writable(storage)
+    }
+
+    /// Copies the disk to a new image on `storage` with fresh image and snapshot UUIDs, so
+    /// VirtualBox accepts it alongside the original, and opens it. The blocks of the copy are
+    /// in logical order.
+    ///
+    /// A clone of a differencing image keeps referencing the same parent, unless it is
+    /// flattened.
+    pub fn clone_to<S: Storage + 'static>(
+        &self,
+        mut storage: Box<S>,
+        options: CloneOptions,
+    ) -> anyhow::Result<Self> {
+        let mut header = self.header;
+        header.uuid_image = header::VdiHeader::uuid_to_disk(uuid::Uuid::new_v4());
+        header.uuid_last_snap = header::VdiHeader::uuid_to_disk(uuid::Uuid::new_v4());
+        if options.flatten {
+            let mut disk = self;
+            while disk.header.image_type.get() == header::VdiHeader::TYPE_DIFFERENCING {
+                disk = disk.parent().ok_or_else(|| {
+                    anyhow::anyhow!("Flattening requires the parents of the image to be attached")
+                })?;
+            }
+            header.image_type.set(header::VdiHeader::TYPE_DYNAMIC);
+            header.uuid_link = uuid::Uuid::nil();
+            header.uuid_parent = uuid::Uuid::nil();
+        }
+
+        self.copy_to(storage.as_mut(), header, options.flatten, options.compact)?;
+        Self::open_writable(storage)
+    }
+
+    /// Writes the blocks of the disk in logical order to `storage`, as an image described by
+    /// `header`. With `flatten` the blocks read from the parent are copied too, with
+    /// `skip_zeros` blocks containing only zeros are marked as zero instead.
+    fn copy_to(
+        &self,
+        storage: &mut dyn Storage,
+        mut header: header::VdiHeader,
+        flatten: bool,
+        skip_zeros: bool,
+    ) -> std::io::Result<()> {
+        let data_offset = header.data_offset.get() as u64;
+        let block_size = header.block_size.get() as u64;
+        storage.set_len(0)?;
+        storage.set_len(data_offset)?;
+
+        let mut entries = self.read_block_map()?;
+        let mut parent_block = vec![0u8; self.block_size];
+        let mut used = 0;
+        let mut tracker = progress::Tracker::new(entries.len() as u64 * self.block_size as u64);
+        self.scan_blocks(|index, stored| {
+            let from_parent = flatten && self.reads_parent(index) && !self.is_hole(index);
+            self.advance(&mut tracker, block_size, stored.is_none() && !from_parent)?;
+            let block = match stored {
+                Some(block) => block,
+                None if from_parent => {
+                    self.read_parent(index as u64 * block_size, &mut parent_block)?;
+                    &parent_block[..]
+                }
+                None => return Ok(()),
+            };
+
+            if skip_zeros && block.iter().all(|&b| b == 0) {
+                entries[index] = header::VdiHeader::BLOCK_ZERO;
+            } else {
+                storage.write_all_at(data_offset + used as u64 * block_size, block)?;
+                entries[index] = used;
+                used += 1;
+            }
+            Ok(())
+        })?;
+        header.blocks_allocated.set(used);
+
+        let raw: Vec<u8> = entries.iter().flat_map(|e| e.to_le_bytes()).collect();
+        storage.write_all_at(header.block_offsets_offset.get() as u64, &raw)?;
+        storage.write_all_at(0, bytemuck::bytes_of(&header))?;
+        storage.flush()
+    }
+
+    /// Calls `f` with the index and contents of every block of the disk, in logical order, eg.
+    /// to hash the disk. Unallocated blocks read as zeros or from the parent, and the last block
+    /// is cut at the end of the disk. The progress is reported to the handler set with
+    /// [`VdiDisk::set_progress_handler`].
+    ///
+    /// With [`VdiDisk::open_uring`], many reads of the allocated blocks are in flight at once.
+    pub fn for_each_block(
+        &self,
+        mut f: impl FnMut(usize, &[u8]) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let block_size = self.block_size as u64;
+        let disk_size = self.header.disk_size.get();
+        let mut unallocated = vec![0u8; self.block_size];
+        let mut tracker = progress::Tracker::new(disk_size);
+        self.scan_blocks(|index, stored| {
+            let start = index as u64 * block_size;
+            let len = std::cmp::min(block_size, disk_size.saturating_sub(start)) as usize;
+            self.advance(&mut tracker, len as u64, self.is_hole(index))?;
+            let block = match stored {
+                Some(block) => block,
+                None if self.reads_parent(index) => {
+                    self.read_parent(start, &mut unallocated)?;
+                    &unallocated[..]
+                }
+                None => {
+                    unallocated.fill(0);
+                    &unallocated[..]
+                }
+            };
+            f(index, &block[..len])
         })
     }
 
-    pub fn slice(&mut self, range: std::ops::Range<u64>) -> slice::Slice<'_> {
+    /// Calls `f` with the index of every block in logical order, along with its decrypted
+    /// contents if it is stored in this image
+    fn scan_blocks(
+        &self,
+        mut f: impl FnMut(usize, Option<&[u8]>) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let mut stored = |index: usize, block: Option<&mut [u8]>| match block {
+            Some(block) => {
+                #[cfg(feature = "crypt")]
+                if let Some(cipher) = &self.cipher {
+                    let sector = index as u64 * self.block_size as u64 / crypt::SECTOR_SIZE;
+                    cipher.decrypt(sector, block);
+                }
+                f(index, Some(&*block))
+            }
+            None => f(index, None),
+        };
+
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        if let Backend::Uring(ring) = &self.backend {
+            return ring.read_blocks(&self.block_offsets, self.block_size, stored);
+        }
+
+        let mut block = vec![0u8; self.block_size];
+        for (index, offset) in self.block_offsets.iter().enumerate() {
+            match *offset {
+                Some(offset) => {
+                    backend::read_block(&self.backend, offset, &mut block)?;
+                    stored(index, Some(&mut block))?;
+                }
+                None => stored(index, None)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the image UUID, eg. to register a copied image alongside the original
+    pub fn set_uuid_image(&mut self, uuid: uuid::Uuid) -> std::io::Result<()> {
+        self.backend.writer()?;
+        self.header.uuid_image = header::VdiHeader::uuid_to_disk(uuid);
+        self.write_header()
+    }
+
+    /// Applies a metadata update, through the journal if the disk has one. Updates that move
+    /// blocks can not be made crash safe without one and are refused.
+    ///
+    /// The block moves are reported to the progress handler. If it cancels them, `cancelled` is
+    /// called with the number of moves done and returns the update that leaves the image
+    /// consistent with them, which is applied before failing.
+    fn commit(
+        &mut self,
+        transaction: journal::Transaction,
+        cancelled: impl FnOnce(usize) -> journal::Transaction,
+    ) -> std::io::Result<()> {
+        let block_size = self.header.block_size.get() as u64;
+        let mut tracker = progress::Tracker::new(transaction.moves.len() as u64 * block_size);
+        let mut moved = 0;
+        // Taken out while the update borrows the disk, and put back below
+        let mut handler = self.progress.get_mut().take();
+        let mut before_move = || {
+            let handler = handler
+                .as_mut()
+                .map(|h| h.as_mut() as &mut dyn progress::ProgressHandler);
+            tracker.advance(block_size, false, handler)?;
+            moved += 1;
+            Ok(())
+        };
+
+        let result = self.apply_update(transaction, &mut before_move);
+        *self.progress.get_mut() = handler;
+        match result {
+            Err(e) if progress::is_cancelled(&e) => {
+                self.apply_update(cancelled(moved), &mut || Ok(()))?;
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    fn apply_update(
+        &mut self,
+        transaction: journal::Transaction,
+        before_move: &mut dyn FnMut() -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        match self.journal.as_deref_mut() {
+            Some(journal) => journal::begin(journal, &transaction)?,
+            None if !transaction.moves.is_empty() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Moving blocks requires a disk opened with VdiDisk::open_journaled",
+                ));
+            }
+            None => {}
+        }
+        let journal = self
+            .journal
+            .as_mut()
+            .map(|j| j.as_mut() as &mut dyn Storage);
+        transaction.apply(self.backend.writer()?, journal, 0, before_move)?;
+
+        self.header = transaction.header;
+        let raw: Vec<u8> = transaction
+            .block_map
+            .iter()
+            .flat_map(|e| e.to_le_bytes())
+            .collect();
+        self.block_offsets = self.header.parse_block_map(&raw);
+        self.zero_blocks = transaction
+            .block_map
+            .iter()
+            .map(|&entry| entry == header::VdiHeader::BLOCK_ZERO)
+            .collect();
+        Ok(())
+    }
+
+    fn read_block_map(&self) -> std::io::Result<Vec<u32>> {
+        let mut raw = vec![0u8; self.header.block_map_size()];
+        self.backend
+            .read_exact_at(self.header.block_offsets_offset.get() as u64, &mut raw)?;
+        Ok(header::VdiHeader::block_map_entries(&raw).collect())
+    }
+
+    /// Absolute file offset of the block stored in file slot `slot`
+    fn slot_offset(&self, slot: u32) -> u64 {
+        self.header.data_offset.get() as u64 + slot as u64 * self.header.block_size.get() as u64
+    }
+
+    fn write_block_map_entry(&mut self, index: usize, entry: u32) -> std::io::Result<()> {
+        let offset = self.header.block_offsets_offset.get() as u64 + index as u64 * 4;
+        self.backend
+            .writer()?
+            .write_all_at(offset, &entry.to_le_bytes())
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let header = self.header;
+        self.backend
+            .writer()?
+            .write_all_at(0, bytemuck::bytes_of(&header))
+    }
+
+    /// Returns a view of the data of block `index` straight from the memory mapping.
+    ///
+    /// Unallocated blocks are returned as zeros. Returns `None` if the disk was not opened with
+    /// [`VdiDisk::open_mmap`], the block lies outside of the image or is read from the parent,
+    /// or the disk is encrypted, as the mapping only holds the encrypted data.
+    #[cfg(feature = "mmap")]
+    pub fn mapped_block(&self, index: usize) -> Option<&[u8]> {
+        let Backend::Mmap { map, zero_block } = &self.backend else {
+            return None;
+        };
+        let offset = *self.block_offsets.get(index)?;
+        #[cfg(feature = "crypt")]
+        if self.cipher.is_some() {
+            return None;
+        }
+        if self.reads_parent(index) {
+            return None;
+        }
+
+        match offset {
+            Some(offset) => map.get(offset as usize..offset as usize + self.block_size),
+            None => Some(zero_block),
+        }
+    }
+
+    /// Enables an LRU cache of disk pages in front of the underlying reader
+    pub fn enable_cache(&mut self, config: CacheConfig) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            config.page_size.is_power_of_two() && self.block_size.is_multiple_of(config.page_size),
+            "Cache page size must be a power of two that divides the block size"
+        );
+        self.cache = Some(Mutex::new(BlockCache::new(config)));
+        Ok(())
+    }
+
+    pub fn disable_cache(&mut self) {
+        self.cache = None;
+    }
+
+    /// Returns the hit/miss statistics of the block cache, if it is enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|c| lock_cache(c).stats())
+    }
+
+    /// Drops all cached pages, eg. after the underlying file was modified externally
+    pub fn invalidate_cache(&self) {
+        if let Some(cache) = &self.cache {
+            lock_cache(cache).clear();
+        }
+    }
+
+    /// Reads and decrypts allocated data at logical position `pos` (stored at `file_offset`). The
+    /// whole sectors covering it are read, `buf` must not cross a block boundary.
+    #[cfg(feature = "crypt")]
+    fn read_decrypted(
+        &self,
+        cipher: &crypt::SectorCipher,
+        pos: u64,
+        file_offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<()> {
+        let start = pos - pos % crypt::SECTOR_SIZE;
+        let end = (pos + buf.len() as u64).next_multiple_of(crypt::SECTOR_SIZE);
+        let stored = file_offset - (pos - start);
+
+        let mut sectors = vec![0u8; (end - start) as usize];
+        let mut filled = 0;
+        while filled < sectors.len() {
+            let chunk = &mut sectors[filled..];
+            let n = match &self.cache {
+                Some(cache) => {
+                    self.read_cached(cache, start + filled as u64, stored + filled as u64, chunk)?
+                }
+                None => self.backend.read_at(stored + filled as u64, chunk)?,
+            };
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Encrypted block is truncated",
+                ));
+            }
+            filled += n;
+        }
+
+        cipher.decrypt(start / crypt::SECTOR_SIZE, &mut sectors);
+        let skip = (pos - start) as usize;
+        buf.copy_from_slice(&sectors[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    /// Bytes from logical position `pos`, in an allocated block, up to `max_len` that are stored
+    /// contiguously in the image
+    fn contiguous_len(&self, pos: u64, max_len: usize) -> usize {
+        let block_size = self.block_size as u64;
+        let index = (pos / block_size) as usize;
+        let start = self.block_offsets[index].expect("block is allocated");
+
+        let mut end = (index as u64 + 1) * block_size;
+        let mut next = index + 1;
+        while end - pos < max_len as u64
+            && self.block_offsets.get(next)
+                == Some(&Some(start + (next - index) as u64 * block_size))
+        {
+            end += block_size;
+            next += 1;
+        }
+        std::cmp::min(end - pos, max_len as u64) as usize
+    }
+
+    /// Reads into `bufs` in turn from `pos`, like [`ReadAt::read_at`] on their concatenation.
+    /// Returns the number of bytes read, which is only short at the end of the disk.
+    pub fn read_vectored_at(
+        &self,
+        pos: u64,
+        bufs: &mut [std::io::IoSliceMut<'_>],
+    ) -> std::io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.read_at(pos + (total + filled) as u64, &mut buf[filled..])? {
+                    0 => return Ok(total + filled),
+                    n => filled += n,
+                }
+            }
+            total += filled;
+        }
+        Ok(total)
+    }
+
+    /// Reads allocated data at logical position `pos` (stored at `file_offset`) through the block cache
+    fn read_cached(
+        &self,
+        cache: &Mutex<BlockCache>,
+        pos: u64,
+        file_offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<usize> {
+        let mut cache = lock_cache(cache);
+        let page_size = cache.page_size() as u64;
+        let page_offset = pos % page_size;
+        let len = std::cmp::min(buf.len() as u64, page_size - page_offset) as usize;
+
+        let page = cache.get_or_load(pos / page_size, |data| {
+            let page_start = file_offset - page_offset;
+            let mut filled = 0;
+            while filled < data.len() {
+                let n = self
+                    .backend
+                    .read_at(page_start + filled as u64, &mut data[filled..])?;
+                if n == 0 {
+                    break; // EOF
+                }
+                filled += n;
+            }
+            Ok(filled)
+        })?;
+
+        // Short at the end of the file, like an uncached read
+        let available = page.get(page_offset as usize..).unwrap_or_default();
+        let len = std::cmp::min(len, available.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        Ok(len)
+    }
+
+    pub fn slice(&self, range: std::ops::Range<u64>) -> slice::Slice<'_> {
         slice::Slice::new(self, range)
     }
 
+    pub fn slice_mut(&mut self, range: std::ops::Range<u64>) -> slice::SliceMut<'_> {
+        slice::SliceMut::new(self, range)
+    }
+
     pub fn slice_owned(self, range: std::ops::Range<u64>) -> std::io::Result<slice::OwnedSlice> {
         slice::OwnedSlice::new(self, range)
     }
 }
 
+/// Options of [`VdiDisk::clone_to`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CloneOptions {
+    /// Copy the data of the parent images too, making the clone a standalone dynamic image
+    pub flatten: bool,
+    /// Leave blocks containing only zeros unallocated, like [`VdiDisk::compact`]
+    pub compact: bool,
+}
+
+fn lock_cache(cache: &Mutex<BlockCache>) -> std::sync::MutexGuard<'_, BlockCache> {
+    cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 impl positioned_io2::ReadAt for VdiDisk {
     fn read_at(&self, mut pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        // The last block can extend past the end of the disk
+        let len = std::cmp::min(
+            buf.len() as u64,
+            self.header.disk_size.get().saturating_sub(pos),
+        );
+        let buf = &mut buf[..len as usize];
         let mut total_read = 0;
         while total_read < buf.len() {
             let block_index = (pos / self.block_size as u64) as usize;
@@ -83,10 +1241,24 @@ impl positioned_io2::ReadAt for VdiDisk {
             let to_read = std::cmp::min(buf.len() - total_read, self.block_size - block_offset);
 
             if let Some(file_offset) = self.block_offsets[block_index] {
-                let n = self.reader.read_at(
-                    file_offset + block_offset as u64,
-                    &mut buf[total_read..total_read + to_read],
-                )?;
+                let file_offset = file_offset + block_offset as u64;
+                let chunk = &mut buf[total_read..total_read + to_read];
+                #[cfg(feature = "crypt")]
+                if let Some(cipher) = &self.cipher {
+                    self.read_decrypted(cipher, pos, file_offset, chunk)?;
+                    total_read += to_read;
+                    pos += to_read as u64;
+                    continue;
+                }
+                let n = match &self.cache {
+                    Some(cache) => self.read_cached(cache, pos, file_offset, chunk)?,
+                    None => {
+                        // Blocks stored one after the other are read with a single call
+                        let len = self.contiguous_len(pos, buf.len() - total_read);
+                        self.backend
+                            .read_at(file_offset, &mut buf[total_read..total_read + len])?
+                    }
+                };
                 if n == 0 {
                     break; // EOF
                 }
@@ -94,7 +1266,12 @@ impl positioned_io2::ReadAt for VdiDisk {
                 pos += n as u64;
             } else {
                 // Unallocated block
-                buf[total_read..total_read + to_read].fill(0);
+                let chunk = &mut buf[total_read..total_read + to_read];
+                if self.reads_parent(block_index) {
+                    self.read_parent(pos, chunk)?;
+                } else {
+                    chunk.fill(0);
+                }
                 total_read += to_read;
                 pos += to_read as u64;
             }
@@ -103,24 +1280,76 @@ impl positioned_io2::ReadAt for VdiDisk {
     }
 }
 
+impl positioned_io2::WriteAt for VdiDisk {
+    fn write_at(&mut self, mut pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        self.backend.writer()?;
+
+        // Like reads, writes stop at the end of the disk rather than the end of the last block
+        let len = std::cmp::min(
+            buf.len() as u64,
+            self.header.disk_size.get().saturating_sub(pos),
+        );
+        let buf = &buf[..len as usize];
+        let mut total_written = 0;
+        while total_written < buf.len() {
+            let block_index = (pos / self.block_size as u64) as usize;
+            let block_offset = (pos % self.block_size as u64) as usize;
+            if block_index >= self.block_offsets.len() {
+                break; // EOF
+            }
+
+            let to_write = std::cmp::min(buf.len() - total_written, self.block_size - block_offset);
+            let chunk = &buf[total_written..total_written + to_write];
+
+            if let Some(file_offset) = self.block_offsets[block_index] {
+                self.backend
+                    .writer()?
+                    .write_all_at(file_offset + block_offset as u64, chunk)?;
+            } else if chunk.iter().any(|&b| b != 0) || self.reads_parent(block_index) {
+                self.allocate_block(block_index, block_offset, chunk)?;
+            } // Zeroes written to an unallocated block don't need to be stored
+
+            if let Some(cache) = &self.cache {
+                lock_cache(cache).invalidate_range(pos..pos + to_write as u64);
+            }
+
+            total_written += to_write;
+            pos += to_write as u64;
+        }
+        Ok(total_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.backend {
+            Backend::Writer(writer) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Read for VdiDisk {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let n = self.read_at(self.position, buf)?;
         self.position += n as u64;
         Ok(n)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [std::io::IoSliceMut<'_>]) -> std::io::Result<usize> {
+        let n = self.read_vectored_at(self.position, bufs)?;
+        self.position += n as u64;
+        Ok(n)
+    }
 }
 
 impl Write for VdiDisk {
-    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Unsupported,
-            "VdiDisk does not support write operations",
-        ))
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.write_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        Ok(())
+        WriteAt::flush(self)
     }
 }
 
@@ -129,7 +1358,7 @@ impl std::io::Seek for VdiDisk {
         let new_pos = match pos {
             std::io::SeekFrom::Start(offset) => offset,
             std::io::SeekFrom::End(offset) => {
-                let end = self.header.disk_size;
+                let end = self.header.disk_size.get();
                 if offset >= 0 {
                     end.checked_add(offset as u64).ok_or_else(|| {
                         std::io::Error::new(
@@ -165,7 +1394,7 @@ impl std::io::Seek for VdiDisk {
             }
         };
 
-        if new_pos > self.header.disk_size {
+        if new_pos > self.header.disk_size.get() {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 "Tried to seek beyond end of the disk",
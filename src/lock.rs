@@ -0,0 +1,30 @@
+//! Advisory locks keeping tools from modifying an image while it is in use, eg. by a running VM
+
+use std::fs::File;
+
+/// Lock taken on an image file, see [`crate::VdiDisk::open_locked`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of readers may hold the lock, but no writer
+    Shared,
+    /// A single writer holds the lock
+    Exclusive,
+}
+
+/// Takes an advisory (`flock`) lock on `file` without waiting, fails if a conflicting lock is
+/// held by another process. The lock is released when the file is closed.
+pub fn lock_file(file: &File, mode: LockMode) -> anyhow::Result<()> {
+    let result = match mode {
+        LockMode::Shared => file.try_lock_shared(),
+        LockMode::Exclusive => file.try_lock(),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(std::fs::TryLockError::WouldBlock) => Err(anyhow::anyhow!(
+            "Image is locked by another process, eg. VirtualBox with a running VM using it"
+        )),
+        Err(std::fs::TryLockError::Error(e)) => {
+            Err(anyhow::Error::new(e).context("Failed to lock the image"))
+        }
+    }
+}
@@ -0,0 +1,849 @@
+//! Network Block Device server, see <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md>
+
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use positioned_io2::{ReadAt, WriteAt};
+
+use crate::VdiDisk;
+use crate::overlay::Overlay;
+use crate::slice::{OwnedSlice, Slice, SliceMut};
+
+const NBD_MAGIC: u64 = 0x4e42444d41474943; // "NBDMAGIC"
+const NBD_IHAVEOPT: u64 = 0x49484156454F5054; // "IHAVEOPT"
+const NBD_OPTION_REPLY_MAGIC: u64 = 0x0003e889045565a9;
+const NBD_REQUEST_MAGIC: u32 = 0x25609513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_ABORT: u32 = 2;
+const NBD_OPT_LIST: u32 = 3;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+const NBD_OPT_LIST_META_CONTEXT: u32 = 9;
+const NBD_OPT_SET_META_CONTEXT: u32 = 10;
+
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_SERVER: u32 = 2;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_META_CONTEXT: u32 = 4;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const NBD_REP_ERR_INVALID: u32 = (1 << 31) | 3;
+const NBD_REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+
+const NBD_INFO_EXPORT: u16 = 0;
+const NBD_INFO_BLOCK_SIZE: u16 = 3;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_BLOCK_STATUS: u16 = 7;
+
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+const NBD_CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const NBD_REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+
+const NBD_STATE_HOLE: u32 = 1 << 0;
+const NBD_STATE_ZERO: u32 = 1 << 1;
+
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_EINVAL: u32 = 22;
+const NBD_ENOSPC: u32 = 28;
+const NBD_EOVERFLOW: u32 = 75;
+const NBD_ENOTSUP: u32 = 95;
+
+const BASE_ALLOCATION: &str = "base:allocation";
+const BASE_ALLOCATION_ID: u32 = 1;
+
+/// Largest option payload accepted during negotiation
+const MAX_OPTION_LEN: u32 = 64 * 1024;
+/// Largest read or write payload accepted in a single request
+const MAX_REQUEST_LEN: u32 = 32 * 1024 * 1024;
+
+/// A contiguous run of a device with the same allocation state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub len: u64,
+    /// Whether the run is unallocated, holes always read as zeros
+    pub hole: bool,
+}
+
+/// A block device that can be served over NBD
+pub trait Export: ReadAt {
+    fn export_size(&self) -> u64;
+
+    /// Writable access to the device, `None` if it can only be read
+    fn writer(&mut self) -> Option<&mut dyn WriteAt> {
+        None
+    }
+
+    /// Releases the storage backing `range`, which reads as zeros afterwards
+    fn trim(&mut self, range: std::ops::Range<u64>) -> std::io::Result<()> {
+        let _ = range;
+        Ok(())
+    }
+
+    /// Allocation state of `range`, starting at `range.start`. May describe less than the
+    /// whole range, but must describe at least one byte of it.
+    fn extents(&self, range: std::ops::Range<u64>) -> std::io::Result<Vec<Extent>> {
+        Ok(vec![Extent {
+            len: range.end - range.start,
+            hole: false,
+        }])
+    }
+}
+
+impl Export for VdiDisk {
+    fn export_size(&self) -> u64 {
+        self.header.disk_size.get()
+    }
+
+    fn writer(&mut self) -> Option<&mut dyn WriteAt> {
+        if self.is_writable() { Some(self) } else { None }
+    }
+
+    fn trim(&mut self, range: std::ops::Range<u64>) -> std::io::Result<()> {
+        self.discard(range, false)
+    }
+
+    fn extents(&self, range: std::ops::Range<u64>) -> std::io::Result<Vec<Extent>> {
+        let block_size = self.block_size as u64;
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut pos = range.start;
+        while pos < range.end {
+            let index = (pos / block_size) as usize;
+            if index >= self.block_offsets.len() {
+                break;
+            }
+
+            let len = std::cmp::min((index as u64 + 1) * block_size, range.end) - pos;
+            let hole = self.is_hole(index);
+            match extents.last_mut() {
+                Some(last) if last.hole == hole => last.len += len,
+                _ => extents.push(Extent { len, hole }),
+            }
+            pos += len;
+        }
+        Ok(extents)
+    }
+}
+
+impl Export for Overlay {
+    fn export_size(&self) -> u64 {
+        self.disk_size()
+    }
+
+    fn writer(&mut self) -> Option<&mut dyn WriteAt> {
+        Some(self)
+    }
+}
+
+/// Forwards to the export behind a slice, translating offsets by `start`
+fn slice_extents<E: Export + ?Sized>(
+    inner: &E,
+    start: u64,
+    range: std::ops::Range<u64>,
+) -> std::io::Result<Vec<Extent>> {
+    inner.extents(start + range.start..start + range.end)
+}
+
+impl<E: Export + ?Sized> Export for Slice<'_, E> {
+    fn export_size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn extents(&self, range: std::ops::Range<u64>) -> std::io::Result<Vec<Extent>> {
+        slice_extents(self.get_ref(), self.range().start, range)
+    }
+}
+
+impl<E: Export + WriteAt + ?Sized> Export for SliceMut<'_, E> {
+    fn export_size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn writer(&mut self) -> Option<&mut dyn WriteAt> {
+        self.get_mut().writer()?;
+        Some(self)
+    }
+
+    fn trim(&mut self, range: std::ops::Range<u64>) -> std::io::Result<()> {
+        let start = self.range().start;
+        self.get_mut().trim(start + range.start..start + range.end)
+    }
+
+    fn extents(&self, range: std::ops::Range<u64>) -> std::io::Result<Vec<Extent>> {
+        slice_extents(self.get_ref(), self.range().start, range)
+    }
+}
+
+impl<E: Export + WriteAt> Export for OwnedSlice<E> {
+    fn export_size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn writer(&mut self) -> Option<&mut dyn WriteAt> {
+        self.get_mut().writer()?;
+        Some(self)
+    }
+
+    fn trim(&mut self, range: std::ops::Range<u64>) -> std::io::Result<()> {
+        let start = self.range().start;
+        self.get_mut().trim(start + range.start..start + range.end)
+    }
+
+    fn extents(&self, range: std::ops::Range<u64>) -> std::io::Result<Vec<Extent>> {
+        slice_extents(self.get_ref(), self.range().start, range)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NbdOptions {
+    /// Name the export is announced under. Clients requesting the empty name get it as well.
+    pub export_name: String,
+    /// Refuse writes even if the export is writable
+    pub read_only: bool,
+}
+
+impl Default for NbdOptions {
+    fn default() -> Self {
+        Self {
+            export_name: "vdi".to_string(),
+            read_only: true,
+        }
+    }
+}
+
+/// Serves a single [`Export`] over the NBD protocol.
+///
+/// Connections are handled one after another, as the export can not be shared between clients.
+pub struct NbdServer<E: Export> {
+    export: E,
+    options: NbdOptions,
+}
+
+struct Session {
+    structured_replies: bool,
+    base_allocation: bool,
+}
+
+enum Negotiated {
+    Transmission,
+    Closed,
+}
+
+impl<E: Export> NbdServer<E> {
+    pub fn new(export: E, options: NbdOptions) -> Self {
+        Self { export, options }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.export
+    }
+
+    /// Accepts and serves clients on `addr` until an accept error occurs. Errors of a single
+    /// client are passed to `on_error` along with its address, and the next one is accepted.
+    pub fn serve_tcp(
+        &mut self,
+        addr: impl std::net::ToSocketAddrs,
+        mut on_error: impl FnMut(std::net::SocketAddr, std::io::Error),
+    ) -> std::io::Result<()> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        loop {
+            let (stream, peer) = listener.accept()?;
+            let result = stream
+                .set_nodelay(true)
+                .and_then(|_| self.serve_client(stream));
+            if let Err(e) = result {
+                on_error(peer, e);
+            }
+        }
+    }
+
+    /// Accepts and serves clients on the unix socket at `path` until an accept error occurs.
+    /// Errors of a single client are passed to `on_error` along with its address, and the next
+    /// one is accepted.
+    #[cfg(unix)]
+    pub fn serve_unix(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+        mut on_error: impl FnMut(std::os::unix::net::SocketAddr, std::io::Error),
+    ) -> std::io::Result<()> {
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        loop {
+            let (stream, peer) = listener.accept()?;
+            if let Err(e) = self.serve_client(stream) {
+                on_error(peer, e);
+            }
+        }
+    }
+
+    /// Serves a single client until it disconnects. A client that hangs up is not an error.
+    pub fn serve_client<S: Read + Write>(&mut self, stream: S) -> std::io::Result<()> {
+        match self.handle_client(stream) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+            result => result,
+        }
+    }
+
+    fn handle_client<S: Read + Write>(&mut self, stream: S) -> std::io::Result<()> {
+        let stream = std::cell::RefCell::new(stream);
+        let mut reader = BufReader::new(RefReader(&stream));
+        let mut writer = BufWriter::new(RefWriter(&stream));
+
+        let mut session = Session {
+            structured_replies: false,
+            base_allocation: false,
+        };
+
+        match self.negotiate(&mut reader, &mut writer, &mut session)? {
+            Negotiated::Transmission => self.transmission(&mut reader, &mut writer, &session),
+            Negotiated::Closed => Ok(()),
+        }
+    }
+
+    fn read_only(&mut self) -> bool {
+        self.options.read_only || self.export.writer().is_none()
+    }
+
+    fn transmission_flags(&mut self) -> u16 {
+        let mut flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH;
+        if self.read_only() {
+            flags |= NBD_FLAG_READ_ONLY;
+        } else {
+            flags |= NBD_FLAG_SEND_FUA | NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES;
+        }
+        flags
+    }
+
+    fn is_our_export(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.options.export_name.as_bytes()
+    }
+
+    fn negotiate(
+        &mut self,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        session: &mut Session,
+    ) -> std::io::Result<Negotiated> {
+        writer.write_all(&NBD_MAGIC.to_be_bytes())?;
+        writer.write_all(&NBD_IHAVEOPT.to_be_bytes())?;
+        writer.write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())?;
+        writer.flush()?;
+
+        let client_flags = read_u32(reader)?;
+        if client_flags & NBD_FLAG_C_FIXED_NEWSTYLE == 0 {
+            return Err(invalid_data(
+                "Client does not support fixed newstyle negotiation",
+            ));
+        }
+        let no_zeroes = client_flags & NBD_FLAG_C_NO_ZEROES != 0;
+
+        loop {
+            if read_u64(reader)? != NBD_IHAVEOPT {
+                return Err(invalid_data("Invalid option magic"));
+            }
+            let option = read_u32(reader)?;
+            let len = read_u32(reader)?;
+            if len > MAX_OPTION_LEN {
+                return Err(invalid_data("Option payload too large"));
+            }
+            let mut data = vec![0u8; len as usize];
+            reader.read_exact(&mut data)?;
+
+            match option {
+                NBD_OPT_EXPORT_NAME => {
+                    if !self.is_our_export(&data) {
+                        // There is no way to report errors for this option
+                        return Ok(Negotiated::Closed);
+                    }
+                    writer.write_all(&self.export.export_size().to_be_bytes())?;
+                    writer.write_all(&self.transmission_flags().to_be_bytes())?;
+                    if !no_zeroes {
+                        writer.write_all(&[0u8; 124])?;
+                    }
+                    writer.flush()?;
+                    return Ok(Negotiated::Transmission);
+                }
+                NBD_OPT_ABORT => {
+                    write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                    writer.flush()?;
+                    return Ok(Negotiated::Closed);
+                }
+                NBD_OPT_LIST => {
+                    let name = self.options.export_name.as_bytes();
+                    let mut reply = (name.len() as u32).to_be_bytes().to_vec();
+                    reply.extend_from_slice(name);
+                    write_option_reply(writer, option, NBD_REP_SERVER, &reply)?;
+                    write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                }
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    let Some(name) = parse_export_name(&data) else {
+                        write_option_reply(writer, option, NBD_REP_ERR_INVALID, &[])?;
+                        writer.flush()?;
+                        continue;
+                    };
+                    if !self.is_our_export(name) {
+                        write_option_reply(writer, option, NBD_REP_ERR_UNKNOWN, &[])?;
+                        writer.flush()?;
+                        continue;
+                    }
+
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&self.export.export_size().to_be_bytes());
+                    info.extend_from_slice(&self.transmission_flags().to_be_bytes());
+                    write_option_reply(writer, option, NBD_REP_INFO, &info)?;
+
+                    let mut info = NBD_INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                    info.extend_from_slice(&1u32.to_be_bytes());
+                    info.extend_from_slice(&4096u32.to_be_bytes());
+                    info.extend_from_slice(&MAX_REQUEST_LEN.to_be_bytes());
+                    write_option_reply(writer, option, NBD_REP_INFO, &info)?;
+
+                    write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                    writer.flush()?;
+                    if option == NBD_OPT_GO {
+                        return Ok(Negotiated::Transmission);
+                    }
+                    continue;
+                }
+                NBD_OPT_STRUCTURED_REPLY => {
+                    if !data.is_empty() {
+                        write_option_reply(writer, option, NBD_REP_ERR_INVALID, &[])?;
+                    } else {
+                        session.structured_replies = true;
+                        write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                    }
+                }
+                NBD_OPT_LIST_META_CONTEXT | NBD_OPT_SET_META_CONTEXT => {
+                    let set = option == NBD_OPT_SET_META_CONTEXT;
+                    match parse_meta_context_queries(&data) {
+                        None => write_option_reply(writer, option, NBD_REP_ERR_INVALID, &[])?,
+                        Some(_) if set && !session.structured_replies => {
+                            write_option_reply(writer, option, NBD_REP_ERR_INVALID, &[])?
+                        }
+                        Some((name, queries)) => {
+                            if !self.is_our_export(name) {
+                                write_option_reply(writer, option, NBD_REP_ERR_UNKNOWN, &[])?;
+                                writer.flush()?;
+                                continue;
+                            }
+
+                            // An empty query list means "all contexts" when listing
+                            let matched = queries.iter().any(|&q| {
+                                q == BASE_ALLOCATION.as_bytes() || (!set && q == b"base:")
+                            }) || (!set && queries.is_empty());
+                            if set {
+                                session.base_allocation = matched;
+                            }
+                            if matched {
+                                let mut reply = BASE_ALLOCATION_ID.to_be_bytes().to_vec();
+                                reply.extend_from_slice(BASE_ALLOCATION.as_bytes());
+                                write_option_reply(writer, option, NBD_REP_META_CONTEXT, &reply)?;
+                            }
+                            write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                        }
+                    }
+                }
+                _ => write_option_reply(writer, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+            writer.flush()?;
+        }
+    }
+
+    fn transmission(
+        &mut self,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        session: &Session,
+    ) -> std::io::Result<()> {
+        let read_only = self.read_only();
+        let size = self.export.export_size();
+
+        loop {
+            if read_u32(reader)? != NBD_REQUEST_MAGIC {
+                return Err(invalid_data("Invalid request magic"));
+            }
+            let flags = read_u16(reader)?;
+            let command = read_u16(reader)?;
+            let cookie = read_u64(reader)?;
+            let offset = read_u64(reader)?;
+            let len = read_u32(reader)?;
+
+            let mut payload = Vec::new();
+            if command == NBD_CMD_WRITE {
+                if len > MAX_REQUEST_LEN {
+                    // The payload can not be skipped safely, the connection is unusable
+                    return Err(invalid_data("Write request too large"));
+                }
+                payload.resize(len as usize, 0);
+                reader.read_exact(&mut payload)?;
+            }
+
+            let range = offset..offset.saturating_add(len as u64);
+            let in_bounds = range.end <= size;
+            let reply = Reply {
+                cookie,
+                structured: session.structured_replies,
+            };
+
+            let result = match command {
+                NBD_CMD_DISC => {
+                    writer.flush()?;
+                    return Ok(());
+                }
+                _ if !in_bounds => Err(if command == NBD_CMD_WRITE {
+                    NBD_ENOSPC
+                } else {
+                    NBD_EINVAL
+                }),
+                NBD_CMD_READ => {
+                    if len > MAX_REQUEST_LEN {
+                        Err(NBD_EOVERFLOW)
+                    } else {
+                        match self.handle_read(writer, &reply, range) {
+                            Ok(()) => continue,
+                            Err(e) => Err(io_error_code(&e)),
+                        }
+                    }
+                }
+                NBD_CMD_BLOCK_STATUS if session.base_allocation => {
+                    match self.block_status(range, flags & NBD_CMD_FLAG_REQ_ONE != 0) {
+                        Ok(descriptors) => {
+                            let mut chunk = BASE_ALLOCATION_ID.to_be_bytes().to_vec();
+                            chunk.extend_from_slice(&descriptors);
+                            reply.chunk(
+                                writer,
+                                NBD_REPLY_FLAG_DONE,
+                                NBD_REPLY_TYPE_BLOCK_STATUS,
+                                &chunk,
+                            )?;
+                            writer.flush()?;
+                            continue;
+                        }
+                        Err(e) => Err(io_error_code(&e)),
+                    }
+                }
+                NBD_CMD_FLUSH => self.flush().map_err(|e| io_error_code(&e)),
+                NBD_CMD_WRITE | NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES if read_only => Err(NBD_EPERM),
+                NBD_CMD_WRITE => self
+                    .write(offset, &payload, flags)
+                    .map_err(|e| io_error_code(&e)),
+                NBD_CMD_TRIM => self
+                    .export
+                    .trim(range)
+                    .and_then(|_| self.flush_if_fua(flags))
+                    .map_err(|e| io_error_code(&e)),
+                NBD_CMD_WRITE_ZEROES => self
+                    .write_zeroes(range, flags)
+                    .map_err(|e| io_error_code(&e)),
+                // Only valid after a metadata context was negotiated
+                NBD_CMD_BLOCK_STATUS => Err(NBD_EINVAL),
+                _ => Err(NBD_ENOTSUP),
+            };
+
+            match result {
+                Ok(()) => reply.done(writer)?,
+                Err(code) => reply.error(writer, code)?,
+            }
+            writer.flush()?;
+        }
+    }
+
+    fn handle_read(
+        &mut self,
+        writer: &mut impl Write,
+        reply: &Reply,
+        range: std::ops::Range<u64>,
+    ) -> std::io::Result<()> {
+        let mut data = vec![0u8; (range.end - range.start) as usize];
+
+        if !reply.structured {
+            self.export.read_exact_at(range.start, &mut data)?;
+            reply.simple(writer, 0, &data)?;
+            return writer.flush();
+        }
+
+        // Read everything up front so an error can still be reported as a single reply
+        let extents = self.all_extents(range.clone())?;
+        let mut pos = range.start;
+        for extent in &extents {
+            if !extent.hole {
+                let start = (pos - range.start) as usize;
+                self.export
+                    .read_exact_at(pos, &mut data[start..start + extent.len as usize])?;
+            }
+            pos += extent.len;
+        }
+
+        let mut pos = range.start;
+        for extent in extents {
+            if extent.hole {
+                let mut chunk = pos.to_be_bytes().to_vec();
+                chunk.extend_from_slice(&(extent.len as u32).to_be_bytes());
+                reply.chunk(writer, 0, NBD_REPLY_TYPE_OFFSET_HOLE, &chunk)?;
+            } else {
+                let start = (pos - range.start) as usize;
+                let mut chunk = pos.to_be_bytes().to_vec();
+                chunk.extend_from_slice(&data[start..start + extent.len as usize]);
+                reply.chunk(writer, 0, NBD_REPLY_TYPE_OFFSET_DATA, &chunk)?;
+            }
+            pos += extent.len;
+        }
+        reply.done(writer)?;
+        writer.flush()
+    }
+
+    /// Allocation state of the whole of `range`
+    fn all_extents(&self, range: std::ops::Range<u64>) -> std::io::Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut pos = range.start;
+        while pos < range.end {
+            let next = self.export.extents(pos..range.end)?;
+            if next.is_empty() {
+                return Err(std::io::Error::other("Export returned no extents"));
+            }
+            for extent in next {
+                let len = std::cmp::min(extent.len, range.end - pos);
+                extents.push(Extent { len, ..extent });
+                pos += len;
+            }
+        }
+        Ok(extents)
+    }
+
+    fn block_status(
+        &self,
+        range: std::ops::Range<u64>,
+        only_one: bool,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut extents = self.export.extents(range.clone())?;
+        if extents.is_empty() {
+            return Err(std::io::Error::other("Export returned no extents"));
+        }
+        if only_one {
+            extents.truncate(1);
+        }
+
+        let mut descriptors = Vec::with_capacity(extents.len() * 8);
+        for extent in extents {
+            // Descriptor lengths are 32 bits wide, larger extents are reported partially
+            let len = extent.len.min(u32::MAX as u64 & !0xFFF) as u32;
+            let status = if extent.hole {
+                NBD_STATE_HOLE | NBD_STATE_ZERO
+            } else {
+                0
+            };
+            descriptors.extend_from_slice(&len.to_be_bytes());
+            descriptors.extend_from_slice(&status.to_be_bytes());
+        }
+        Ok(descriptors)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], flags: u16) -> std::io::Result<()> {
+        self.writer()?.write_all_at(offset, data)?;
+        self.flush_if_fua(flags)
+    }
+
+    fn write_zeroes(&mut self, range: std::ops::Range<u64>, flags: u16) -> std::io::Result<()> {
+        if flags & NBD_CMD_FLAG_NO_HOLE == 0 {
+            // Lets whole blocks become holes. Trimming is optional for exports, so the range is
+            // still written below, zeros written to released blocks are not stored again.
+            self.export.trim(range.clone())?;
+        }
+
+        let zeros = vec![0u8; 1024 * 1024];
+        let mut pos = range.start;
+        while pos < range.end {
+            let len = std::cmp::min(zeros.len() as u64, range.end - pos) as usize;
+            self.writer()?.write_all_at(pos, &zeros[..len])?;
+            pos += len as u64;
+        }
+        self.flush_if_fua(flags)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.export.writer() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn flush_if_fua(&mut self, flags: u16) -> std::io::Result<()> {
+        if flags & NBD_CMD_FLAG_FUA != 0 {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn writer(&mut self) -> std::io::Result<&mut dyn WriteAt> {
+        self.export.writer().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Export is read-only")
+        })
+    }
+}
+
+struct Reply {
+    cookie: u64,
+    structured: bool,
+}
+
+impl Reply {
+    fn simple(&self, writer: &mut impl Write, error: u32, data: &[u8]) -> std::io::Result<()> {
+        writer.write_all(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+        writer.write_all(&error.to_be_bytes())?;
+        writer.write_all(&self.cookie.to_be_bytes())?;
+        writer.write_all(data)
+    }
+
+    fn chunk(
+        &self,
+        writer: &mut impl Write,
+        flags: u16,
+        kind: u16,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        writer.write_all(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes())?;
+        writer.write_all(&flags.to_be_bytes())?;
+        writer.write_all(&kind.to_be_bytes())?;
+        writer.write_all(&self.cookie.to_be_bytes())?;
+        writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+        writer.write_all(payload)
+    }
+
+    /// Successful completion without data
+    fn done(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        if self.structured {
+            self.chunk(writer, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_NONE, &[])
+        } else {
+            self.simple(writer, 0, &[])
+        }
+    }
+
+    fn error(&self, writer: &mut impl Write, code: u32) -> std::io::Result<()> {
+        if self.structured {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(&0u16.to_be_bytes()); // No message
+            self.chunk(writer, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_ERROR, &payload)
+        } else {
+            self.simple(writer, code, &[])
+        }
+    }
+}
+
+fn write_option_reply(
+    writer: &mut impl Write,
+    option: u32,
+    reply_type: u32,
+    data: &[u8],
+) -> std::io::Result<()> {
+    writer.write_all(&NBD_OPTION_REPLY_MAGIC.to_be_bytes())?;
+    writer.write_all(&option.to_be_bytes())?;
+    writer.write_all(&reply_type.to_be_bytes())?;
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+/// Parses the export name of an `NBD_OPT_INFO`/`NBD_OPT_GO` payload
+fn parse_export_name(data: &[u8]) -> Option<&[u8]> {
+    let (name, rest) = parse_length_prefixed(data)?;
+    let count = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+    // The client's information requests are ignored, everything is always sent
+    (rest.len() == 2 + count * 2).then_some(name)
+}
+
+/// Parses the export name and queries of a meta context option payload
+fn parse_meta_context_queries(data: &[u8]) -> Option<(&[u8], Vec<&[u8]>)> {
+    let (name, rest) = parse_length_prefixed(data)?;
+    let count = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+    let mut rest = &rest[4..];
+    let mut queries = Vec::new();
+    for _ in 0..count {
+        let (query, remaining) = parse_length_prefixed(rest)?;
+        queries.push(query);
+        rest = remaining;
+    }
+    rest.is_empty().then_some((name, queries))
+}
+
+fn parse_length_prefixed(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let value = data.get(4..4 + len)?;
+    Some((value, &data[4 + len..]))
+}
+
+fn io_error_code(error: &std::io::Error) -> u32 {
+    match error.kind() {
+        std::io::ErrorKind::PermissionDenied => NBD_EPERM,
+        std::io::ErrorKind::InvalidInput => NBD_EINVAL,
+        std::io::ErrorKind::Unsupported => NBD_ENOTSUP,
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::WriteZero => NBD_ENOSPC,
+        _ => NBD_EIO,
+    }
+}
+
+fn invalid_data(message: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn read_u16(reader: &mut impl Read) -> std::io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Lets a buffered reader and writer share one bidirectional stream
+struct RefReader<'a, S>(&'a std::cell::RefCell<S>);
+struct RefWriter<'a, S>(&'a std::cell::RefCell<S>);
+
+impl<S: Read> Read for RefReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl<S: Write> Write for RefWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
@@ -0,0 +1,496 @@
+//! OVA appliances: tar archives of an OVF descriptor, an optional manifest and disk images.
+//!
+//! Members are read in place through [`Ova::member`], nothing is extracted.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use positioned_io2::ReadAt;
+use roxmltree::Node;
+use sha2::Digest;
+
+use crate::VdiDisk;
+use crate::header::VdiHeader;
+use crate::slice::OwnedSlice;
+
+/// Format URI written in the descriptor for VDI disks
+pub const VDI_FORMAT: &str = "http://www.virtualbox.org/ovf/machine/vdi";
+
+const OVF_NAMESPACE: &str = "http://schemas.dmtf.org/ovf/envelope/1";
+
+/// Largest descriptor read into memory, real ones are a few KiB
+const MAX_DESCRIPTOR_SIZE: u64 = 4 * 1024 * 1024;
+
+/// File of the package listed in the descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OvfFile {
+    pub id: String,
+    /// Name of the member in the archive
+    pub href: String,
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OvfDisk {
+    pub id: String,
+    /// Id of the [`OvfFile`] holding the disk image, `None` for blank disks
+    pub file_ref: Option<String>,
+    /// Size of the virtual disk in bytes
+    pub capacity: u64,
+    /// Format URI of the disk image, eg. streamOptimized VMDK
+    pub format: String,
+}
+
+/// OVF descriptor of an appliance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ovf {
+    pub files: Vec<OvfFile>,
+    pub disks: Vec<OvfDisk>,
+    /// Ids of the virtual machines of the appliance
+    pub virtual_systems: Vec<String>,
+}
+
+impl Ovf {
+    pub fn parse(xml: &str) -> anyhow::Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let envelope = doc.root_element();
+
+        let files = match child(envelope, "References") {
+            Some(references) => children(references, "File")
+                .map(|file| {
+                    Ok(OvfFile {
+                        id: required(file, "id")?.to_string(),
+                        href: required(file, "href")?.to_string(),
+                        size: attribute(file, "size").map(str::parse).transpose()?,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            None => Vec::new(),
+        };
+
+        let disks = match child(envelope, "DiskSection") {
+            Some(section) => children(section, "Disk")
+                .map(|disk| {
+                    let capacity: u64 = required(disk, "capacity")?.parse()?;
+                    let units = attribute(disk, "capacityAllocationUnits").unwrap_or("byte");
+                    Ok(OvfDisk {
+                        id: required(disk, "diskId")?.to_string(),
+                        file_ref: attribute(disk, "fileRef").map(str::to_string),
+                        capacity: capacity
+                            .checked_mul(parse_units(units)?)
+                            .ok_or_else(|| anyhow::anyhow!("Disk capacity is too large"))?,
+                        format: attribute(disk, "format").unwrap_or_default().to_string(),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            None => Vec::new(),
+        };
+
+        let mut virtual_systems = Vec::new();
+        for node in envelope.descendants() {
+            if node.is_element() && node.tag_name().name() == "VirtualSystem" {
+                virtual_systems.push(required(node, "id")?.to_string());
+            }
+        }
+
+        Ok(Self {
+            files,
+            disks,
+            virtual_systems,
+        })
+    }
+
+    pub fn file(&self, id: &str) -> Option<&OvfFile> {
+        self.files.iter().find(|f| f.id == id)
+    }
+}
+
+/// Member of the archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OvaEntry {
+    pub name: String,
+    /// Offset of the member data in the archive
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Reader of the archive, shared with the members opened from it
+pub struct ArchiveReader<R>(Arc<R>);
+
+impl<R: ReadAt> ReadAt for ArchiveReader<R> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read_at(pos, buf)
+    }
+}
+
+pub struct Ova<R = File> {
+    reader: Arc<R>,
+    entries: Vec<OvaEntry>,
+    descriptor: Ovf,
+}
+
+impl Ova<File> {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl<R: ReadAt> Ova<R> {
+    /// Indexes the members of the archive and parses its descriptor
+    pub fn new(reader: R) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        let mut archive = tar::Archive::new(positioned_io2::Cursor::new(&reader));
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            entries.push(OvaEntry {
+                name: entry.path()?.to_string_lossy().into_owned(),
+                offset: entry.raw_file_position(),
+                size: entry.size(),
+            });
+        }
+
+        // OVF requires the descriptor to be the first member, but not every tool follows that
+        let ovf = entries
+            .iter()
+            .find(|e| e.name.to_ascii_lowercase().ends_with(".ovf"))
+            .ok_or_else(|| anyhow::anyhow!("Archive does not contain an OVF descriptor"))?;
+        anyhow::ensure!(
+            ovf.size <= MAX_DESCRIPTOR_SIZE,
+            "OVF descriptor {} is too large ({} bytes)",
+            ovf.name,
+            ovf.size
+        );
+        let mut xml = vec![0u8; ovf.size as usize];
+        reader.read_exact_at(ovf.offset, &mut xml)?;
+        let descriptor = Ovf::parse(std::str::from_utf8(&xml)?)?;
+
+        Ok(Self {
+            reader: Arc::new(reader),
+            entries,
+            descriptor,
+        })
+    }
+
+    pub fn entries(&self) -> &[OvaEntry] {
+        &self.entries
+    }
+
+    pub fn descriptor(&self) -> &Ovf {
+        &self.descriptor
+    }
+
+    /// Returns a reader over the data of member `name`
+    pub fn member(&self, name: &str) -> Option<OwnedSlice<ArchiveReader<R>>> {
+        let entry = self.entries.iter().find(|e| e.name == name)?;
+        let reader = ArchiveReader(self.reader.clone());
+        OwnedSlice::new(reader, entry.offset..entry.offset + entry.size).ok()
+    }
+
+    /// Opens a disk of the descriptor, its image must be a VDI
+    pub fn open_disk(&self, disk: &OvfDisk) -> anyhow::Result<VdiDisk>
+    where
+        R: 'static,
+    {
+        let file = disk
+            .file_ref
+            .as_deref()
+            .and_then(|id| self.descriptor.file(id))
+            .ok_or_else(|| anyhow::anyhow!("Disk {} has no image", disk.id))?;
+        let member = self
+            .member(&file.href)
+            .ok_or_else(|| anyhow::anyhow!("Archive does not contain {}", file.href))?;
+
+        let mut signature = [0u8; 4];
+        member.read_exact_at(
+            std::mem::offset_of!(VdiHeader, signature) as u64,
+            &mut signature,
+        )?;
+        anyhow::ensure!(
+            u32::from_le_bytes(signature) == VdiHeader::SIGNATURE,
+            "Disk {} is not a VDI image ({})",
+            disk.id,
+            disk.format
+        );
+        VdiDisk::open(Box::new(member))
+    }
+
+    /// Checks the members against the SHA1 or SHA256 digests of the manifest. Returns `false`
+    /// if the archive has no manifest.
+    pub fn verify_manifest(&self) -> anyhow::Result<bool> {
+        let Some(manifest) = self
+            .entries
+            .iter()
+            .find(|e| e.name.to_ascii_lowercase().ends_with(".mf"))
+        else {
+            return Ok(false);
+        };
+        let mut text = String::new();
+        self.member(&manifest.name)
+            .ok_or_else(|| anyhow::anyhow!("Archive does not contain {}", manifest.name))?
+            .read_to_string(&mut text)?;
+
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            // eg. `SHA256(disk.vdi)= 0123...`
+            let (algorithm, rest) = line
+                .split_once('(')
+                .ok_or_else(|| anyhow::anyhow!("Malformed manifest line: {line}"))?;
+            let (name, expected) = rest
+                .split_once(")=")
+                .ok_or_else(|| anyhow::anyhow!("Malformed manifest line: {line}"))?;
+            let mut member = self
+                .member(name)
+                .ok_or_else(|| anyhow::anyhow!("Manifest lists missing member {name}"))?;
+
+            let digest = match algorithm.trim() {
+                "SHA1" => hash::<sha1::Sha1>(&mut member)?,
+                "SHA256" => hash::<sha2::Sha256>(&mut member)?,
+                other => anyhow::bail!("Unsupported manifest digest {other}"),
+            };
+            anyhow::ensure!(
+                digest.eq_ignore_ascii_case(expected.trim()),
+                "Digest mismatch for {name}"
+            );
+        }
+        Ok(true)
+    }
+}
+
+/// Options of [`write_ova`]
+#[derive(Debug, Clone)]
+pub struct OvaOptions {
+    /// Name of the virtual machine, also used to name the members
+    pub name: String,
+    pub cpus: u32,
+    pub memory_mb: u64,
+}
+
+impl Default for OvaOptions {
+    fn default() -> Self {
+        Self {
+            name: "vm".to_string(),
+            cpus: 1,
+            memory_mb: 1024,
+        }
+    }
+}
+
+/// Writes an appliance with a single virtual machine using `disks`, attached in order to a
+/// SATA controller. The archive contains the descriptor, a SHA256 manifest and the disks.
+pub fn write_ova<W: Write>(out: W, disks: &[PathBuf], options: &OvaOptions) -> anyhow::Result<()> {
+    struct Member {
+        name: String,
+        path: PathBuf,
+        size: u64,
+        capacity: u64,
+        digest: String,
+    }
+
+    let mut members = Vec::new();
+    for (i, path) in disks.iter().enumerate() {
+        let mut file = File::open(path)?;
+        let capacity = VdiDisk::open(Box::new(file.try_clone()?))?
+            .header
+            .disk_size
+            .get();
+        members.push(Member {
+            name: format!("{}-disk{:03}.vdi", options.name, i + 1),
+            path: path.clone(),
+            size: file.metadata()?.len(),
+            capacity,
+            digest: hash::<sha2::Sha256>(&mut file)?,
+        });
+    }
+
+    let mut references = String::new();
+    let mut disk_section = String::new();
+    let mut disk_items = String::new();
+    for (i, member) in members.iter().enumerate() {
+        let n = i + 1;
+        references.push_str(&format!(
+            "    <File ovf:id=\"file{n}\" ovf:href=\"{}\" ovf:size=\"{}\"/>\n",
+            escape(&member.name),
+            member.size
+        ));
+        disk_section.push_str(&format!(
+            "    <Disk ovf:capacity=\"{}\" ovf:diskId=\"vmdisk{n}\" ovf:fileRef=\"file{n}\" ovf:format=\"{VDI_FORMAT}\"/>\n",
+            member.capacity
+        ));
+        disk_items.push_str(&format!(
+            r#"      <Item>
+        <rasd:AddressOnParent>{i}</rasd:AddressOnParent>
+        <rasd:Caption>disk{n}</rasd:Caption>
+        <rasd:Description>Disk Image</rasd:Description>
+        <rasd:ElementName>disk{n}</rasd:ElementName>
+        <rasd:HostResource>/disk/vmdisk{n}</rasd:HostResource>
+        <rasd:InstanceID>{}</rasd:InstanceID>
+        <rasd:Parent>3</rasd:Parent>
+        <rasd:ResourceType>17</rasd:ResourceType>
+      </Item>
+"#,
+            n + 3
+        ));
+    }
+
+    let name = escape(&options.name);
+    let (cpus, memory) = (options.cpus, options.memory_mb);
+    let ovf = format!(
+        r#"<?xml version="1.0"?>
+<Envelope ovf:version="1.0" xml:lang="en-US" xmlns="{OVF_NAMESPACE}" xmlns:ovf="{OVF_NAMESPACE}" xmlns:rasd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_ResourceAllocationSettingData" xmlns:vssd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_VirtualSystemSettingData" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <References>
+{references}  </References>
+  <DiskSection>
+    <Info>List of the virtual disks used in the package</Info>
+{disk_section}  </DiskSection>
+  <VirtualSystem ovf:id="{name}">
+    <Info>A virtual machine</Info>
+    <VirtualHardwareSection>
+      <Info>Virtual hardware requirements for a virtual machine</Info>
+      <System>
+        <vssd:ElementName>Virtual Hardware Family</vssd:ElementName>
+        <vssd:InstanceID>0</vssd:InstanceID>
+        <vssd:VirtualSystemIdentifier>{name}</vssd:VirtualSystemIdentifier>
+        <vssd:VirtualSystemType>virtualbox-2.2</vssd:VirtualSystemType>
+      </System>
+      <Item>
+        <rasd:Caption>{cpus} virtual CPU</rasd:Caption>
+        <rasd:Description>Number of virtual CPUs</rasd:Description>
+        <rasd:ElementName>{cpus} virtual CPU</rasd:ElementName>
+        <rasd:InstanceID>1</rasd:InstanceID>
+        <rasd:ResourceType>3</rasd:ResourceType>
+        <rasd:VirtualQuantity>{cpus}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:AllocationUnits>MegaBytes</rasd:AllocationUnits>
+        <rasd:Caption>{memory} MB of memory</rasd:Caption>
+        <rasd:Description>Memory Size</rasd:Description>
+        <rasd:ElementName>{memory} MB of memory</rasd:ElementName>
+        <rasd:InstanceID>2</rasd:InstanceID>
+        <rasd:ResourceType>4</rasd:ResourceType>
+        <rasd:VirtualQuantity>{memory}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:Address>0</rasd:Address>
+        <rasd:Caption>sataController0</rasd:Caption>
+        <rasd:Description>SATA Controller</rasd:Description>
+        <rasd:ElementName>sataController0</rasd:ElementName>
+        <rasd:InstanceID>3</rasd:InstanceID>
+        <rasd:ResourceSubType>AHCI</rasd:ResourceSubType>
+        <rasd:ResourceType>20</rasd:ResourceType>
+      </Item>
+{disk_items}    </VirtualHardwareSection>
+  </VirtualSystem>
+</Envelope>
+"#
+    );
+
+    let ovf_name = format!("{}.ovf", options.name);
+    let mut manifest = format!(
+        "SHA256({ovf_name})= {}\n",
+        hash::<sha2::Sha256>(&mut ovf.as_bytes())?
+    );
+    for member in &members {
+        manifest.push_str(&format!("SHA256({})= {}\n", member.name, member.digest));
+    }
+
+    // The descriptor must come first, followed by the manifest
+    let mut builder = tar::Builder::new(out);
+    append(&mut builder, &ovf_name, ovf.len() as u64, ovf.as_bytes())?;
+    let manifest_name = format!("{}.mf", options.name);
+    append(
+        &mut builder,
+        &manifest_name,
+        manifest.len() as u64,
+        manifest.as_bytes(),
+    )?;
+    for member in &members {
+        let file = File::open(&member.path)?;
+        anyhow::ensure!(
+            file.metadata()?.len() == member.size,
+            "{} changed while the appliance was written",
+            member.path.display()
+        );
+        append(&mut builder, &member.name, member.size, file)?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn append<W: Write>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    size: u64,
+    data: impl Read,
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+    builder.append_data(&mut header, name, data)
+}
+
+/// Hex digest of everything `reader` returns
+fn hash<D: Digest + Write>(reader: &mut impl Read) -> std::io::Result<String> {
+    let mut hasher = D::new();
+    std::io::copy(reader, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Parses `capacityAllocationUnits`, eg. `byte * 2^30`
+fn parse_units(units: &str) -> anyhow::Result<u64> {
+    let units: String = units.chars().filter(|c| !c.is_whitespace()).collect();
+    match units.strip_prefix("byte") {
+        Some("") => Ok(1),
+        Some(power) => {
+            let exponent = power
+                .strip_prefix("*2^")
+                .ok_or_else(|| anyhow::anyhow!("Unsupported allocation units {units}"))?;
+            1u64.checked_shl(exponent.parse()?)
+                .ok_or_else(|| anyhow::anyhow!("Unsupported allocation units {units}"))
+        }
+        None => anyhow::bail!("Unsupported allocation units {units}"),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+/// Attribute in the OVF namespace, descriptors in the wild also use unqualified ones
+fn attribute<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attribute((OVF_NAMESPACE, name))
+        .or_else(|| node.attribute(name))
+}
+
+fn required<'a>(node: Node<'a, '_>, name: &str) -> anyhow::Result<&'a str> {
+    attribute(node, name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} element is missing the {name} attribute",
+            node.tag_name().name()
+        )
+    })
+}
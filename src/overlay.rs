@@ -0,0 +1,224 @@
+//! Copy-on-write overlay, letting tools write to a disk that must not be modified.
+//!
+//! The base disk is only ever read. Written blocks are copied to a delta, kept in memory or in a
+//! temporary file, which can be exported as a differencing image or discarded.
+
+use std::io::{Read, Seek, Write};
+
+use positioned_io2::{ReadAt, WriteAt};
+
+use crate::header::VdiHeader;
+use crate::{Storage, VdiDisk};
+
+pub struct Overlay {
+    base: VdiDisk,
+    delta: Box<dyn Storage>,
+    /// Offsets in the delta of the blocks written through the overlay
+    blocks: Vec<Option<u64>>,
+    delta_len: u64,
+    position: u64,
+}
+
+impl Overlay {
+    /// Wraps `base`, keeping the delta in memory
+    pub fn new(base: VdiDisk) -> Self {
+        Self::with_storage(base, Box::new(Vec::new()))
+    }
+
+    /// Wraps `base`, keeping the delta in an anonymous temporary file that is deleted with the
+    /// overlay
+    pub fn with_temp_file(base: VdiDisk) -> std::io::Result<Self> {
+        Ok(Self::with_storage(base, Box::new(tempfile::tempfile()?)))
+    }
+
+    /// Wraps `base`, keeping the delta on `delta`, which is overwritten
+    pub fn with_storage(base: VdiDisk, delta: Box<dyn Storage>) -> Self {
+        Self {
+            blocks: vec![None; base.block_offsets.len()],
+            base,
+            delta,
+            delta_len: 0,
+            position: 0,
+        }
+    }
+
+    pub fn base(&self) -> &VdiDisk {
+        &self.base
+    }
+
+    pub fn disk_size(&self) -> u64 {
+        self.base.header.disk_size.get()
+    }
+
+    /// Number of blocks written through the overlay
+    pub fn modified_blocks(&self) -> usize {
+        self.blocks.iter().filter(|b| b.is_some()).count()
+    }
+
+    /// Drops the delta, returning the untouched base disk
+    pub fn discard(self) -> VdiDisk {
+        self.base
+    }
+
+    /// Writes the delta to `storage` as a differencing image of the base disk, which can be
+    /// attached to it with [`VdiDisk::set_parent`]. Modified blocks containing only zeros are
+    /// marked as zero instead of being stored.
+    pub fn export<S: Storage + 'static>(&self, mut storage: Box<S>) -> anyhow::Result<VdiDisk> {
+        let mut header = self.base.header;
+        header.image_type.set(VdiHeader::TYPE_DIFFERENCING);
+        header.uuid_image = VdiHeader::uuid_to_disk(uuid::Uuid::new_v4());
+        header.uuid_last_snap = VdiHeader::uuid_to_disk(uuid::Uuid::new_v4());
+        header.uuid_link = self.base.header.uuid_image;
+        header.uuid_parent = self.base.header.uuid_last_snap;
+
+        let data_offset = header.data_offset.get() as u64;
+        let block_size = self.base.block_size;
+        storage.set_len(0)?;
+        storage.set_len(data_offset)?;
+
+        let mut entries = vec![VdiHeader::BLOCK_FREE; self.blocks.len()];
+        let mut block = vec![0u8; block_size];
+        let mut used = 0;
+        for (entry, offset) in entries.iter_mut().zip(&self.blocks) {
+            let Some(offset) = *offset else {
+                continue;
+            };
+            self.delta.read_exact_at(offset, &mut block)?;
+            if block.iter().all(|&b| b == 0) {
+                *entry = VdiHeader::BLOCK_ZERO;
+            } else {
+                storage.write_all_at(data_offset + used as u64 * block_size as u64, &block)?;
+                *entry = used;
+                used += 1;
+            }
+        }
+        header.blocks_allocated.set(used);
+
+        let raw: Vec<u8> = entries.iter().flat_map(|e| e.to_le_bytes()).collect();
+        storage.write_all_at(header.block_offsets_offset.get() as u64, &raw)?;
+        storage.write_all_at(0, bytemuck::bytes_of(&header))?;
+        storage.flush()?;
+        VdiDisk::open_writable(storage)
+    }
+
+    /// Copies block `index` of the base disk to the delta, unless `whole` is set because the
+    /// caller overwrites all of it
+    fn copy_block(&mut self, index: usize, whole: bool) -> std::io::Result<u64> {
+        let block_size = self.base.block_size;
+        let offset = self.delta_len;
+        if whole {
+            self.delta.set_len(offset + block_size as u64)?;
+        } else {
+            let mut block = vec![0u8; block_size];
+            let start = index as u64 * block_size as u64;
+            let mut filled = 0;
+            while filled < block.len() {
+                match self
+                    .base
+                    .read_at(start + filled as u64, &mut block[filled..])?
+                {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            self.delta.write_all_at(offset, &block)?;
+        }
+        self.delta_len += block_size as u64;
+        self.blocks[index] = Some(offset);
+        Ok(offset)
+    }
+}
+
+impl ReadAt for Overlay {
+    fn read_at(&self, mut pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let block_size = self.base.block_size;
+        let len = std::cmp::min(buf.len() as u64, self.disk_size().saturating_sub(pos));
+        let buf = &mut buf[..len as usize];
+        let mut total_read = 0;
+        while total_read < buf.len() {
+            let block_index = (pos / block_size as u64) as usize;
+            let block_offset = (pos % block_size as u64) as usize;
+            if block_index >= self.blocks.len() {
+                break; // EOF
+            }
+
+            let to_read = std::cmp::min(buf.len() - total_read, block_size - block_offset);
+            let chunk = &mut buf[total_read..total_read + to_read];
+            let n = match self.blocks[block_index] {
+                Some(offset) => {
+                    self.delta
+                        .read_exact_at(offset + block_offset as u64, chunk)?;
+                    to_read
+                }
+                None => self.base.read_at(pos, chunk)?,
+            };
+            if n == 0 {
+                break; // EOF
+            }
+            total_read += n;
+            pos += n as u64;
+        }
+        Ok(total_read)
+    }
+}
+
+impl WriteAt for Overlay {
+    fn write_at(&mut self, mut pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let block_size = self.base.block_size;
+        let len = std::cmp::min(buf.len() as u64, self.disk_size().saturating_sub(pos));
+        let buf = &buf[..len as usize];
+        let mut total_written = 0;
+        while total_written < buf.len() {
+            let block_index = (pos / block_size as u64) as usize;
+            let block_offset = (pos % block_size as u64) as usize;
+            if block_index >= self.blocks.len() {
+                break; // EOF
+            }
+
+            let to_write = std::cmp::min(buf.len() - total_written, block_size - block_offset);
+            let offset = match self.blocks[block_index] {
+                Some(offset) => offset,
+                None => self.copy_block(block_index, to_write == block_size)?,
+            };
+            self.delta.write_all_at(
+                offset + block_offset as u64,
+                &buf[total_written..total_written + to_write],
+            )?;
+
+            total_written += to_write;
+            pos += to_write as u64;
+        }
+        Ok(total_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.delta.flush()
+    }
+}
+
+impl Read for Overlay {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.read_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Overlay {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.write_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        WriteAt::flush(self)
+    }
+}
+
+impl Seek for Overlay {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.position = crate::slice::seek_within(self.disk_size(), self.position, pos)?;
+        Ok(self.position)
+    }
+}
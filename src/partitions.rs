@@ -0,0 +1,411 @@
+use positioned_io2::ReadAt;
+use uuid::Uuid;
+
+use crate::VdiDisk;
+use crate::slice::{Slice, SliceMut};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Upper bound on the length of an EBR chain, protects against loops in corrupted tables
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MAX_ENTRIES: u32 = 16384;
+/// Upper bound on the size of the partition entry array, the usual one takes 16 KiB
+const GPT_MAX_ENTRY_ARRAY_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTableKind {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Debug, Clone)]
+pub enum PartitionKind {
+    Mbr {
+        type_id: u8,
+        bootable: bool,
+        /// Whether this is a logical partition inside an extended partition
+        logical: bool,
+    },
+    Gpt {
+        type_guid: Uuid,
+        unique_guid: Uuid,
+        name: String,
+        attributes: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// Partition number as used by Linux, logical MBR partitions start at 5
+    pub number: usize,
+    pub first_byte: u64,
+    pub len: u64,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    pub fn range(&self) -> std::ops::Range<u64> {
+        self.first_byte..self.first_byte + self.len
+    }
+
+    pub fn slice<'a>(&self, disk: &'a VdiDisk) -> Slice<'a> {
+        Slice::new(disk, self.range())
+    }
+
+    pub fn slice_mut<'a>(&self, disk: &'a mut VdiDisk) -> SliceMut<'a> {
+        SliceMut::new(disk, self.range())
+    }
+
+    /// Human readable name of the partition type, if it is a well-known one
+    pub fn type_name(&self) -> Option<&'static str> {
+        match &self.kind {
+            PartitionKind::Mbr { type_id, .. } => mbr_type_name(*type_id),
+            PartitionKind::Gpt { type_guid, .. } => gpt_type_name(type_guid),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionTable {
+    pub kind: PartitionTableKind,
+    pub disk_guid: Option<Uuid>,
+    /// 32-bit disk signature stored in the MBR, zero for most GPT disks
+    pub disk_signature: u32,
+    pub partitions: Vec<Partition>,
+    /// Set when the primary GPT header was damaged and the backup header was used instead
+    pub used_backup_gpt: bool,
+}
+
+impl VdiDisk {
+    /// Parses the partition table of the disk
+    pub fn partition_table(&self) -> anyhow::Result<PartitionTable> {
+        let sector_size = match self.header.sector_size.get() {
+            0 => 512,
+            size => size as u64,
+        };
+        read_partition_table(self, self.header.disk_size.get(), sector_size)
+    }
+
+    pub fn partitions(&self) -> anyhow::Result<Vec<Partition>> {
+        Ok(self.partition_table()?.partitions)
+    }
+}
+
+/// Parses an MBR or GPT partition table from any random-access disk of `disk_size` bytes
+pub fn read_partition_table<R: ReadAt + ?Sized>(
+    disk: &R,
+    disk_size: u64,
+    sector_size: u64,
+) -> anyhow::Result<PartitionTable> {
+    let mut mbr = [0u8; 512];
+    disk.read_exact_at(0, &mut mbr)?;
+    anyhow::ensure!(
+        mbr[510..512] == MBR_SIGNATURE,
+        "No partition table found (missing MBR signature)"
+    );
+
+    let entries: Vec<MbrEntry> = (0..4).map(|i| MbrEntry::parse(&mbr, i)).collect();
+    if entries.iter().any(|e| e.type_id == MBR_TYPE_GPT_PROTECTIVE) {
+        let mut table = read_gpt(disk, disk_size, sector_size)?;
+        table.disk_signature = mbr_disk_signature(&mbr);
+        return Ok(table);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.is_empty() {
+            continue;
+        }
+
+        if MBR_EXTENDED_TYPES.contains(&entry.type_id) {
+            read_logical_partitions(disk, entry.lba_start as u64, sector_size, &mut partitions)?;
+        }
+
+        partitions.push(Partition {
+            number: i + 1,
+            first_byte: entry.lba_start as u64 * sector_size,
+            len: entry.sectors as u64 * sector_size,
+            kind: PartitionKind::Mbr {
+                type_id: entry.type_id,
+                bootable: entry.status & 0x80 != 0,
+                logical: false,
+            },
+        });
+    }
+    partitions.sort_by_key(|p| p.number);
+
+    Ok(PartitionTable {
+        kind: PartitionTableKind::Mbr,
+        disk_guid: None,
+        disk_signature: mbr_disk_signature(&mbr),
+        partitions,
+        used_backup_gpt: false,
+    })
+}
+
+fn mbr_disk_signature(mbr: &[u8; 512]) -> u32 {
+    u32::from_le_bytes(mbr[440..444].try_into().unwrap())
+}
+
+struct MbrEntry {
+    status: u8,
+    type_id: u8,
+    lba_start: u32,
+    sectors: u32,
+}
+
+impl MbrEntry {
+    fn parse(sector: &[u8], index: usize) -> Self {
+        let raw = &sector[446 + index * 16..446 + (index + 1) * 16];
+        Self {
+            status: raw[0],
+            type_id: raw[4],
+            lba_start: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            sectors: u32::from_le_bytes(raw[12..16].try_into().unwrap()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.type_id == 0 || self.sectors == 0
+    }
+}
+
+/// Walks the EBR chain of an extended partition starting at `extended_lba`
+fn read_logical_partitions<R: ReadAt + ?Sized>(
+    disk: &R,
+    extended_lba: u64,
+    sector_size: u64,
+    partitions: &mut Vec<Partition>,
+) -> anyhow::Result<()> {
+    let mut ebr_lba = extended_lba;
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        let mut ebr = [0u8; 512];
+        disk.read_exact_at(ebr_lba * sector_size, &mut ebr)?;
+        anyhow::ensure!(
+            ebr[510..512] == MBR_SIGNATURE,
+            "Invalid EBR signature at sector {ebr_lba}"
+        );
+
+        let logical = MbrEntry::parse(&ebr, 0);
+        if !logical.is_empty() {
+            partitions.push(Partition {
+                number,
+                // Logical partitions are relative to their EBR
+                first_byte: (ebr_lba + logical.lba_start as u64) * sector_size,
+                len: logical.sectors as u64 * sector_size,
+                kind: PartitionKind::Mbr {
+                    type_id: logical.type_id,
+                    bootable: logical.status & 0x80 != 0,
+                    logical: true,
+                },
+            });
+        }
+
+        let next = MbrEntry::parse(&ebr, 1);
+        if next.is_empty() {
+            return Ok(());
+        }
+        // The next EBR is relative to the start of the extended partition
+        ebr_lba = extended_lba + next.lba_start as u64;
+    }
+
+    anyhow::bail!("EBR chain is too long or contains a loop")
+}
+
+struct GptHeader {
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: u32,
+    entries_crc32: u32,
+    disk_guid: Uuid,
+}
+
+fn read_gpt<R: ReadAt + ?Sized>(
+    disk: &R,
+    disk_size: u64,
+    sector_size: u64,
+) -> anyhow::Result<PartitionTable> {
+    let primary = read_gpt_header(disk, 1, sector_size)
+        .and_then(|header| Ok((read_gpt_entries(disk, &header, sector_size)?, header)));
+
+    let (used_backup_gpt, (entries, header)) = match primary {
+        Ok(primary) => (false, primary),
+        Err(primary_err) => {
+            // The backup header lives in the last sector of the disk
+            let last_lba = (disk_size / sector_size).saturating_sub(1);
+            let header = read_gpt_header(disk, last_lba, sector_size).map_err(|_| {
+                primary_err.context("Both the primary and backup GPT headers are invalid")
+            })?;
+            (
+                true,
+                (read_gpt_entries(disk, &header, sector_size)?, header),
+            )
+        }
+    };
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(header.entry_size as usize).enumerate() {
+        let type_guid = Uuid::from_bytes_le(entry[0..16].try_into().unwrap());
+        if type_guid.is_nil() {
+            continue;
+        }
+
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        anyhow::ensure!(
+            last_lba >= first_lba,
+            "GPT entry {} ends before it starts",
+            i + 1
+        );
+
+        let name_utf16: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+
+        partitions.push(Partition {
+            number: i + 1,
+            first_byte: first_lba * sector_size,
+            len: (last_lba - first_lba + 1) * sector_size,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: Uuid::from_bytes_le(entry[16..32].try_into().unwrap()),
+                name: String::from_utf16_lossy(&name_utf16),
+                attributes: u64::from_le_bytes(entry[48..56].try_into().unwrap()),
+            },
+        });
+    }
+
+    Ok(PartitionTable {
+        kind: PartitionTableKind::Gpt,
+        disk_guid: Some(header.disk_guid),
+        disk_signature: 0,
+        partitions,
+        used_backup_gpt,
+    })
+}
+
+fn read_gpt_header<R: ReadAt + ?Sized>(
+    disk: &R,
+    lba: u64,
+    sector_size: u64,
+) -> anyhow::Result<GptHeader> {
+    let mut raw = vec![0u8; sector_size as usize];
+    disk.read_exact_at(lba * sector_size, &mut raw)?;
+    anyhow::ensure!(
+        &raw[0..8] == GPT_SIGNATURE,
+        "Invalid GPT signature at sector {lba}"
+    );
+
+    let header_size = u32::from_le_bytes(raw[12..16].try_into().unwrap()) as usize;
+    anyhow::ensure!(
+        (GPT_MIN_HEADER_SIZE..=raw.len()).contains(&header_size),
+        "Invalid GPT header size {header_size}"
+    );
+
+    let header_crc32 = u32::from_le_bytes(raw[16..20].try_into().unwrap());
+    raw[16..20].fill(0);
+    anyhow::ensure!(
+        crc32fast::hash(&raw[..header_size]) == header_crc32,
+        "GPT header checksum mismatch at sector {lba}"
+    );
+
+    let header = GptHeader {
+        disk_guid: Uuid::from_bytes_le(raw[56..72].try_into().unwrap()),
+        entries_lba: u64::from_le_bytes(raw[72..80].try_into().unwrap()),
+        num_entries: u32::from_le_bytes(raw[80..84].try_into().unwrap()),
+        entry_size: u32::from_le_bytes(raw[84..88].try_into().unwrap()),
+        entries_crc32: u32::from_le_bytes(raw[88..92].try_into().unwrap()),
+    };
+    anyhow::ensure!(
+        header.entry_size >= 128
+            && header.entry_size.is_multiple_of(8)
+            && header.entry_size as u64 <= sector_size,
+        "Invalid GPT entry size {}",
+        header.entry_size
+    );
+    anyhow::ensure!(
+        header.num_entries <= GPT_MAX_ENTRIES
+            && header.num_entries as u64 * header.entry_size as u64 <= GPT_MAX_ENTRY_ARRAY_SIZE,
+        "Too many GPT entries ({})",
+        header.num_entries
+    );
+
+    Ok(header)
+}
+
+fn read_gpt_entries<R: ReadAt + ?Sized>(
+    disk: &R,
+    header: &GptHeader,
+    sector_size: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut entries = vec![0u8; header.num_entries as usize * header.entry_size as usize];
+    disk.read_exact_at(header.entries_lba * sector_size, &mut entries)?;
+    anyhow::ensure!(
+        crc32fast::hash(&entries) == header.entries_crc32,
+        "GPT partition entry array checksum mismatch"
+    );
+    Ok(entries)
+}
+
+pub fn mbr_type_name(type_id: u8) -> Option<&'static str> {
+    Some(match type_id {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x05 | 0x0F => "Extended",
+        0x07 => "NTFS/exFAT",
+        0x0B | 0x0C => "FAT32",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x85 => "Linux extended",
+        0x8E => "Linux LVM",
+        0xA5 => "FreeBSD",
+        0xA6 => "OpenBSD",
+        0xA8 => "Apple UFS",
+        0xAF => "Apple HFS/HFS+",
+        0xEE => "GPT protective",
+        0xEF => "EFI System",
+        0xFD => "Linux RAID",
+        _ => return None,
+    })
+}
+
+pub fn gpt_type_name(type_guid: &Uuid) -> Option<&'static str> {
+    const TYPES: &[(u128, &str)] = &[
+        (0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B, "EFI System"),
+        (0x21686148_6449_6E6F_744E_656564454649, "BIOS boot"),
+        (0xE3C9E316_0B5C_4DB8_817D_F92DF00215AE, "Microsoft reserved"),
+        (
+            0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7,
+            "Microsoft basic data",
+        ),
+        (0xDE94BBA4_06D1_4D40_A16A_BFD50179D6AC, "Windows recovery"),
+        (0x0FC63DAF_8483_4772_8E79_3D69D8477DE4, "Linux filesystem"),
+        (
+            0x4F68BCE3_E8CD_4DB1_96E7_FBCAF984B709,
+            "Linux root (x86-64)",
+        ),
+        (0x933AC7E1_2EB4_4F13_B844_0E14E2AEF915, "Linux home"),
+        (0x0657FD6D_A4AB_43C4_84E5_0933C84B4F4F, "Linux swap"),
+        (0xE6D6D379_F507_44C2_A23C_238F2A3DF928, "Linux LVM"),
+        (0xA19D880F_05FC_4D3B_A006_743F0F84911E, "Linux RAID"),
+        (
+            0xBC13C2FF_59E6_4262_A352_B275FD6F7172,
+            "Linux extended boot",
+        ),
+        (0x48465300_0000_11AA_AA11_00306543ECAC, "Apple HFS+"),
+        (0x7C3457EF_0000_11AA_AA11_00306543ECAC, "Apple APFS"),
+        (0x516E7CB4_6ECF_11D6_8FF8_00022D09712B, "FreeBSD"),
+    ];
+
+    let value = type_guid.as_u128();
+    TYPES
+        .iter()
+        .find(|&&(guid, _)| guid == value)
+        .map(|&(_, name)| name)
+}
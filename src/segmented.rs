@@ -0,0 +1,146 @@
+use std::io::{Read, Seek, Write};
+
+use positioned_io2::{ReadAt, WriteAt};
+
+/// A physical extent of the underlying reader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Offset of the segment in the underlying reader
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Concatenates a list of physical segments of `R` into one contiguous logical range.
+///
+/// Useful for volumes that are not stored contiguously, eg. LVM logical volumes spread over
+/// multiple extents of a physical volume.
+pub struct SegmentedSlice<R> {
+    inner: R,
+    segments: Vec<Segment>,
+    /// Logical start offset of each segment
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+}
+
+impl<R> SegmentedSlice<R> {
+    pub fn new(inner: R, segments: impl IntoIterator<Item = Segment>) -> Self {
+        let segments: Vec<Segment> = segments.into_iter().filter(|s| s.len > 0).collect();
+        let mut starts = Vec::with_capacity(segments.len());
+        let mut len = 0;
+        for segment in &segments {
+            starts.push(len);
+            len += segment.len;
+        }
+
+        Self {
+            inner,
+            segments,
+            starts,
+            len,
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Maps a logical position to the physical offset and the bytes left in its segment
+    fn map(&self, pos: u64) -> Option<(u64, u64)> {
+        if pos >= self.len {
+            return None;
+        }
+        let index = self.starts.partition_point(|&start| start <= pos) - 1;
+        let segment = &self.segments[index];
+        let offset_in_segment = pos - self.starts[index];
+        Some((
+            segment.offset + offset_in_segment,
+            segment.len - offset_in_segment,
+        ))
+    }
+}
+
+impl<R: ReadAt> ReadAt for SegmentedSlice<R> {
+    fn read_at(&self, mut pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut total_read = 0;
+        while total_read < buf.len() {
+            let Some((physical, remaining)) = self.map(pos) else {
+                break; // EOF
+            };
+
+            let to_read = std::cmp::min((buf.len() - total_read) as u64, remaining) as usize;
+            let n = self
+                .inner
+                .read_at(physical, &mut buf[total_read..total_read + to_read])?;
+            if n == 0 {
+                break; // EOF
+            }
+            total_read += n;
+            pos += n as u64;
+        }
+        Ok(total_read)
+    }
+}
+
+impl<R: WriteAt> WriteAt for SegmentedSlice<R> {
+    fn write_at(&mut self, mut pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let mut total_written = 0;
+        while total_written < buf.len() {
+            let Some((physical, remaining)) = self.map(pos) else {
+                break; // EOF
+            };
+
+            let to_write = std::cmp::min((buf.len() - total_written) as u64, remaining) as usize;
+            let n = self
+                .inner
+                .write_at(physical, &buf[total_written..total_written + to_write])?;
+            if n == 0 {
+                break;
+            }
+            total_written += n;
+            pos += n as u64;
+        }
+        Ok(total_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: ReadAt> Read for SegmentedSlice<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read_at(self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: WriteAt> Write for SegmentedSlice<R> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.write_at(self.pos, buf)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        WriteAt::flush(self)
+    }
+}
+
+impl<R> Seek for SegmentedSlice<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.pos = crate::slice::seek_within(self.len, self.pos, pos)?;
+        Ok(self.pos)
+    }
+}
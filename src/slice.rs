@@ -1,17 +1,54 @@
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
 
-use positioned_io2::ReadAt;
+use positioned_io2::{ReadAt, WriteAt};
 
 use crate::VdiDisk;
 
-pub struct Slice<'a> {
-    inner: &'a VdiDisk,
+/// Resolves a seek relative to a slice of `len` bytes, positions are relative to the slice start
+pub(crate) fn seek_within(len: u64, pos: u64, seek: std::io::SeekFrom) -> std::io::Result<u64> {
+    let new_pos = match seek {
+        std::io::SeekFrom::Start(offset) => Some(offset),
+        std::io::SeekFrom::End(offset) => len.checked_add_signed(offset),
+        std::io::SeekFrom::Current(offset) => pos.checked_add_signed(offset),
+    };
+
+    match new_pos {
+        Some(new_pos) if new_pos <= len => Ok(new_pos),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "seek out of bounds",
+        )),
+    }
+}
+
+/// Clamps an access of `buf_len` bytes at `pos` to a slice of `len` bytes
+fn clamp_len(len: u64, pos: u64, buf_len: usize) -> usize {
+    if pos >= len {
+        return 0;
+    }
+    std::cmp::min(buf_len as u64, len - pos) as usize
+}
+
+/// Maps `range`, relative to a slice covering `parent`, to the parent's coordinates.
+/// The result is clamped to the parent range.
+fn subrange(parent: &std::ops::Range<u64>, range: std::ops::Range<u64>) -> std::ops::Range<u64> {
+    let start = parent.start.saturating_add(range.start).min(parent.end);
+    let end = parent
+        .start
+        .saturating_add(range.end)
+        .clamp(start, parent.end);
+    start..end
+}
+
+/// Read-only view of a byte range of any `ReadAt`, including other slices
+pub struct Slice<'a, R: ?Sized = VdiDisk> {
+    inner: &'a R,
     range: std::ops::Range<u64>,
     pos: u64,
 }
 
-impl<'a> Slice<'a> {
-    pub fn new(inner: &'a VdiDisk, range: std::ops::Range<u64>) -> Self {
+impl<'a, R: ?Sized> Slice<'a, R> {
+    pub fn new(inner: &'a R, range: std::ops::Range<u64>) -> Self {
         Self {
             inner,
             range,
@@ -23,67 +60,155 @@ impl<'a> Slice<'a> {
     pub fn len(&self) -> usize {
         (self.range.end - self.range.start) as usize
     }
+
+    /// Range of the underlying reader covered by this slice
+    pub fn range(&self) -> std::ops::Range<u64> {
+        self.range.clone()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inner
+    }
+
+    /// Returns a view of `range` (relative to this slice) over the same underlying reader
+    pub fn slice(&self, range: std::ops::Range<u64>) -> Slice<'a, R> {
+        Slice::new(self.inner, subrange(&self.range, range))
+    }
 }
 
-impl<'a> Read for Slice<'a> {
+impl<'a, R: ReadAt + ?Sized> Read for Slice<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read = self.inner.read_at(self.range.start + self.pos, buf)?;
+        let read = self.read_at(self.pos, buf)?;
         self.pos += read as u64;
         Ok(read)
     }
 }
 
-impl<'a> Seek for Slice<'a> {
+impl<'a, R: ?Sized> Seek for Slice<'a, R> {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        let new_pos = match pos {
-            std::io::SeekFrom::Start(offset) => offset,
-            std::io::SeekFrom::End(offset) => {
-                if offset >= 0 {
-                    self.len() as u64 + offset as u64
-                } else {
-                    self.len() as u64 - (-offset) as u64
-                }
-            }
-            std::io::SeekFrom::Current(offset) => {
-                if offset >= 0 {
-                    self.pos + offset as u64
-                } else {
-                    self.pos - (-offset) as u64
-                }
-            }
-        };
-
-        if new_pos > self.range.end {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "seek out of bounds",
-            ));
+        self.pos = seek_within(self.len() as u64, self.pos, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl<'a, R: ReadAt + ?Sized> positioned_io2::ReadAt for Slice<'a, R> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read_len = clamp_len(self.len() as u64, pos, buf.len());
+        if read_len == 0 {
+            return Ok(0);
         }
+        self.inner
+            .read_at(self.range.start + pos, &mut buf[..read_len])
+    }
+}
+
+/// Mutable counterpart of [`Slice`], writes are clamped to the slice range
+pub struct SliceMut<'a, R: ?Sized = VdiDisk> {
+    inner: &'a mut R,
+    range: std::ops::Range<u64>,
+    pos: u64,
+}
+
+impl<'a, R: ?Sized> SliceMut<'a, R> {
+    pub fn new(inner: &'a mut R, range: std::ops::Range<u64>) -> Self {
+        Self {
+            inner,
+            range,
+            pos: 0,
+        }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        (self.range.end - self.range.start) as usize
+    }
+
+    /// Range of the underlying reader covered by this slice
+    pub fn range(&self) -> std::ops::Range<u64> {
+        self.range.clone()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner
+    }
 
-        self.pos = new_pos;
+    /// Returns a read-only view of `range` (relative to this slice)
+    pub fn slice(&self, range: std::ops::Range<u64>) -> Slice<'_, R> {
+        Slice::new(self.inner, subrange(&self.range, range))
+    }
+
+    /// Returns a writable view of `range` (relative to this slice)
+    pub fn slice_mut(&mut self, range: std::ops::Range<u64>) -> SliceMut<'_, R> {
+        let range = subrange(&self.range, range);
+        SliceMut::new(self.inner, range)
+    }
+}
+
+impl<'a, R: ReadAt + ?Sized> Read for SliceMut<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read_at(self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<'a, R: WriteAt + ?Sized> Write for SliceMut<'a, R> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.write_at(self.pos, buf)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        WriteAt::flush(self)
+    }
+}
+
+impl<'a, R: ?Sized> Seek for SliceMut<'a, R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.pos = seek_within(self.len() as u64, self.pos, pos)?;
         Ok(self.pos)
     }
 }
 
-impl<'a> positioned_io2::ReadAt for Slice<'a> {
+impl<'a, R: ReadAt + ?Sized> positioned_io2::ReadAt for SliceMut<'a, R> {
     fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
-        if pos >= self.len() as u64 {
+        let read_len = clamp_len(self.len() as u64, pos, buf.len());
+        if read_len == 0 {
+            return Ok(0);
+        }
+        self.inner
+            .read_at(self.range.start + pos, &mut buf[..read_len])
+    }
+}
+
+impl<'a, R: WriteAt + ?Sized> positioned_io2::WriteAt for SliceMut<'a, R> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let write_len = clamp_len(self.len() as u64, pos, buf.len());
+        if write_len == 0 {
             return Ok(0);
         }
-        let read_len = std::cmp::min(buf.len() as u64, self.len() as u64 - pos);
         self.inner
-            .read_at(self.range.start + pos, &mut buf[..read_len as usize])
+            .write_at(self.range.start + pos, &buf[..write_len])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        WriteAt::flush(&mut *self.inner)
     }
 }
 
-pub struct OwnedSlice {
-    inner: VdiDisk,
+pub struct OwnedSlice<R = VdiDisk> {
+    inner: R,
     range: std::ops::Range<u64>,
     pos: u64,
 }
 
-impl OwnedSlice {
-    pub fn new(inner: VdiDisk, range: std::ops::Range<u64>) -> std::io::Result<Self> {
+impl<R> OwnedSlice<R> {
+    pub fn new(inner: R, range: std::ops::Range<u64>) -> std::io::Result<Self> {
         Ok(Self {
             inner,
             range,
@@ -91,7 +216,7 @@ impl OwnedSlice {
         })
     }
 
-    pub fn into_inner(self) -> VdiDisk {
+    pub fn into_inner(self) -> R {
         self.inner
     }
 
@@ -99,55 +224,75 @@ impl OwnedSlice {
     pub fn len(&self) -> usize {
         (self.range.end - self.range.start) as usize
     }
+
+    /// Range of the underlying reader covered by this slice
+    pub fn range(&self) -> std::ops::Range<u64> {
+        self.range.clone()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns a view of `range` (relative to this slice) over the owned reader
+    pub fn slice(&self, range: std::ops::Range<u64>) -> Slice<'_, R> {
+        Slice::new(&self.inner, subrange(&self.range, range))
+    }
 }
 
-impl Read for OwnedSlice {
+impl<R: ReadAt> Read for OwnedSlice<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read = self.inner.read_at(self.range.start + self.pos, buf)?;
+        let read = self.read_at(self.pos, buf)?;
         self.pos += read as u64;
         Ok(read)
     }
 }
 
-impl Seek for OwnedSlice {
-    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        let new_pos = match pos {
-            std::io::SeekFrom::Start(offset) => offset,
-            std::io::SeekFrom::End(offset) => {
-                if offset >= 0 {
-                    self.len() as u64 + offset as u64
-                } else {
-                    self.len() as u64 - (-offset) as u64
-                }
-            }
-            std::io::SeekFrom::Current(offset) => {
-                if offset >= 0 {
-                    self.pos + offset as u64
-                } else {
-                    self.pos - (-offset) as u64
-                }
-            }
-        };
-
-        if new_pos > self.range.end {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "seek out of bounds",
-            ));
-        }
+impl<R: WriteAt> Write for OwnedSlice<R> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.write_at(self.pos, buf)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
 
-        self.pos = new_pos;
+    fn flush(&mut self) -> std::io::Result<()> {
+        WriteAt::flush(self)
+    }
+}
+
+impl<R> Seek for OwnedSlice<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.pos = seek_within(self.len() as u64, self.pos, pos)?;
         Ok(self.pos)
     }
 }
 
-impl positioned_io2::ReadAt for OwnedSlice {
+impl<R: ReadAt> positioned_io2::ReadAt for OwnedSlice<R> {
     fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
-        if pos >= self.len() as u64 {
+        let read_len = clamp_len(self.len() as u64, pos, buf.len());
+        if read_len == 0 {
+            return Ok(0);
+        }
+        self.inner
+            .read_at(self.range.start + pos, &mut buf[..read_len])
+    }
+}
+
+impl<R: WriteAt> positioned_io2::WriteAt for OwnedSlice<R> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+        let write_len = clamp_len(self.len() as u64, pos, buf.len());
+        if write_len == 0 {
             return Ok(0);
         }
-        let read_len = std::cmp::min(buf.len() as u64, self.len() as u64 - pos);
         self.inner
-            .read_at(self.range.start + pos, &mut buf[..read_len as usize])
+            .write_at(self.range.start + pos, &buf[..write_len])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        WriteAt::flush(&mut self.inner)
     }
 }
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use positioned_io2::{ReadAt, WriteAt};
+
+use crate::header::VdiHeader;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamOptions {
+    /// Maximum amount of out-of-order block data held in memory before spilling to a temporary file
+    pub memory_limit: usize,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            memory_limit: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamStats {
+    pub blocks_allocated: usize,
+    /// Blocks that arrived before their logical predecessors and had to be held back
+    pub blocks_buffered: usize,
+    /// Buffered blocks that did not fit in memory and went to the temporary file
+    pub blocks_spilled: usize,
+}
+
+/// Receives the blocks of a streamed VDI in ascending logical order
+pub trait BlockSink {
+    /// Called exactly once per block of the image. `data` is `None` for unallocated blocks.
+    fn write_block(&mut self, index: usize, data: Option<&[u8]>) -> std::io::Result<()>;
+}
+
+/// Writes the blocks as a raw disk image
+pub struct RawSink<W: Write> {
+    writer: W,
+    remaining: u64,
+    zeros: Vec<u8>,
+}
+
+impl<W: Write> RawSink<W> {
+    pub fn new(writer: W, header: &VdiHeader) -> Self {
+        Self {
+            writer,
+            remaining: header.disk_size.get(),
+            zeros: vec![0u8; header.block_size.get() as usize],
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> BlockSink for RawSink<W> {
+    fn write_block(&mut self, _index: usize, data: Option<&[u8]>) -> std::io::Result<()> {
+        let data = data.unwrap_or(&self.zeros);
+        // The last block may extend past the end of the disk
+        let len = std::cmp::min(data.len() as u64, self.remaining) as usize;
+        self.writer.write_all(&data[..len])?;
+        self.remaining -= len as u64;
+        Ok(())
+    }
+}
+
+/// Reads a VDI image from a non-seekable stream, eg. a pipe.
+///
+/// Block data is consumed in file order and handed out in logical order, holding back blocks
+/// that arrive early. Held-back blocks are kept in memory up to [`StreamOptions::memory_limit`]
+/// and spilled to a temporary file beyond that.
+pub struct VdiStream<R: Read> {
+    pub header: VdiHeader,
+    pub block_size: usize,
+    /// Raw block map entries, see [`VdiHeader::BLOCK_FREE`] and [`VdiHeader::BLOCK_ZERO`]
+    pub block_map: Vec<u32>,
+
+    reader: R,
+    options: StreamOptions,
+}
+
+enum HeldBlock {
+    Memory(Vec<u8>),
+    Spilled(u64),
+}
+
+impl<R: Read> VdiStream<R> {
+    /// Consumes the header and block map from the stream
+    pub fn new(mut reader: R, options: StreamOptions) -> anyhow::Result<Self> {
+        let mut header_raw = vec![0u8; std::mem::size_of::<VdiHeader>()];
+        reader.read_exact(&mut header_raw)?;
+        let header = bytemuck::pod_read_unaligned::<VdiHeader>(&header_raw);
+        header.validate_standalone()?;
+
+        let mut consumed = header_raw.len() as u64;
+        let map_offset = header.block_offsets_offset.get() as u64;
+        let map_end = map_offset + header.block_map_size() as u64;
+        anyhow::ensure!(
+            map_offset >= consumed && header.data_offset.get() as u64 >= map_end,
+            "Block map overlaps the header or block data, the image cannot be streamed"
+        );
+
+        consumed += skip(&mut reader, map_offset - consumed)?;
+        let mut block_map_raw = vec![0u8; header.block_map_size()];
+        reader.read_exact(&mut block_map_raw)?;
+        consumed += block_map_raw.len() as u64;
+        skip(&mut reader, header.data_offset.get() as u64 - consumed)?;
+
+        Ok(Self {
+            header,
+            block_size: header.block_size.get() as usize,
+            block_map: VdiHeader::block_map_entries(&block_map_raw).collect(),
+            reader,
+            options,
+        })
+    }
+
+    /// Streams the image into `writer` as a raw disk image
+    pub fn write_raw<W: Write>(self, writer: W) -> anyhow::Result<StreamStats> {
+        let mut sink = RawSink::new(writer, &self.header);
+        let stats = self.convert(&mut sink)?;
+        sink.into_inner().flush()?;
+        Ok(stats)
+    }
+
+    /// Streams the remaining block data into `sink`, in logical block order
+    pub fn convert(mut self, sink: &mut impl BlockSink) -> anyhow::Result<StreamStats> {
+        // Logical block stored in each file slot
+        let mut slots: Vec<Option<usize>> = Vec::new();
+        for (index, &entry) in self.block_map.iter().enumerate() {
+            if entry >= VdiHeader::BLOCK_ZERO {
+                continue;
+            }
+            let slot = entry as usize;
+            if slot >= slots.len() {
+                slots.resize(slot + 1, None);
+            }
+            anyhow::ensure!(
+                slots[slot].replace(index).is_none(),
+                "Block map references file block {slot} more than once"
+            );
+        }
+
+        let mut stats = StreamStats {
+            blocks_allocated: slots.iter().flatten().count(),
+            ..Default::default()
+        };
+
+        let mut queue = ReorderQueue {
+            block_size: self.block_size,
+            memory_limit: self.options.memory_limit,
+            held: HashMap::new(),
+            held_in_memory: 0,
+            spill: None,
+            spill_len: 0,
+            next: 0,
+        };
+        let mut data = vec![0u8; self.block_size];
+
+        queue.drain(&self.block_map, sink, &mut data)?;
+        for logical in slots {
+            self.reader.read_exact(&mut data)?;
+            let Some(logical) = logical else {
+                continue; // Orphaned block
+            };
+
+            if logical == queue.next {
+                sink.write_block(logical, Some(&data))?;
+                queue.next += 1;
+                queue.drain(&self.block_map, sink, &mut data)?;
+            } else {
+                stats.blocks_buffered += 1;
+                if queue.hold(logical, &data)? {
+                    stats.blocks_spilled += 1;
+                }
+            }
+        }
+
+        anyhow::ensure!(
+            queue.next == self.block_map.len(),
+            "Block {} was never received",
+            queue.next
+        );
+
+        Ok(stats)
+    }
+}
+
+struct ReorderQueue {
+    block_size: usize,
+    memory_limit: usize,
+    held: HashMap<usize, HeldBlock>,
+    held_in_memory: usize,
+    spill: Option<File>,
+    spill_len: u64,
+    /// Next logical block to hand to the sink
+    next: usize,
+}
+
+impl ReorderQueue {
+    /// Holds back a block that arrived early, returns whether it was spilled to disk
+    fn hold(&mut self, logical: usize, data: &[u8]) -> std::io::Result<bool> {
+        if self.held_in_memory + self.block_size <= self.memory_limit {
+            self.held_in_memory += self.block_size;
+            self.held.insert(logical, HeldBlock::Memory(data.to_vec()));
+            return Ok(false);
+        }
+
+        let file = match &mut self.spill {
+            Some(file) => file,
+            None => self.spill.insert(tempfile::tempfile()?),
+        };
+        file.write_all_at(self.spill_len, data)?;
+        self.held
+            .insert(logical, HeldBlock::Spilled(self.spill_len));
+        self.spill_len += self.block_size as u64;
+        Ok(true)
+    }
+
+    /// Emits unallocated and held-back blocks until the next block still has to be received
+    fn drain(
+        &mut self,
+        block_map: &[u32],
+        sink: &mut impl BlockSink,
+        scratch: &mut [u8],
+    ) -> std::io::Result<()> {
+        while self.next < block_map.len() {
+            if block_map[self.next] >= VdiHeader::BLOCK_ZERO {
+                sink.write_block(self.next, None)?;
+            } else {
+                match self.held.remove(&self.next) {
+                    Some(HeldBlock::Memory(block)) => {
+                        self.held_in_memory -= self.block_size;
+                        sink.write_block(self.next, Some(&block))?;
+                    }
+                    Some(HeldBlock::Spilled(offset)) => {
+                        let file = self
+                            .spill
+                            .as_ref()
+                            .expect("unreachable: spilled block without spill file");
+                        file.read_exact_at(offset, scratch)?;
+                        sink.write_block(self.next, Some(scratch))?;
+                    }
+                    None => break,
+                }
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
+
+fn skip(reader: &mut impl Read, len: u64) -> std::io::Result<u64> {
+    let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+    if skipped != len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Stream ended before the block data",
+        ));
+    }
+    Ok(skipped)
+}
@@ -0,0 +1,134 @@
+//! io_uring backend, keeping many block reads in flight during bulk scans of an image.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+
+use io_uring::{IoUring, opcode, types};
+
+/// Read-only image file with an io_uring submission queue
+pub(crate) struct Ring {
+    file: File,
+    ring: RefCell<IoUring>,
+    queue_depth: usize,
+}
+
+impl Ring {
+    pub fn new(file: File, queue_depth: u32) -> std::io::Result<Self> {
+        if queue_depth == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Queue depth must not be zero",
+            ));
+        }
+        Ok(Self {
+            file,
+            ring: RefCell::new(IoUring::new(queue_depth)?),
+            queue_depth: queue_depth as usize,
+        })
+    }
+
+    /// Reads the blocks stored at `offsets` with up to the queue depth of reads in flight, and
+    /// calls `f` with each in logical order. Blocks without an offset are passed as `None`.
+    pub fn read_blocks(
+        &self,
+        offsets: &[Option<u64>],
+        block_size: usize,
+        mut f: impl FnMut(usize, Option<&mut [u8]>) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let mut ring = self.ring.try_borrow_mut().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::ResourceBusy, "Ring is already in use")
+        })?;
+        let allocated: Vec<(usize, u64)> = offsets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, offset)| offset.map(|offset| (index, offset)))
+            .collect();
+
+        // The read of the n-th allocated block goes to slot n % queue_depth
+        let depth = std::cmp::min(self.queue_depth, allocated.len().max(1));
+        let mut buffers = vec![vec![0u8; block_size]; depth];
+        let mut results: Vec<Option<i32>> = vec![None; depth];
+        let mut in_flight = 0;
+
+        let outcome = (|| {
+            let (mut submitted, mut delivered, mut next_index) = (0, 0, 0);
+            while delivered < allocated.len() {
+                while submitted < allocated.len() && submitted - delivered < depth {
+                    let slot = submitted % depth;
+                    let read = opcode::Read::new(
+                        types::Fd(self.file.as_raw_fd()),
+                        buffers[slot].as_mut_ptr(),
+                        block_size as u32,
+                    )
+                    .offset(allocated[submitted].1)
+                    .build()
+                    .user_data(slot as u64);
+                    // SAFETY: the buffer is neither moved nor dropped until the read completed
+                    unsafe { ring.submission().push(&read) }
+                        .map_err(|_| std::io::Error::other("Submission queue is full"))?;
+                    submitted += 1;
+                    in_flight += 1;
+                }
+
+                let slot = delivered % depth;
+                while results[slot].is_none() {
+                    submit_and_wait(&ring, 1)?;
+                    for completion in ring.completion() {
+                        results[completion.user_data() as usize] = Some(completion.result());
+                        in_flight -= 1;
+                    }
+                }
+
+                let (index, offset) = allocated[delivered];
+                while next_index < index {
+                    f(next_index, None)?;
+                    next_index += 1;
+                }
+                let result = results[slot].take().expect("read completed");
+                if result < 0 {
+                    return Err(std::io::Error::from_raw_os_error(-result));
+                }
+                let read = result as usize;
+                let buffer = &mut buffers[slot];
+                if read < block_size {
+                    crate::backend::read_block(self, offset + read as u64, &mut buffer[read..])?;
+                }
+                f(index, Some(buffer))?;
+                next_index += 1;
+                delivered += 1;
+            }
+
+            for index in next_index..offsets.len() {
+                f(index, None)?;
+            }
+            Ok(())
+        })();
+
+        // Reads still in flight after an error write to the buffers, which must outlive them
+        while in_flight > 0 {
+            if let Err(e) = submit_and_wait(&ring, in_flight) {
+                std::mem::forget(buffers);
+                return Err(e);
+            }
+            in_flight -= ring.completion().count();
+        }
+        outcome
+    }
+}
+
+fn submit_and_wait(ring: &IoUring, want: usize) -> std::io::Result<()> {
+    loop {
+        match ring.submit_and_wait(want) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+impl positioned_io2::ReadAt for Ring {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        FileExt::read_at(&self.file, buf, pos)
+    }
+}
@@ -0,0 +1,483 @@
+//! VirtualBox machine settings (`.vbox`) and the global media registry (`VirtualBox.xml`).
+//!
+//! Relative media locations are resolved against the directory of the file they are read from.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use roxmltree::Node;
+use uuid::Uuid;
+
+use crate::VdiDisk;
+use crate::lock::LockMode;
+
+/// Medium property holding the key store of an encrypted medium
+pub const KEY_STORE_PROPERTY: &str = "CRYPT/KeyStore";
+
+/// Medium registered in a media registry, with the differencing images based on it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HardDisk {
+    pub uuid: Uuid,
+    pub location: PathBuf,
+    /// Storage format, eg. `VDI` or `VMDK`
+    pub format: String,
+    /// Attachment behaviour, eg. `Normal`, `Immutable` or `MultiAttach`. Only set on base images.
+    pub kind: Option<String>,
+    /// Medium properties, eg. the [`KEY_STORE_PROPERTY`] of encrypted media
+    pub properties: BTreeMap<String, String>,
+    pub children: Vec<HardDisk>,
+}
+
+impl HardDisk {
+    pub fn is_encrypted(&self) -> bool {
+        self.properties.contains_key(KEY_STORE_PROPERTY)
+    }
+
+    /// Key store of an encrypted medium
+    #[cfg(feature = "crypt")]
+    pub fn key_store(&self) -> anyhow::Result<Option<crate::crypt::KeyStore>> {
+        self.properties
+            .get(KEY_STORE_PROPERTY)
+            .map(|value| crate::crypt::KeyStore::from_base64(value))
+            .transpose()
+    }
+
+    /// Opens the image read-only, decrypting it with `password` if it is encrypted
+    fn open(&self, password: Option<&str>) -> anyhow::Result<VdiDisk> {
+        anyhow::ensure!(
+            self.format.eq_ignore_ascii_case("VDI"),
+            "Medium {} has unsupported format {}",
+            self.uuid,
+            self.format
+        );
+        let file = std::fs::File::open(&self.location)
+            .map_err(|e| anyhow::anyhow!("Failed to open {}: {e}", self.location.display()))?;
+        #[allow(unused_mut)]
+        let mut disk = VdiDisk::open_locked(file, LockMode::Shared)?;
+        if !self.is_encrypted() {
+            return Ok(disk);
+        }
+
+        #[cfg(feature = "crypt")]
+        if let (Some(password), Some(key_store)) = (password, self.key_store()?) {
+            disk.set_encryption(key_store.unlock(password)?)?;
+            return Ok(disk);
+        }
+        let _ = password;
+        anyhow::bail!(
+            "Medium {} is encrypted, reading it requires its password",
+            self.uuid
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MediaRegistry {
+    /// Base images, the differencing images are nested in them
+    pub hard_disks: Vec<HardDisk>,
+}
+
+impl MediaRegistry {
+    fn parse(node: Node, dir: &Path) -> anyhow::Result<Self> {
+        let hard_disks = match child(node, "HardDisks") {
+            Some(disks) => children(disks, "HardDisk")
+                .map(|disk| parse_hard_disk(disk, dir))
+                .collect::<anyhow::Result<_>>()?,
+            None => Vec::new(),
+        };
+        Ok(Self { hard_disks })
+    }
+
+    /// Returns the chain of images ending with `uuid`, starting at its base image
+    pub fn chain(&self, uuid: Uuid) -> Option<Vec<&HardDisk>> {
+        fn find<'a>(disks: &'a [HardDisk], uuid: Uuid, chain: &mut Vec<&'a HardDisk>) -> bool {
+            for disk in disks {
+                chain.push(disk);
+                if disk.uuid == uuid || find(&disk.children, uuid, chain) {
+                    return true;
+                }
+                chain.pop();
+            }
+            false
+        }
+
+        let mut chain = Vec::new();
+        find(&self.hard_disks, uuid, &mut chain).then_some(chain)
+    }
+
+    pub fn find(&self, uuid: Uuid) -> Option<&HardDisk> {
+        self.chain(uuid)?.pop()
+    }
+
+    /// Opens image `uuid` read-only, with its parents attached. Fails on encrypted media, see
+    /// [`MediaRegistry::open_chain_encrypted`].
+    pub fn open_chain(&self, uuid: Uuid) -> anyhow::Result<VdiDisk> {
+        self.open_chain_with(uuid, None)
+    }
+
+    /// Opens image `uuid` like [`MediaRegistry::open_chain`], decrypting the encrypted media of
+    /// the chain with `password`
+    #[cfg(feature = "crypt")]
+    pub fn open_chain_encrypted(&self, uuid: Uuid, password: &str) -> anyhow::Result<VdiDisk> {
+        self.open_chain_with(uuid, Some(password))
+    }
+
+    fn open_chain_with(&self, uuid: Uuid, password: Option<&str>) -> anyhow::Result<VdiDisk> {
+        let chain = self
+            .chain(uuid)
+            .ok_or_else(|| anyhow::anyhow!("Medium {uuid} is not registered"))?;
+
+        let mut disk: Option<VdiDisk> = None;
+        for medium in chain {
+            let mut child = medium.open(password)?;
+            if let Some(parent) = disk.take() {
+                child.set_parent(parent)?;
+            }
+            disk = Some(child);
+        }
+        Ok(disk.expect("a chain contains at least the image itself"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StorageController {
+    pub name: String,
+    /// Controller model, eg. `AHCI` or `PIIX4`
+    pub controller_type: String,
+    pub attachments: Vec<Attachment>,
+}
+
+/// Device attached to a port of a storage controller
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attachment {
+    /// Kind of device, eg. `HardDisk` or `DVD`
+    pub device_type: String,
+    pub port: u32,
+    pub device: u32,
+    /// Medium in the device, if any
+    pub image: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub uuid: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    /// Storage configuration when the snapshot was taken
+    pub storage_controllers: Vec<StorageController>,
+    pub children: Vec<Snapshot>,
+}
+
+impl Snapshot {
+    fn parse(node: Node) -> anyhow::Result<Self> {
+        let children = match child(node, "Snapshots") {
+            Some(snapshots) => children(snapshots, "Snapshot")
+                .map(Snapshot::parse)
+                .collect::<anyhow::Result<_>>()?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            uuid: parse_uuid(node, "uuid")?,
+            name: node.attribute("name").unwrap_or_default().to_string(),
+            description: child(node, "Description")
+                .and_then(|d| d.text())
+                .map(str::to_string),
+            storage_controllers: parse_storage_controllers(node)?,
+            children,
+        })
+    }
+
+    fn find(&self, uuid: Uuid) -> Option<&Snapshot> {
+        if self.uuid == uuid {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(uuid))
+    }
+}
+
+/// Settings of a virtual machine, read from its `.vbox` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Machine {
+    pub uuid: Uuid,
+    pub name: String,
+    pub current_snapshot: Option<Uuid>,
+    /// Media registered by the machine, newer VirtualBox versions register them here rather
+    /// than in the global registry
+    pub media_registry: MediaRegistry,
+    /// Current storage configuration
+    pub storage_controllers: Vec<StorageController>,
+    /// Root of the snapshot tree
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Machine {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let xml = std::fs::read_to_string(path)?;
+        Self::parse(&xml, path.parent().unwrap_or(Path::new(".")))
+    }
+
+    /// Parses the contents of a `.vbox` file, `dir` being the directory it is in
+    pub fn parse(xml: &str, dir: &Path) -> anyhow::Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let machine = child(doc.root_element(), "Machine")
+            .ok_or_else(|| anyhow::anyhow!("Missing Machine element"))?;
+
+        Ok(Self {
+            uuid: parse_uuid(machine, "uuid")?,
+            name: machine.attribute("name").unwrap_or_default().to_string(),
+            current_snapshot: machine
+                .attribute("currentSnapshot")
+                .map(Uuid::parse_str)
+                .transpose()?,
+            media_registry: match child(machine, "MediaRegistry") {
+                Some(registry) => MediaRegistry::parse(registry, dir)?,
+                None => MediaRegistry::default(),
+            },
+            storage_controllers: parse_storage_controllers(machine)?,
+            snapshot: child(machine, "Snapshot")
+                .map(Snapshot::parse)
+                .transpose()?,
+        })
+    }
+
+    pub fn find_snapshot(&self, uuid: Uuid) -> Option<&Snapshot> {
+        self.snapshot.as_ref()?.find(uuid)
+    }
+
+    /// Storage configuration of the machine in its current state, or when `snapshot` was taken
+    pub fn storage_controllers_at(
+        &self,
+        snapshot: Option<Uuid>,
+    ) -> anyhow::Result<&[StorageController]> {
+        match snapshot {
+            Some(uuid) => self
+                .find_snapshot(uuid)
+                .map(|s| s.storage_controllers.as_slice())
+                .ok_or_else(|| anyhow::anyhow!("Snapshot {uuid} not found")),
+            None => Ok(&self.storage_controllers),
+        }
+    }
+
+    /// Hard disks attached in the current state or in `snapshot`, with the controller they are
+    /// attached to
+    pub fn hard_disk_attachments(
+        &self,
+        snapshot: Option<Uuid>,
+    ) -> anyhow::Result<Vec<(&StorageController, &Attachment)>> {
+        Ok(self
+            .storage_controllers_at(snapshot)?
+            .iter()
+            .flat_map(|controller| {
+                controller
+                    .attachments
+                    .iter()
+                    .filter(|a| a.device_type == "HardDisk" && a.image.is_some())
+                    .map(move |a| (controller, a))
+            })
+            .collect())
+    }
+
+    /// Opens the disk attached to `controller` at `port` and `device`, in the current state or
+    /// in `snapshot`, with its parents attached. Media not registered by the machine are looked
+    /// up in `global`.
+    pub fn open_disk(
+        &self,
+        snapshot: Option<Uuid>,
+        controller: &str,
+        port: u32,
+        device: u32,
+        global: Option<&MediaRegistry>,
+    ) -> anyhow::Result<VdiDisk> {
+        self.open_disk_with(snapshot, controller, port, device, global, None)
+    }
+
+    /// Opens a disk like [`Machine::open_disk`], decrypting the encrypted media of its chain
+    /// with `password`
+    #[cfg(feature = "crypt")]
+    pub fn open_disk_encrypted(
+        &self,
+        snapshot: Option<Uuid>,
+        controller: &str,
+        port: u32,
+        device: u32,
+        global: Option<&MediaRegistry>,
+        password: &str,
+    ) -> anyhow::Result<VdiDisk> {
+        self.open_disk_with(snapshot, controller, port, device, global, Some(password))
+    }
+
+    fn open_disk_with(
+        &self,
+        snapshot: Option<Uuid>,
+        controller: &str,
+        port: u32,
+        device: u32,
+        global: Option<&MediaRegistry>,
+        password: Option<&str>,
+    ) -> anyhow::Result<VdiDisk> {
+        let (_, attachment) = self
+            .hard_disk_attachments(snapshot)?
+            .into_iter()
+            .find(|(c, a)| c.name == controller && a.port == port && a.device == device)
+            .ok_or_else(|| {
+                anyhow::anyhow!("No hard disk attached to {controller} port {port} device {device}")
+            })?;
+        let uuid = attachment
+            .image
+            .expect("hard disk attachments have an image");
+
+        match global {
+            Some(global) if self.media_registry.chain(uuid).is_none() => {
+                global.open_chain_with(uuid, password)
+            }
+            _ => self.media_registry.open_chain_with(uuid, password),
+        }
+    }
+}
+
+/// Machine listed in the global registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MachineEntry {
+    pub uuid: Uuid,
+    /// Path of the `.vbox` file
+    pub settings: PathBuf,
+}
+
+/// Global VirtualBox settings, read from `VirtualBox.xml`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GlobalRegistry {
+    pub machines: Vec<MachineEntry>,
+    pub media_registry: MediaRegistry,
+}
+
+impl GlobalRegistry {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let xml = std::fs::read_to_string(path)?;
+        Self::parse(&xml, path.parent().unwrap_or(Path::new(".")))
+    }
+
+    /// Parses the contents of `VirtualBox.xml`, `dir` being the directory it is in
+    pub fn parse(xml: &str, dir: &Path) -> anyhow::Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let global = child(doc.root_element(), "Global")
+            .ok_or_else(|| anyhow::anyhow!("Missing Global element"))?;
+
+        let machines = match child(global, "MachineRegistry") {
+            Some(registry) => children(registry, "MachineEntry")
+                .map(|entry| {
+                    Ok(MachineEntry {
+                        uuid: parse_uuid(entry, "uuid")?,
+                        settings: dir.join(required(entry, "src")?),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            machines,
+            media_registry: match child(global, "MediaRegistry") {
+                Some(registry) => MediaRegistry::parse(registry, dir)?,
+                None => MediaRegistry::default(),
+            },
+        })
+    }
+
+    /// Loads the settings of machine `uuid`
+    pub fn machine(&self, uuid: Uuid) -> anyhow::Result<Machine> {
+        let entry = self
+            .machines
+            .iter()
+            .find(|m| m.uuid == uuid)
+            .ok_or_else(|| anyhow::anyhow!("Machine {uuid} is not registered"))?;
+        Machine::load(&entry.settings)
+    }
+}
+
+fn parse_hard_disk(node: Node, dir: &Path) -> anyhow::Result<HardDisk> {
+    Ok(HardDisk {
+        uuid: parse_uuid(node, "uuid")?,
+        location: dir.join(required(node, "location")?),
+        format: required(node, "format")?.to_string(),
+        kind: node.attribute("type").map(str::to_string),
+        properties: children(node, "Property")
+            .map(|property| {
+                Ok((
+                    required(property, "name")?.to_string(),
+                    property.attribute("value").unwrap_or_default().to_string(),
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?,
+        children: children(node, "HardDisk")
+            .map(|child| parse_hard_disk(child, dir))
+            .collect::<anyhow::Result<_>>()?,
+    })
+}
+
+/// Parses the storage controllers of a machine or snapshot. Newer versions store them in the
+/// `Hardware` element, older ones next to it.
+fn parse_storage_controllers(node: Node) -> anyhow::Result<Vec<StorageController>> {
+    let Some(controllers) = child(node, "StorageControllers")
+        .or_else(|| child(node, "Hardware").and_then(|hw| child(hw, "StorageControllers")))
+    else {
+        return Ok(Vec::new());
+    };
+
+    children(controllers, "StorageController")
+        .map(|controller| {
+            let attachments = children(controller, "AttachedDevice")
+                .map(|device| {
+                    Ok(Attachment {
+                        device_type: required(device, "type")?.to_string(),
+                        port: device.attribute("port").unwrap_or("0").parse()?,
+                        device: device.attribute("device").unwrap_or("0").parse()?,
+                        image: child(device, "Image")
+                            .map(|image| parse_uuid(image, "uuid"))
+                            .transpose()?,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(StorageController {
+                name: required(controller, "name")?.to_string(),
+                controller_type: controller.attribute("type").unwrap_or_default().to_string(),
+                attachments,
+            })
+        })
+        .collect()
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn required<'a>(node: Node<'a, '_>, attribute: &str) -> anyhow::Result<&'a str> {
+    node.attribute(attribute).ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} element is missing the {attribute} attribute",
+            node.tag_name().name()
+        )
+    })
+}
+
+/// Parses a UUID attribute, VirtualBox writes them in braces
+fn parse_uuid(node: Node, attribute: &str) -> anyhow::Result<Uuid> {
+    Ok(Uuid::parse_str(required(node, attribute)?)?)
+}
@@ -0,0 +1,53 @@
+#![cfg(feature = "tokio")]
+
+mod support;
+
+use std::io::{SeekFrom, Write};
+
+use support::{Block, ImageBuilder, Layout};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use vdi::async_disk::AsyncVdiDisk;
+
+async fn open(fixture: &support::Fixture) -> anyhow::Result<AsyncVdiDisk> {
+    let mut file = tempfile::tempfile()?;
+    file.write_all(&fixture.image)?;
+    AsyncVdiDisk::open(tokio::fs::File::from_std(file)).await
+}
+
+#[tokio::test]
+async fn reads_stop_at_the_end_of_the_disk() {
+    let fixture = ImageBuilder::new(6 * 4096 + 100, 4096)
+        .pattern(&[Block::Data, Block::Free])
+        .layout(Layout::Reversed)
+        .build();
+    let mut disk = open(&fixture).await.unwrap();
+    let end = fixture.raw.len() as u64;
+
+    let mut all = Vec::new();
+    disk.read_to_end(&mut all).await.unwrap();
+    assert!(all == fixture.raw);
+
+    let mut buf = vec![0u8; 5000];
+    let mut filled = 0;
+    while let n @ 1.. = disk
+        .read_at(end - 150 + filled as u64, &mut buf[filled..])
+        .await
+        .unwrap()
+    {
+        filled += n;
+    }
+    assert_eq!(&buf[..filled], &fixture.raw[end as usize - 150..]);
+    assert_eq!(disk.read_at(end, &mut buf).await.unwrap(), 0);
+
+    disk.seek(SeekFrom::End(-10)).await.unwrap();
+    let mut tail = Vec::new();
+    disk.read_to_end(&mut tail).await.unwrap();
+    assert_eq!(tail, &fixture.raw[end as usize - 10..]);
+}
+
+#[tokio::test]
+async fn differencing_images_are_rejected() {
+    let base = ImageBuilder::new(8 * 4096, 4096).build();
+    let child = ImageBuilder::new(8 * 4096, 4096).parent(&base).build();
+    assert!(open(&child).await.is_err());
+}
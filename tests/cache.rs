@@ -0,0 +1,100 @@
+mod support;
+
+use positioned_io2::{ReadAt, WriteAt};
+use support::{Block, ImageBuilder, Layout};
+use vdi::VdiDisk;
+use vdi::cache::CacheConfig;
+
+const BLOCK_SIZE: u64 = 4096;
+const PAGE_SIZE: usize = 1024;
+
+fn enable_cache(disk: &mut VdiDisk, pages: usize) {
+    disk.enable_cache(CacheConfig {
+        capacity: pages * PAGE_SIZE,
+        page_size: PAGE_SIZE,
+    })
+    .unwrap();
+}
+
+#[test]
+fn cache_counts_hits_misses_and_evictions() {
+    let fixture = ImageBuilder::new(8 * BLOCK_SIZE, BLOCK_SIZE as u32)
+        .pattern(&[Block::Data, Block::Free, Block::Zero])
+        .layout(Layout::Shuffled(6))
+        .build();
+    let mut disk = fixture.open();
+    assert!(disk.cache_stats().is_none());
+    enable_cache(&mut disk, 4);
+
+    let mut buf = [0u8; 100];
+    disk.read_exact_at(10, &mut buf).unwrap();
+    disk.read_exact_at(500, &mut buf).unwrap();
+    assert!(buf[..] == fixture.raw[500..600]);
+    let stats = disk.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.pages), (1, 1, 1));
+
+    // Free and zero blocks are not cached
+    disk.read_exact_at(BLOCK_SIZE, &mut buf).unwrap();
+    disk.read_exact_at(2 * BLOCK_SIZE, &mut buf).unwrap();
+    assert_eq!(disk.cache_stats().unwrap().misses, 1);
+
+    // Across a page boundary
+    disk.read_exact_at(PAGE_SIZE as u64 - 50, &mut buf).unwrap();
+    assert!(buf[..] == fixture.raw[PAGE_SIZE - 50..][..100]);
+    let stats = disk.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.pages), (2, 2, 2));
+
+    // Filling the cache evicts the least recently used page
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    disk.read_exact_at(3 * BLOCK_SIZE, &mut block).unwrap();
+    assert!(block[..] == fixture.raw[3 * BLOCK_SIZE as usize..][..BLOCK_SIZE as usize]);
+    let stats = disk.cache_stats().unwrap();
+    assert_eq!((stats.misses, stats.evictions, stats.pages), (6, 2, 4));
+    disk.read_exact_at(10, &mut buf).unwrap();
+    assert_eq!(disk.cache_stats().unwrap().misses, 7);
+}
+
+#[test]
+fn cache_is_invalidated_by_writes() {
+    let fixture = ImageBuilder::new(8 * BLOCK_SIZE, BLOCK_SIZE as u32).build();
+    let mut disk = VdiDisk::open_writable(Box::new(fixture.image.clone())).unwrap();
+    enable_cache(&mut disk, 16);
+
+    let mut buf = [0u8; 100];
+    disk.read_exact_at(BLOCK_SIZE, &mut buf).unwrap();
+    disk.write_all_at(BLOCK_SIZE + 50, &[0x5A; 10]).unwrap();
+    disk.read_exact_at(BLOCK_SIZE, &mut buf).unwrap();
+    assert!(buf[..50] == fixture.raw[BLOCK_SIZE as usize..][..50]);
+    assert_eq!(buf[50..60], [0x5A; 10]);
+    let stats = disk.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (0, 2));
+
+    disk.invalidate_cache();
+    assert_eq!(disk.cache_stats().unwrap().pages, 0);
+    disk.read_exact_at(BLOCK_SIZE, &mut buf).unwrap();
+    assert_eq!(buf[50..60], [0x5A; 10]);
+    assert_eq!(disk.cache_stats().unwrap().misses, 3);
+}
+
+#[test]
+fn cached_reads_are_short_at_the_end_of_the_file_like_uncached_ones() {
+    let fixture = ImageBuilder::new(8 * BLOCK_SIZE, BLOCK_SIZE as u32).build();
+    // The last block is cut off inside its first page
+    let mut image = fixture.image.clone();
+    image.truncate(fixture.header.data_offset.get() as usize + 7 * BLOCK_SIZE as usize + 1000);
+    let uncached = VdiDisk::open(Box::new(image.clone())).unwrap();
+    let mut cached = VdiDisk::open(Box::new(image)).unwrap();
+    enable_cache(&mut cached, 16);
+
+    for pos in [7 * BLOCK_SIZE, 7 * BLOCK_SIZE + 600, 7 * BLOCK_SIZE + 2000] {
+        let mut expected = [0u8; 2048];
+        let mut buf = [0u8; 2048];
+        let n = uncached.read_at(pos, &mut expected).unwrap();
+        assert_eq!(cached.read_at(pos, &mut buf).unwrap(), n, "at {pos}");
+        assert!(buf[..n] == expected[..n]);
+    }
+    assert_eq!(
+        uncached.read_at(7 * BLOCK_SIZE, &mut [0; 2048]).unwrap(),
+        1000
+    );
+}
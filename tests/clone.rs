@@ -0,0 +1,148 @@
+mod support;
+
+use positioned_io2::ReadAt;
+use support::{Block, ImageBuilder, Layout};
+use vdi::header::VdiHeader;
+use vdi::{CloneOptions, VdiDisk};
+
+fn read_all(disk: &VdiDisk) -> Vec<u8> {
+    let mut data = vec![0u8; disk.header.disk_size.get() as usize];
+    disk.read_exact_at(0, &mut data).unwrap();
+    data
+}
+
+/// Whether the stored blocks of `disk` follow each other in logical order from the start of the
+/// data area
+fn in_logical_order(disk: &VdiDisk) -> bool {
+    let data_offset = disk.header.data_offset.get() as u64;
+    let offsets = disk.block_offsets.iter().flatten();
+    offsets
+        .enumerate()
+        .all(|(slot, &offset)| offset == data_offset + slot as u64 * disk.block_size as u64)
+}
+
+#[test]
+fn clone_gets_fresh_uuids() {
+    let fixture = ImageBuilder::new(12 * 4096, 4096)
+        .pattern(&[Block::Data, Block::Free, Block::Zeros])
+        .layout(Layout::Shuffled(5))
+        .build();
+    let disk = fixture.open();
+
+    let clone = disk
+        .clone_to(Box::new(Vec::new()), CloneOptions::default())
+        .unwrap();
+    assert_ne!(clone.header.uuid_image, fixture.header.uuid_image);
+    assert_ne!(clone.header.uuid_last_snap, fixture.header.uuid_last_snap);
+    assert_ne!(clone.header.uuid_image, clone.header.uuid_last_snap);
+    assert_eq!(clone.header.blocks_allocated.get(), 8);
+    assert!(in_logical_order(&clone));
+    assert!(read_all(&clone) == fixture.raw);
+}
+
+#[test]
+fn clone_of_a_differencing_image_keeps_its_parent() {
+    let parent = ImageBuilder::new(12 * 4096, 4096).build();
+    let child = ImageBuilder::new(12 * 4096, 4096)
+        .pattern(&[Block::Free, Block::Data])
+        .seed(2)
+        .parent(&parent)
+        .build();
+    let disk = child.open_with_parent(parent.open());
+
+    let mut clone = disk
+        .clone_to(Box::new(Vec::new()), CloneOptions::default())
+        .unwrap();
+    assert_eq!(clone.header.image_type.get(), VdiHeader::TYPE_DIFFERENCING);
+    assert_eq!(clone.header.uuid_link, parent.header.uuid_image);
+    assert_eq!(clone.header.blocks_allocated.get(), 6);
+    clone.set_parent(parent.open()).unwrap();
+    assert!(read_all(&clone) == child.raw);
+}
+
+#[test]
+fn flattened_clone_reads_like_the_chain() {
+    let base = ImageBuilder::new(12 * 4096 + 1000, 4096)
+        .pattern(&[Block::Data, Block::Free, Block::Free])
+        .build();
+    let middle = ImageBuilder::new(12 * 4096 + 1000, 4096)
+        .pattern(&[Block::Free, Block::Data, Block::Free, Block::Zero])
+        .layout(Layout::Reversed)
+        .seed(2)
+        .parent(&base)
+        .build();
+    let top = ImageBuilder::new(12 * 4096 + 1000, 4096)
+        .pattern(&[Block::Free, Block::Free, Block::Data])
+        .seed(3)
+        .parent(&middle)
+        .build();
+    let disk = top.open_with_parent(middle.open_with_parent(base.open()));
+
+    let options = CloneOptions {
+        flatten: true,
+        ..Default::default()
+    };
+    let flat = disk.clone_to(Box::new(Vec::new()), options).unwrap();
+    assert_eq!(flat.header.image_type.get(), VdiHeader::TYPE_DYNAMIC);
+    assert!(flat.header.uuid_link.is_nil());
+    assert!(flat.parent().is_none());
+    assert!(in_logical_order(&flat));
+    assert!(read_all(&flat) == top.raw);
+
+    // Without the parents there is nothing to flatten from
+    assert!(top.open().clone_to(Box::new(Vec::new()), options).is_err());
+}
+
+#[test]
+fn compacted_clone_leaves_out_zero_blocks() {
+    let fixture = ImageBuilder::new(12 * 4096, 4096)
+        .pattern(&[Block::Data, Block::Zeros, Block::Free, Block::Zero])
+        .layout(Layout::Reversed)
+        .build();
+    let disk = fixture.open();
+
+    let file = tempfile::tempfile().unwrap();
+    let options = CloneOptions {
+        compact: true,
+        ..Default::default()
+    };
+    let clone = disk
+        .clone_to(Box::new(file.try_clone().unwrap()), options)
+        .unwrap();
+    assert_eq!(clone.header.blocks_allocated.get(), 3);
+    assert_eq!(
+        clone.block_offsets.iter().flatten().count(),
+        3,
+        "zeroed blocks are not stored"
+    );
+    assert_eq!(
+        file.metadata().unwrap().len(),
+        clone.header.data_offset.get() as u64 + 3 * 4096
+    );
+    assert!(read_all(&clone) == fixture.raw);
+}
+
+#[test]
+fn repack_to_keeps_the_uuids_and_orders_the_blocks() {
+    let fixture = ImageBuilder::new(12 * 4096, 4096)
+        .pattern(&[Block::Data, Block::Free, Block::Zeros])
+        .layout(Layout::Shuffled(9))
+        .stale_slots(2)
+        .build();
+    let disk = fixture.open();
+
+    let file = tempfile::tempfile().unwrap();
+    let repacked = disk.repack_to(Box::new(file.try_clone().unwrap())).unwrap();
+    assert_eq!(repacked.header.uuid_image, fixture.header.uuid_image);
+    assert_eq!(
+        repacked.header.uuid_last_snap,
+        fixture.header.uuid_last_snap
+    );
+    assert_eq!(repacked.header.blocks_allocated.get(), 8);
+    assert!(in_logical_order(&repacked));
+    assert_eq!(
+        file.metadata().unwrap().len(),
+        repacked.header.data_offset.get() as u64 + 8 * 4096
+    );
+    assert!(read_all(&repacked) == fixture.raw);
+}
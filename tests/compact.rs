@@ -0,0 +1,178 @@
+mod support;
+
+use std::cell::RefCell;
+use std::ops::ControlFlow;
+use std::rc::Rc;
+
+use positioned_io2::{ReadAt, WriteAt};
+use support::{Block, ImageBuilder, Layout};
+use vdi::VdiDisk;
+use vdi::progress::{Progress, is_cancelled};
+
+fn read_all(disk: &VdiDisk) -> Vec<u8> {
+    let mut data = vec![0u8; disk.header.disk_size.get() as usize];
+    disk.read_exact_at(0, &mut data).unwrap();
+    data
+}
+
+#[test]
+fn compact_moves_blocks_only_with_a_journal() {
+    let fixture = ImageBuilder::new(16 * 4096, 4096)
+        .pattern(&[Block::Data, Block::Zeros, Block::Free])
+        .layout(Layout::Shuffled(11))
+        .stale_slots(2)
+        .build();
+
+    let mut disk = VdiDisk::open_writable(Box::new(fixture.image.clone())).unwrap();
+    assert!(disk.compact().is_err());
+    assert!(read_all(&disk) == fixture.raw);
+
+    let mut disk =
+        VdiDisk::open_journaled(Box::new(fixture.image.clone()), Box::new(Vec::new())).unwrap();
+    let reclaimed = disk.compact().unwrap();
+    assert_eq!(reclaimed, (2 + 5) * 4096);
+    assert!(read_all(&disk) == fixture.raw);
+}
+
+#[test]
+fn resize_moves_blocks_only_with_a_journal() {
+    let fixture = ImageBuilder::new(8 * 4096, 4096).packed().build();
+    let grown = 4096 * 4096;
+
+    let mut disk = VdiDisk::open_writable(Box::new(fixture.image.clone())).unwrap();
+    assert!(disk.resize(grown).is_err());
+    assert_eq!(disk.header.disk_size.get(), fixture.raw.len() as u64);
+
+    let mut disk =
+        VdiDisk::open_journaled(Box::new(fixture.image.clone()), Box::new(Vec::new())).unwrap();
+    disk.resize(grown).unwrap();
+    let data = read_all(&disk);
+    assert!(data[..fixture.raw.len()] == fixture.raw);
+    assert!(data[fixture.raw.len()..].iter().all(|&b| b == 0));
+}
+
+/// Handler cancelling the block moves before move `cancel_at`, recording the progress of the
+/// moves
+fn cancel_moves(
+    scan_total: u64,
+    cancel_at: u64,
+    log: Rc<RefCell<Vec<Progress>>>,
+) -> impl FnMut(&Progress) -> ControlFlow<()> {
+    move |progress: &Progress| {
+        if progress.bytes_total == scan_total {
+            return ControlFlow::Continue(());
+        }
+        log.borrow_mut().push(*progress);
+        if progress.bytes_processed > cancel_at * 4096 {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+#[test]
+fn cancelled_compact_keeps_the_moved_blocks() {
+    let fixture = ImageBuilder::new(16 * 4096, 4096)
+        .pattern(&[Block::Data, Block::Zeros, Block::Free])
+        .stale_slots(4)
+        .build();
+    let mut disk =
+        VdiDisk::open_journaled(Box::new(fixture.image.clone()), Box::new(Vec::new())).unwrap();
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    disk.set_progress_handler(cancel_moves(16 * 4096, 2, log.clone()));
+    let error = disk.compact().unwrap_err();
+    assert!(is_cancelled(error.as_ref()));
+    let log = log.borrow();
+    assert_eq!(log.len(), 3);
+    assert!(log.iter().all(|p| p.bytes_total == 5 * 4096));
+    assert!(read_all(&disk) == fixture.raw);
+
+    disk.take_progress_handler();
+    assert_eq!(disk.compact().unwrap(), (4 + 5) * 4096);
+    assert_eq!(disk.header.blocks_allocated.get(), 6);
+    assert!(read_all(&disk) == fixture.raw);
+}
+
+#[test]
+fn resize_reports_and_cancels_block_moves() {
+    let fixture = ImageBuilder::new(8 * 4096, 4096).packed().build();
+    let grown = 4096 * 4096;
+
+    let mut disk =
+        VdiDisk::open_journaled(Box::new(fixture.image.clone()), Box::new(Vec::new())).unwrap();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    disk.set_progress_handler(cancel_moves(0, 0, log.clone()));
+    let error = disk.resize(grown).unwrap_err();
+    assert!(is_cancelled(error.as_ref()));
+    assert_eq!(log.borrow().len(), 1);
+    assert_eq!(disk.header.disk_size.get(), fixture.raw.len() as u64);
+    assert!(read_all(&disk) == fixture.raw);
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    disk.set_progress_handler(cancel_moves(0, u64::MAX / 4096, log.clone()));
+    disk.resize(grown).unwrap();
+    let log = log.borrow();
+    let last = log.last().unwrap();
+    assert_eq!(last.bytes_processed, last.bytes_total);
+    assert_eq!(log.len() as u64, last.bytes_total / 4096);
+    assert!(read_all(&disk)[..fixture.raw.len()] == fixture.raw);
+}
+
+#[test]
+fn torn_journal_records_are_discarded() {
+    let fixture = ImageBuilder::new(8 * 4096, 4096)
+        .pattern(&[Block::Data, Block::Free])
+        .build();
+
+    // Record lengths past the end of the journal, without and with the payload overflowing
+    for (len, written) in [(100, 50), (1 << 40, 0), (u64::MAX - 8, 0)] {
+        let mut record = b"VDIJRNL1".to_vec();
+        record.extend_from_slice(&u64::to_le_bytes(len));
+        record.extend_from_slice(&[0u8; 8]);
+        record.extend(std::iter::repeat_n(0x11, written));
+
+        let mut journal = tempfile::tempfile().unwrap();
+        journal.write_all_at(0, &record).unwrap();
+        let disk = VdiDisk::open_journaled(
+            Box::new(fixture.image.clone()),
+            Box::new(journal.try_clone().unwrap()),
+        )
+        .unwrap();
+        assert!(read_all(&disk) == fixture.raw);
+        assert_eq!(
+            journal.metadata().unwrap().len(),
+            0,
+            "record of {len} bytes"
+        );
+    }
+}
+
+#[test]
+fn repack_stores_the_blocks_in_logical_order() {
+    for (layout, stale_slots) in [(Layout::Reversed, 0), (Layout::Shuffled(5), 3)] {
+        let fixture = ImageBuilder::new(16 * 4096 + 1000, 4096)
+            .pattern(&[Block::Data, Block::Free, Block::Data, Block::Zero])
+            .layout(layout)
+            .stale_slots(stale_slots)
+            .build();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all_at(0, &fixture.image).unwrap();
+        let mut disk = VdiDisk::open_writable(Box::new(file.try_clone().unwrap())).unwrap();
+
+        assert!(disk.repack().unwrap() > 0);
+        assert_eq!(disk.repack().unwrap(), 0, "{layout:?}");
+        let data_offset = disk.header.data_offset.get() as u64;
+        let stored: Vec<u64> = disk.block_offsets.iter().flatten().copied().collect();
+        let in_order: Vec<u64> = (0..9).map(|slot| data_offset + slot * 4096).collect();
+        assert_eq!(stored, in_order, "{layout:?}");
+        assert_eq!(disk.header.blocks_allocated.get(), 9);
+        assert_eq!(file.metadata().unwrap().len(), data_offset + 9 * 4096);
+        assert!(read_all(&disk) == fixture.raw, "{layout:?}");
+
+        drop(disk);
+        let reopened = VdiDisk::open(Box::new(file)).unwrap();
+        assert!(read_all(&reopened) == fixture.raw, "{layout:?}");
+    }
+}
@@ -0,0 +1,155 @@
+//! Reads `data/encrypted.vdi`, which holds the image built by [`plain`] with its stored blocks
+//! encrypted with AES-XTS256 by an independent implementation. The data key is wrapped in
+//! `data/encrypted.keystore` with PBKDF2-SHA256 and the password [`PASSWORD`].
+
+#![cfg(feature = "crypt")]
+
+mod support;
+
+use positioned_io2::ReadAt;
+use support::{Block, ImageBuilder, Layout};
+use vdi::VdiDisk;
+use vdi::cache::CacheConfig;
+use vdi::crypt::{KeyStore, SectorCipher};
+
+const PASSWORD: &str = "correct horse";
+const IMAGE: &[u8] = include_bytes!("data/encrypted.vdi");
+const KEY_STORE: &str = include_str!("data/encrypted.keystore");
+
+fn plain() -> support::Fixture {
+    ImageBuilder::new(6 * 4096 + 1000, 4096)
+        .pattern(&[Block::Data, Block::Free, Block::Data, Block::Zero])
+        .layout(Layout::Reversed)
+        .packed()
+        .build()
+}
+
+fn cipher() -> SectorCipher {
+    KeyStore::from_base64(KEY_STORE)
+        .unwrap()
+        .unlock(PASSWORD)
+        .unwrap()
+}
+
+fn raw_key_store() -> Vec<u8> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD
+        .decode(KEY_STORE.trim())
+        .unwrap()
+}
+
+fn open() -> VdiDisk {
+    let mut disk = VdiDisk::open(Box::new(IMAGE.to_vec())).unwrap();
+    disk.set_encryption(cipher()).unwrap();
+    disk
+}
+
+#[test]
+fn key_store_unlocks_with_the_password_only() {
+    let key_store = KeyStore::from_base64(KEY_STORE).unwrap();
+    assert_eq!(key_store.cipher, "AES-XTS256-PLAIN64");
+    assert_eq!(key_store.kdf, "PBKDF2-SHA256");
+    assert!(key_store.unlock(PASSWORD).is_ok());
+    assert!(key_store.unlock("wrong horse").is_err());
+    assert!(key_store.unlock("").is_err());
+
+    let mut raw = raw_key_store();
+    raw[0] ^= 1;
+    assert!(KeyStore::parse(&raw).is_err());
+    assert!(KeyStore::parse(&raw[1..]).is_err());
+}
+
+#[test]
+fn encrypted_image_stores_no_plain_data() {
+    let fixture = plain();
+    assert_eq!(IMAGE.len(), fixture.image.len());
+    // The headers differ in their random UUIDs, the block maps must match
+    let map_offset = fixture.header.block_offsets_offset.get() as usize;
+    let data_offset = fixture.header.data_offset.get() as usize;
+    assert_eq!(
+        IMAGE[map_offset..data_offset],
+        fixture.image[map_offset..data_offset]
+    );
+    assert!(IMAGE[data_offset..] != fixture.image[data_offset..]);
+}
+
+#[test]
+fn reads_are_decrypted() {
+    let fixture = plain();
+    let disk = open();
+
+    let mut all = vec![0u8; fixture.raw.len()];
+    disk.read_exact_at(0, &mut all).unwrap();
+    assert!(all == fixture.raw);
+
+    // Unaligned reads decrypt the whole sectors around them
+    let mut rng = support::Rng::new(3);
+    for _ in 0..200 {
+        let pos = rng.below(fixture.raw.len() as u64);
+        let len = 1 + rng.below(3000) as usize;
+        let len = len.min(fixture.raw.len() - pos as usize);
+        let mut buf = vec![0u8; len];
+        disk.read_exact_at(pos, &mut buf).unwrap();
+        assert_eq!(buf, &fixture.raw[pos as usize..][..len], "{pos}+{len}");
+    }
+}
+
+#[test]
+fn cached_reads_are_decrypted() {
+    let fixture = plain();
+    let mut disk = open();
+    disk.enable_cache(CacheConfig {
+        capacity: 8 * 1024,
+        page_size: 1024,
+    })
+    .unwrap();
+
+    let mut rng = support::Rng::new(5);
+    for _ in 0..200 {
+        let pos = rng.below(fixture.raw.len() as u64);
+        let len = 1 + rng.below(2000) as usize;
+        let len = len.min(fixture.raw.len() - pos as usize);
+        let mut buf = vec![0u8; len];
+        disk.read_exact_at(pos, &mut buf).unwrap();
+        assert_eq!(buf, &fixture.raw[pos as usize..][..len], "{pos}+{len}");
+    }
+    assert!(disk.cache_stats().unwrap().hits > 0);
+}
+
+#[test]
+fn block_scans_are_decrypted() {
+    let fixture = plain();
+    let disk = open();
+
+    let mut all = Vec::new();
+    disk.for_each_block(|index, block| {
+        assert_eq!(all.len(), index * 4096);
+        all.extend_from_slice(block);
+        Ok(())
+    })
+    .unwrap();
+    assert!(all == fixture.raw);
+
+    // Written decrypted
+    let copy = disk.repack_to(Box::new(Vec::new())).unwrap();
+    let mut all = vec![0u8; fixture.raw.len()];
+    copy.read_exact_at(0, &mut all).unwrap();
+    assert!(all == fixture.raw);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn encrypted_blocks_are_not_mapped() {
+    use std::io::Write;
+
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(IMAGE).unwrap();
+    let mut disk = unsafe { VdiDisk::open_mmap(&file) }.unwrap();
+    assert!(disk.mapped_block(0).is_some());
+
+    disk.set_encryption(cipher()).unwrap();
+    assert!(disk.mapped_block(0).is_none());
+    let mut block = vec![0u8; 4096];
+    disk.read_exact_at(0, &mut block).unwrap();
+    assert_eq!(block, &plain().raw[..4096]);
+}
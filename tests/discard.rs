@@ -0,0 +1,124 @@
+mod support;
+
+use positioned_io2::{ReadAt, WriteAt};
+use support::{Block, Fixture, ImageBuilder, Layout};
+use vdi::VdiDisk;
+
+const BLOCK_SIZE: u64 = 4096;
+const DISK_SIZE: u64 = 8 * BLOCK_SIZE + 1000;
+
+fn read_all(disk: &VdiDisk) -> Vec<u8> {
+    let mut data = vec![0u8; disk.header.disk_size.get() as usize];
+    disk.read_exact_at(0, &mut data).unwrap();
+    data
+}
+
+/// Opens a writable copy of `fixture` in a temporary file, also returned to check its length
+fn open_file(fixture: &Fixture) -> (VdiDisk, std::fs::File) {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all_at(0, &fixture.image).unwrap();
+    let disk = VdiDisk::open_writable(Box::new(file.try_clone().unwrap())).unwrap();
+    (disk, file)
+}
+
+fn full_fixture() -> Fixture {
+    ImageBuilder::new(DISK_SIZE, BLOCK_SIZE as u32)
+        .layout(Layout::Shuffled(4))
+        .build()
+}
+
+#[test]
+fn discarding_whole_blocks_releases_them() {
+    let fixture = full_fixture();
+    let (mut disk, file) = open_file(&fixture);
+
+    disk.discard(BLOCK_SIZE..3 * BLOCK_SIZE, false).unwrap();
+    assert_eq!(disk.block_offsets[1..3], [None, None]);
+    assert_eq!(disk.header.blocks_allocated.get(), 7);
+    let data_end = disk.header.data_offset.get() as u64 + 7 * BLOCK_SIZE;
+    assert_eq!(file.metadata().unwrap().len(), data_end);
+
+    let mut expected = fixture.raw.clone();
+    expected[BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize].fill(0);
+    assert!(read_all(&disk) == expected);
+
+    // The last, partial block is covered up to the end of the disk
+    disk.discard(8 * BLOCK_SIZE..u64::MAX, false).unwrap();
+    assert_eq!(disk.block_offsets[8], None);
+    assert_eq!(disk.header.blocks_allocated.get(), 6);
+    assert_eq!(file.metadata().unwrap().len(), data_end - BLOCK_SIZE);
+    expected[8 * BLOCK_SIZE as usize..].fill(0);
+
+    let reopened = VdiDisk::open(Box::new(file)).unwrap();
+    assert!(read_all(&reopened) == expected);
+    assert_eq!(
+        reopened.stats(data_end - BLOCK_SIZE).unwrap().unused_slots,
+        0
+    );
+}
+
+#[test]
+fn discarding_part_of_a_block_zero_fills_it() {
+    let fixture = full_fixture();
+    let (mut disk, file) = open_file(&fixture);
+    let file_len = file.metadata().unwrap().len();
+
+    let range = BLOCK_SIZE + 100..3 * BLOCK_SIZE + 50;
+    disk.discard(range.clone(), false).unwrap();
+    // Only block 2 is covered completely
+    assert!(disk.block_offsets[1].is_some());
+    assert_eq!(disk.block_offsets[2], None);
+    assert!(disk.block_offsets[3].is_some());
+    assert_eq!(disk.header.blocks_allocated.get(), 8);
+    assert_eq!(file.metadata().unwrap().len(), file_len - BLOCK_SIZE);
+
+    let mut expected = fixture.raw.clone();
+    expected[range.start as usize..range.end as usize].fill(0);
+    assert!(read_all(&disk) == expected);
+
+    disk.discard(5 * BLOCK_SIZE + 1..6 * BLOCK_SIZE - 1, false)
+        .unwrap();
+    assert_eq!(disk.header.blocks_allocated.get(), 8);
+    expected[5 * BLOCK_SIZE as usize + 1..6 * BLOCK_SIZE as usize - 1].fill(0);
+    assert!(read_all(&disk) == expected);
+}
+
+#[test]
+fn deferred_shrink_leaves_the_slots_unused() {
+    let fixture = full_fixture();
+    let (mut disk, file) = open_file(&fixture);
+    let file_len = file.metadata().unwrap().len();
+
+    disk.discard(0..4 * BLOCK_SIZE, true).unwrap();
+    assert_eq!(disk.block_offsets[..4], [None; 4]);
+    assert_eq!(disk.header.blocks_allocated.get(), 9);
+    assert_eq!(file.metadata().unwrap().len(), file_len);
+
+    let stats = disk.stats(file_len).unwrap();
+    assert_eq!(stats.allocated_blocks, 5);
+    assert_eq!(stats.zero_blocks, 4);
+    assert_eq!(stats.unused_slots, 4);
+    assert_eq!(stats.reclaimable_bytes, 4 * BLOCK_SIZE);
+
+    let mut expected = fixture.raw.clone();
+    expected[..4 * BLOCK_SIZE as usize].fill(0);
+    assert!(read_all(&disk) == expected);
+}
+
+#[test]
+fn discard_hides_the_data_of_the_parent() {
+    let parent = ImageBuilder::new(DISK_SIZE, BLOCK_SIZE as u32).build();
+    let child = ImageBuilder::new(DISK_SIZE, BLOCK_SIZE as u32)
+        .pattern(&[Block::Free, Block::Data])
+        .seed(2)
+        .parent(&parent)
+        .build();
+    let mut disk = VdiDisk::open_writable(Box::new(child.image.clone())).unwrap();
+    disk.set_parent(parent.open()).unwrap();
+
+    disk.discard(0..4 * BLOCK_SIZE, false).unwrap();
+    assert_eq!(disk.header.blocks_allocated.get(), 2);
+    let mut expected = child.raw.clone();
+    expected[..4 * BLOCK_SIZE as usize].fill(0);
+    assert!(read_all(&disk) == expected);
+}
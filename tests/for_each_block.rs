@@ -0,0 +1,88 @@
+mod support;
+
+use positioned_io2::WriteAt;
+use support::{Block, Fixture, ImageBuilder, Layout};
+use vdi::VdiDisk;
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Opens `image` on each backend reading whole blocks differently
+fn backends(image: &[u8]) -> Vec<(&'static str, VdiDisk)> {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all_at(0, image).unwrap();
+    let reader = VdiDisk::open(Box::new(file.try_clone().unwrap())).unwrap();
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    let uring = [1, 3, 64].map(|queue_depth| {
+        let file = file.try_clone().unwrap();
+        ("uring", VdiDisk::open_uring(file, queue_depth).unwrap())
+    });
+    #[cfg(not(all(feature = "uring", target_os = "linux")))]
+    let uring = [];
+    std::iter::once(("reader", reader)).chain(uring).collect()
+}
+
+/// Concatenates the blocks passed by `for_each_block`, checking they come in order
+fn scan(disk: &VdiDisk) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut next = 0;
+    disk.for_each_block(|index, block| {
+        assert_eq!(index, next);
+        next += 1;
+        data.extend_from_slice(block);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(next, disk.block_offsets.len());
+    data
+}
+
+fn fixture() -> Fixture {
+    ImageBuilder::new(20 * BLOCK_SIZE as u64 + 1000, BLOCK_SIZE as u32)
+        .pattern(&[
+            Block::Data,
+            Block::Free,
+            Block::Zeros,
+            Block::Zero,
+            Block::Data,
+        ])
+        .layout(Layout::Shuffled(8))
+        .build()
+}
+
+#[test]
+fn blocks_are_passed_in_logical_order() {
+    let fixture = fixture();
+    for (backend, disk) in backends(&fixture.image) {
+        assert!(scan(&disk) == fixture.raw, "{backend}");
+    }
+}
+
+#[test]
+fn blocks_of_the_parent_are_passed_for_free_blocks() {
+    let parent = ImageBuilder::new(8 * BLOCK_SIZE as u64, BLOCK_SIZE as u32).build();
+    let child = ImageBuilder::new(8 * BLOCK_SIZE as u64, BLOCK_SIZE as u32)
+        .pattern(&[Block::Free, Block::Data, Block::Zero])
+        .layout(Layout::Reversed)
+        .seed(2)
+        .parent(&parent)
+        .build();
+    for (backend, mut disk) in backends(&child.image) {
+        disk.set_parent(parent.open()).unwrap();
+        assert!(scan(&disk) == child.raw, "{backend}");
+    }
+}
+
+#[test]
+fn blocks_cut_off_by_the_end_of_the_file_read_as_zeros() {
+    let fixture = ImageBuilder::new(8 * BLOCK_SIZE as u64, BLOCK_SIZE as u32).build();
+    let mut image = fixture.image.clone();
+    image.truncate(image.len() - BLOCK_SIZE + 1000);
+    let mut expected = fixture.raw.clone();
+    expected[7 * BLOCK_SIZE + 1000..].fill(0);
+
+    for (backend, disk) in backends(&image) {
+        assert!(scan(&disk) == expected, "{backend}");
+        let stats = disk.stats(image.len() as u64).unwrap();
+        assert_eq!(stats.allocated_blocks, 8, "{backend}");
+    }
+}
@@ -0,0 +1,56 @@
+mod support;
+
+use std::fs::File;
+use std::path::Path;
+
+use support::ImageBuilder;
+use vdi::VdiDisk;
+use vdi::lock::LockMode;
+
+/// Opens the image at `path` again, as another process would. `flock` locks belong to the open
+/// file, so they conflict between two opens of the same path.
+fn open_locked(path: &Path, mode: LockMode) -> anyhow::Result<VdiDisk> {
+    let file = File::options()
+        .read(true)
+        .write(mode == LockMode::Exclusive)
+        .open(path)?;
+    VdiDisk::open_locked(file, mode)
+}
+
+fn write_fixture() -> tempfile::NamedTempFile {
+    let fixture = ImageBuilder::new(8 * 4096, 4096).build();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), &fixture.image).unwrap();
+    file
+}
+
+#[test]
+fn exclusive_lock_excludes_all_others() {
+    let file = write_fixture();
+    let disk = open_locked(file.path(), LockMode::Exclusive).unwrap();
+    assert_eq!(disk.lock_mode(), Some(LockMode::Exclusive));
+
+    let Err(err) = open_locked(file.path(), LockMode::Exclusive) else {
+        panic!("a second exclusive lock was taken");
+    };
+    assert!(err.to_string().contains("locked"), "{err}");
+    assert!(open_locked(file.path(), LockMode::Shared).is_err());
+
+    // Closing the image releases the lock
+    drop(disk);
+    open_locked(file.path(), LockMode::Exclusive).unwrap();
+}
+
+#[test]
+fn shared_locks_exclude_only_writers() {
+    let file = write_fixture();
+    let first = open_locked(file.path(), LockMode::Shared).unwrap();
+    let second = open_locked(file.path(), LockMode::Shared).unwrap();
+    assert_eq!(second.lock_mode(), Some(LockMode::Shared));
+    assert!(open_locked(file.path(), LockMode::Exclusive).is_err());
+
+    drop(first);
+    assert!(open_locked(file.path(), LockMode::Exclusive).is_err());
+    drop(second);
+    open_locked(file.path(), LockMode::Exclusive).unwrap();
+}
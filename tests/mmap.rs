@@ -0,0 +1,69 @@
+#![cfg(feature = "mmap")]
+
+mod support;
+
+use std::fs::File;
+
+use positioned_io2::{ReadAt, WriteAt};
+use support::{Block, Fixture, ImageBuilder, Layout};
+use vdi::VdiDisk;
+
+const BLOCK_SIZE: usize = 4096;
+
+fn write_file(image: &[u8]) -> File {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all_at(0, image).unwrap();
+    file
+}
+
+fn block(fixture: &Fixture, index: usize) -> &[u8] {
+    &fixture.raw[index * BLOCK_SIZE..][..BLOCK_SIZE]
+}
+
+#[test]
+fn mapped_blocks_hold_the_block_data() {
+    let fixture = ImageBuilder::new(9 * BLOCK_SIZE as u64, BLOCK_SIZE as u32)
+        .pattern(&[Block::Data, Block::Free, Block::Zero])
+        .layout(Layout::Shuffled(2))
+        .build();
+    let file = write_file(&fixture.image);
+    let disk = unsafe { VdiDisk::open_mmap(&file) }.unwrap();
+
+    for index in [0, 3, 6] {
+        assert!(disk.mapped_block(index) == Some(block(&fixture, index)));
+    }
+    // Free and zero blocks map to zeros
+    for index in [1, 2, 7, 8] {
+        assert!(disk.mapped_block(index) == Some(&[0; BLOCK_SIZE][..]));
+    }
+    assert!(disk.mapped_block(9).is_none());
+
+    let mut buf = vec![0u8; fixture.raw.len()];
+    disk.read_exact_at(0, &mut buf).unwrap();
+    assert!(buf == fixture.raw);
+
+    // Only mapped disks hand out their blocks
+    assert!(fixture.open().mapped_block(0).is_none());
+}
+
+#[test]
+fn blocks_not_in_the_mapping_are_not_mapped() {
+    let parent = ImageBuilder::new(4 * BLOCK_SIZE as u64, BLOCK_SIZE as u32).build();
+    let child = ImageBuilder::new(4 * BLOCK_SIZE as u64, BLOCK_SIZE as u32)
+        .pattern(&[Block::Free, Block::Data])
+        .seed(2)
+        .parent(&parent)
+        .build();
+    // The last stored block is cut off by the end of the file
+    let mut image = child.image.clone();
+    image.truncate(image.len() - 100);
+    let file = write_file(&image);
+    let mut disk = unsafe { VdiDisk::open_mmap(&file) }.unwrap();
+    disk.set_parent(parent.open()).unwrap();
+
+    assert!(disk.mapped_block(1) == Some(block(&child, 1)));
+    assert!(disk.mapped_block(3).is_none());
+    // Free blocks of a differencing image are read from the parent
+    assert!(disk.mapped_block(0).is_none());
+    assert!(disk.mapped_block(2).is_none());
+}
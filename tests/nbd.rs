@@ -0,0 +1,300 @@
+#![cfg(unix)]
+
+mod support;
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+use positioned_io2::ReadAt;
+use support::{Block, ImageBuilder};
+use vdi::VdiDisk;
+use vdi::nbd::{NbdOptions, NbdServer};
+
+const BLOCK_SIZE: u32 = 4096;
+
+const NBD_EINVAL: u32 = 22;
+const NBD_REPLY_FLAG_DONE: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const NBD_REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+const NBD_STATE_HOLE: u32 = 1;
+
+/// Minimal NBD client speaking the fixed newstyle handshake
+struct Client {
+    stream: UnixStream,
+    cookie: u64,
+}
+
+/// Structured reply chunk
+struct Chunk {
+    kind: u16,
+    payload: Vec<u8>,
+}
+
+impl Client {
+    /// Negotiates structured replies, optionally `base:allocation`, and enters transmission.
+    /// Returns the export size.
+    fn connect(stream: UnixStream, base_allocation: bool) -> (Self, u64) {
+        let mut client = Self { stream, cookie: 0 };
+        assert_eq!(&client.read_vec(8), b"NBDMAGIC");
+        assert_eq!(&client.read_vec(8), b"IHAVEOPT");
+        let server_flags = client.read_u16();
+        assert_eq!(server_flags & 1, 1, "fixed newstyle");
+        client.send(&3u32.to_be_bytes()); // Fixed newstyle, no zeroes
+
+        client.option(8, &[]);
+        assert_eq!(client.option_reply(8).0, 1);
+
+        if base_allocation {
+            let mut query = 0u32.to_be_bytes().to_vec(); // Default export
+            query.extend_from_slice(&1u32.to_be_bytes());
+            query.extend_from_slice(&15u32.to_be_bytes());
+            query.extend_from_slice(b"base:allocation");
+            client.option(10, &query);
+            let (kind, data) = client.option_reply(10);
+            assert_eq!(kind, 4, "meta context");
+            assert_eq!(&data[4..], b"base:allocation");
+            assert_eq!(client.option_reply(10).0, 1);
+        }
+
+        let mut go = 0u32.to_be_bytes().to_vec();
+        go.extend_from_slice(&0u16.to_be_bytes());
+        client.option(7, &go);
+        let mut size = None;
+        loop {
+            match client.option_reply(7) {
+                (1, _) => break,
+                (3, info) if info[..2] == [0, 0] => {
+                    size = Some(u64::from_be_bytes(info[2..10].try_into().unwrap()));
+                }
+                (3, _) => {}
+                (kind, _) => panic!("unexpected option reply {kind:#x}"),
+            }
+        }
+        (client, size.expect("export info"))
+    }
+
+    fn option(&mut self, option: u32, data: &[u8]) {
+        let mut request = b"IHAVEOPT".to_vec();
+        request.extend_from_slice(&option.to_be_bytes());
+        request.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        request.extend_from_slice(data);
+        self.send(&request);
+    }
+
+    fn option_reply(&mut self, option: u32) -> (u32, Vec<u8>) {
+        assert_eq!(self.read_u64(), 0x0003e889045565a9);
+        assert_eq!(self.read_u32(), option);
+        let kind = self.read_u32();
+        let len = self.read_u32() as usize;
+        (kind, self.read_vec(len))
+    }
+
+    /// Sends a request and collects its reply chunks
+    fn request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+    ) -> Vec<Chunk> {
+        self.cookie += 1;
+        let mut request = 0x25609513u32.to_be_bytes().to_vec();
+        request.extend_from_slice(&flags.to_be_bytes());
+        request.extend_from_slice(&command.to_be_bytes());
+        request.extend_from_slice(&self.cookie.to_be_bytes());
+        request.extend_from_slice(&offset.to_be_bytes());
+        request.extend_from_slice(&len.to_be_bytes());
+        request.extend_from_slice(data);
+        self.send(&request);
+
+        let mut chunks = Vec::new();
+        loop {
+            assert_eq!(self.read_u32(), 0x668e33ef, "structured reply");
+            let flags = self.read_u16();
+            let kind = self.read_u16();
+            assert_eq!(self.read_u64(), self.cookie);
+            let len = self.read_u32() as usize;
+            let payload = self.read_vec(len);
+            if kind != 0 {
+                chunks.push(Chunk { kind, payload });
+            }
+            if flags & NBD_REPLY_FLAG_DONE != 0 {
+                return chunks;
+            }
+        }
+    }
+
+    /// Reads `len` bytes at `offset`, returning the data and the ranges sent as holes
+    fn read(&mut self, offset: u64, len: u32) -> (Vec<u8>, Vec<std::ops::Range<u64>>) {
+        let mut data = vec![0xEEu8; len as usize];
+        let mut holes = Vec::new();
+        for chunk in self.request(0, 0, offset, len, &[]) {
+            let at = u64::from_be_bytes(chunk.payload[..8].try_into().unwrap());
+            let start = (at - offset) as usize;
+            match chunk.kind {
+                NBD_REPLY_TYPE_OFFSET_DATA => {
+                    let bytes = &chunk.payload[8..];
+                    data[start..start + bytes.len()].copy_from_slice(bytes);
+                }
+                NBD_REPLY_TYPE_OFFSET_HOLE => {
+                    let hole = u32::from_be_bytes(chunk.payload[8..12].try_into().unwrap());
+                    data[start..start + hole as usize].fill(0);
+                    holes.push(at..at + hole as u64);
+                }
+                kind => panic!("unexpected read chunk {kind:#x}"),
+            }
+        }
+        (data, holes)
+    }
+
+    /// Extents of `offset..offset + len` as (length, is hole)
+    fn block_status(&mut self, offset: u64, len: u32) -> Vec<(u32, bool)> {
+        let chunks = self.request(7, 0, offset, len, &[]);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].kind, NBD_REPLY_TYPE_BLOCK_STATUS);
+        assert_eq!(chunks[0].payload[..4], 1u32.to_be_bytes(), "context id");
+        chunks[0].payload[4..]
+            .chunks(8)
+            .map(|descriptor| {
+                let len = u32::from_be_bytes(descriptor[..4].try_into().unwrap());
+                let status = u32::from_be_bytes(descriptor[4..].try_into().unwrap());
+                (len, status & NBD_STATE_HOLE != 0)
+            })
+            .collect()
+    }
+
+    /// Sends a command without reply data, returning its error code
+    fn command(&mut self, command: u16, offset: u64, len: u32, data: &[u8]) -> u32 {
+        match self.request(command, 0, offset, len, data).as_slice() {
+            [] => 0,
+            [chunk] if chunk.kind == NBD_REPLY_TYPE_ERROR => {
+                u32::from_be_bytes(chunk.payload[..4].try_into().unwrap())
+            }
+            _ => panic!("unexpected reply"),
+        }
+    }
+
+    fn disconnect(mut self) {
+        let mut request = 0x25609513u32.to_be_bytes().to_vec();
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&2u16.to_be_bytes());
+        request.extend_from_slice(&[0u8; 20]);
+        self.send(&request);
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        self.stream.write_all(data).unwrap();
+    }
+
+    fn read_vec(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.read_vec(2).try_into().unwrap())
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.read_vec(4).try_into().unwrap())
+    }
+
+    fn read_u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.read_vec(8).try_into().unwrap())
+    }
+}
+
+/// Serves `disk` to `client`, which runs on its own thread, and returns the disk afterwards
+fn serve(disk: VdiDisk, client: impl FnOnce(UnixStream) + Send + 'static) -> VdiDisk {
+    let (server_end, client_end) = UnixStream::pair().unwrap();
+    let client = std::thread::spawn(move || client(client_end));
+
+    let options = NbdOptions {
+        read_only: false,
+        ..Default::default()
+    };
+    let mut server = NbdServer::new(disk, options);
+    server.serve_client(server_end).unwrap();
+    client.join().unwrap();
+    server.into_inner()
+}
+
+#[test]
+fn client_reads_writes_and_trims() {
+    let fixture = ImageBuilder::new(8 * BLOCK_SIZE as u64, BLOCK_SIZE)
+        .pattern(&[Block::Data, Block::Free, Block::Zero, Block::Data])
+        .build();
+    let disk = VdiDisk::open_writable(Box::new(fixture.image.clone())).unwrap();
+    let block = BLOCK_SIZE as u64;
+
+    let raw = fixture.raw.clone();
+    let disk = serve(disk, move |stream| {
+        let (mut client, size) = Client::connect(stream, true);
+        assert_eq!(size, raw.len() as u64);
+
+        let (data, holes) = client.read(0, raw.len() as u32);
+        assert!(data == raw);
+        assert_eq!(holes, [block..3 * block, 5 * block..7 * block]);
+
+        let extents = client.block_status(0, raw.len() as u32);
+        let block = block as u32;
+        let expected = [
+            (block, false),
+            (2 * block, true),
+            (2 * block, false),
+            (2 * block, true),
+            (block, false),
+        ];
+        assert_eq!(extents, expected);
+        let block = block as u64;
+
+        // Write into a free block and across the edge of a stored one
+        let mut expected = raw.clone();
+        let written = vec![0x5Au8; 3000];
+        let at = 2 * block - 1000;
+        expected[at as usize..][..written.len()].copy_from_slice(&written);
+        assert_eq!(client.command(1, at, written.len() as u32, &written), 0);
+        assert!(client.read(0, raw.len() as u32).0 == expected);
+        assert_eq!(
+            client.block_status(block, block as u32),
+            [(block as u32, false)]
+        );
+
+        // Releases the stored block 3 and zeroes the edges around it
+        let trimmed = 3 * block - 100..4 * block + 100;
+        let len = (trimmed.end - trimmed.start) as u32;
+        assert_eq!(client.command(4, trimmed.start, len, &[]), 0);
+        expected[trimmed.start as usize..trimmed.end as usize].fill(0);
+        assert!(client.read(0, raw.len() as u32).0 == expected);
+        assert_eq!(
+            client.block_status(3 * block, block as u32),
+            [(block as u32, true)]
+        );
+
+        // Out of bounds
+        assert_eq!(client.command(0, raw.len() as u64, 1, &[]), NBD_EINVAL);
+        client.disconnect();
+    });
+
+    let mut data = vec![0u8; 3000];
+    disk.read_exact_at(2 * block - 1000, &mut data).unwrap();
+    assert!(data.iter().all(|&b| b == 0x5A));
+}
+
+#[test]
+fn block_status_needs_a_meta_context() {
+    let fixture = ImageBuilder::new(4 * BLOCK_SIZE as u64, BLOCK_SIZE).build();
+    let disk = VdiDisk::open_writable(Box::new(fixture.image.clone())).unwrap();
+
+    serve(disk, |stream| {
+        let (mut client, _) = Client::connect(stream, false);
+        assert_eq!(client.command(7, 0, BLOCK_SIZE, &[]), NBD_EINVAL);
+        // The connection stays usable
+        assert_eq!(client.read(0, BLOCK_SIZE).0.len(), BLOCK_SIZE as usize);
+        client.disconnect();
+    });
+}
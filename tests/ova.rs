@@ -0,0 +1,109 @@
+#![cfg(feature = "ova")]
+
+mod support;
+
+use std::io::Write;
+
+use positioned_io2::ReadAt;
+use support::{Block, ImageBuilder, Layout};
+use vdi::ova::{Ova, OvaOptions, VDI_FORMAT, write_ova};
+
+#[test]
+fn written_appliance_reads_back() {
+    let fixtures = [
+        ImageBuilder::new(10 * 4096 + 300, 4096)
+            .pattern(&[Block::Data, Block::Free, Block::Zero])
+            .layout(Layout::Reversed)
+            .build(),
+        ImageBuilder::new(6 * 65536, 65536).seed(0x20).build(),
+    ];
+    let files: Vec<_> = fixtures
+        .iter()
+        .map(|fixture| {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(&fixture.image).unwrap();
+            file
+        })
+        .collect();
+    let paths: Vec<_> = files.iter().map(|f| f.path().to_path_buf()).collect();
+
+    let options = OvaOptions {
+        name: "test".to_string(),
+        ..Default::default()
+    };
+    let mut archive = Vec::new();
+    write_ova(&mut archive, &paths, &options).unwrap();
+
+    let ova = Ova::new(archive.clone()).unwrap();
+    assert!(ova.verify_manifest().unwrap());
+    let descriptor = ova.descriptor();
+    assert_eq!(descriptor.disks.len(), fixtures.len());
+    for (disk, fixture) in descriptor.disks.iter().zip(&fixtures) {
+        assert_eq!(disk.capacity, fixture.raw.len() as u64);
+        assert_eq!(disk.format, VDI_FORMAT);
+
+        let opened = ova.open_disk(disk).unwrap();
+        let mut data = vec![0u8; fixture.raw.len()];
+        opened.read_exact_at(0, &mut data).unwrap();
+        assert!(data == fixture.raw);
+    }
+
+    // Corrupt the data of the second disk
+    let entry = ova
+        .entries()
+        .iter()
+        .find(|e| e.name == "test-disk002.vdi")
+        .unwrap();
+    archive[(entry.offset + entry.size / 2) as usize] ^= 0xFF;
+    let ova = Ova::new(archive).unwrap();
+    assert!(ova.verify_manifest().is_err());
+}
+
+/// Tar archive of a single member of `size` bytes, reading as zeros past its header without
+/// being held in memory
+struct SparseArchive {
+    header: tar::Header,
+    len: u64,
+}
+
+impl SparseArchive {
+    fn new(name: &str, size: u64) -> Self {
+        let mut header = tar::Header::new_ustar();
+        header.set_path(name).unwrap();
+        header.set_size(size);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        // The data padded to whole records, then two zero records ending the archive
+        let len = 512 + size.div_ceil(512) * 512 + 1024;
+        Self { header, len }
+    }
+}
+
+impl ReadAt for SparseArchive {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.len.saturating_sub(pos)) as usize;
+        let buf = &mut buf[..len];
+        buf.fill(0);
+        let header = self.header.as_bytes();
+        if pos < header.len() as u64 {
+            let header = &header[pos as usize..];
+            let n = std::cmp::min(header.len(), len);
+            buf[..n].copy_from_slice(&header[..n]);
+        }
+        Ok(len)
+    }
+}
+
+#[test]
+fn oversized_descriptors_are_refused() {
+    let Err(err) = Ova::new(SparseArchive::new("big.ovf", 1 << 40)) else {
+        panic!("a 1 TiB descriptor was read");
+    };
+    assert!(err.to_string().contains("too large"), "{err}");
+
+    // Small enough, but no XML
+    let Err(err) = Ova::new(SparseArchive::new("small.ovf", 1000)) else {
+        panic!("an empty descriptor was parsed");
+    };
+    assert!(!err.to_string().contains("too large"), "{err}");
+}
@@ -0,0 +1,102 @@
+mod support;
+
+use std::io::Read;
+
+use positioned_io2::{ReadAt, WriteAt};
+use support::{Block, ImageBuilder, Layout};
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+use vdi::overlay::Overlay;
+
+const BLOCK_SIZE: u64 = 4096;
+const DISK_SIZE: u64 = 12 * BLOCK_SIZE + 1000;
+
+fn read_all(disk: &impl ReadAt) -> Vec<u8> {
+    let mut data = vec![0u8; DISK_SIZE as usize];
+    disk.read_exact_at(0, &mut data).unwrap();
+    data
+}
+
+fn base_fixture() -> support::Fixture {
+    ImageBuilder::new(DISK_SIZE, BLOCK_SIZE as u32)
+        .pattern(&[Block::Data, Block::Free, Block::Data, Block::Zero])
+        .layout(Layout::Shuffled(3))
+        .build()
+}
+
+#[test]
+fn partial_writes_copy_the_base_block() {
+    let fixture = base_fixture();
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all_at(0, &fixture.image).unwrap();
+    let open = || VdiDisk::open(Box::new(file.try_clone().unwrap())).unwrap();
+
+    for mut overlay in [
+        Overlay::new(open()),
+        Overlay::with_temp_file(open()).unwrap(),
+    ] {
+        let mut expected = fixture.raw.clone();
+        // Into a stored block, a free one and across the edge of the two
+        for at in [
+            2 * BLOCK_SIZE + 1000,
+            5 * BLOCK_SIZE + 7,
+            3 * BLOCK_SIZE - 50,
+        ] {
+            overlay.write_all_at(at, &[0x5A; 100]).unwrap();
+            expected[at as usize..][..100].fill(0x5A);
+        }
+        assert_eq!(overlay.modified_blocks(), 3);
+        assert!(read_all(&overlay) == expected);
+        assert!(read_all(&overlay.discard()) == fixture.raw);
+    }
+
+    let mut image = Vec::new();
+    file.read_to_end(&mut image).unwrap();
+    assert!(image == fixture.image, "the base file is untouched");
+}
+
+#[test]
+fn writes_stop_at_the_end_of_the_disk() {
+    let fixture = base_fixture();
+    let mut overlay = Overlay::new(fixture.open());
+
+    assert_eq!(overlay.write_at(DISK_SIZE - 10, &[0x5A; 100]).unwrap(), 10);
+    assert_eq!(overlay.write_at(DISK_SIZE, &[0x5A; 100]).unwrap(), 0);
+    let mut tail = [0u8; 100];
+    assert_eq!(overlay.read_at(DISK_SIZE - 10, &mut tail).unwrap(), 10);
+    assert_eq!(tail[..10], [0x5A; 10]);
+
+    let exported = overlay.export(Box::new(Vec::new())).unwrap();
+    assert!(exported.block_offsets[12].is_some());
+    let mut tail = [0u8; 100];
+    assert_eq!(exported.read_at(DISK_SIZE - 10, &mut tail).unwrap(), 10);
+    assert_eq!(tail[..10], [0x5A; 10]);
+}
+
+#[test]
+fn export_reads_like_the_overlay() {
+    let fixture = base_fixture();
+    let mut overlay = Overlay::new(fixture.open());
+    overlay
+        .write_all_at(BLOCK_SIZE + 10, &[0x11; 5000])
+        .unwrap();
+    overlay.write_all_at(7 * BLOCK_SIZE, &[0x22; 4096]).unwrap();
+    // Zeroing whole blocks, stored and free in the base
+    overlay.write_all_at(4 * BLOCK_SIZE, &[0; 4096]).unwrap();
+    overlay.write_all_at(9 * BLOCK_SIZE, &[0; 4096]).unwrap();
+
+    let mut exported = overlay.export(Box::new(Vec::new())).unwrap();
+    assert_eq!(
+        exported.header.image_type.get(),
+        VdiHeader::TYPE_DIFFERENCING
+    );
+    assert_eq!(exported.header.uuid_link, fixture.header.uuid_image);
+    assert_eq!(exported.header.blocks_allocated.get(), 3);
+    let stats = exported.stats(0).unwrap();
+    assert_eq!(stats.allocated_blocks, 3);
+    assert_eq!(stats.zero_blocks, 2);
+
+    exported.set_parent(fixture.open()).unwrap();
+    assert!(read_all(&exported) == read_all(&overlay));
+    assert!(read_all(&overlay.discard()) == fixture.raw);
+}
@@ -0,0 +1,185 @@
+use uuid::Uuid;
+use vdi::partitions::{PartitionKind, PartitionTableKind, read_partition_table};
+
+const SECTOR: u64 = 512;
+const DISK_SECTORS: u64 = 64;
+const DISK_SIZE: u64 = DISK_SECTORS * SECTOR;
+
+const LINUX_GUID: Uuid = uuid::uuid!("0fc63daf-8483-4772-8e79-3d69d8477de4");
+
+fn sector(disk: &mut [u8], lba: u64) -> &mut [u8] {
+    &mut disk[(lba * SECTOR) as usize..][..SECTOR as usize]
+}
+
+/// Writes an MBR partition entry into `table` at `index`
+fn mbr_entry(table: &mut [u8], index: usize, type_id: u8, lba_start: u32, sectors: u32) {
+    let raw = &mut table[446 + index * 16..][..16];
+    raw[4] = type_id;
+    raw[8..12].copy_from_slice(&lba_start.to_le_bytes());
+    raw[12..16].copy_from_slice(&sectors.to_le_bytes());
+    table[510..512].copy_from_slice(&[0x55, 0xAA]);
+}
+
+/// Disk with a primary partition and an extended one holding a chain of `logical` partitions
+/// of 2 sectors each
+fn mbr_disk(logical: u32) -> Vec<u8> {
+    let mut disk = vec![0u8; DISK_SIZE as usize];
+    mbr_entry(sector(&mut disk, 0), 0, 0x83, 1, 8);
+    mbr_entry(sector(&mut disk, 0), 1, 0x05, 16, 48);
+    for i in 0..logical {
+        // EBRs are 4 sectors apart, each followed by its partition
+        let ebr = sector(&mut disk, 16 + i as u64 * 4);
+        mbr_entry(ebr, 0, 0x83, 1, 2);
+        if i + 1 < logical {
+            mbr_entry(ebr, 1, 0x05, (i + 1) * 4, 4);
+        }
+    }
+    disk
+}
+
+/// GPT header at `lba` describing `num_entries` entries of `entry_size` bytes at `entries_lba`
+fn gpt_header(
+    disk: &mut [u8],
+    lba: u64,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: u32,
+    entries_crc32: u32,
+) {
+    let raw = sector(disk, lba);
+    raw[0..8].copy_from_slice(b"EFI PART");
+    raw[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    raw[12..16].copy_from_slice(&92u32.to_le_bytes());
+    raw[24..32].copy_from_slice(&lba.to_le_bytes());
+    raw[56..72].copy_from_slice(&Uuid::from_u128(0x1234).to_bytes_le());
+    raw[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    raw[80..84].copy_from_slice(&num_entries.to_le_bytes());
+    raw[84..88].copy_from_slice(&entry_size.to_le_bytes());
+    raw[88..92].copy_from_slice(&entries_crc32.to_le_bytes());
+    let crc = crc32fast::hash(&raw[..92]);
+    raw[16..20].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Disk with a protective MBR and matching primary and backup GPTs of 4 entries, the second of
+/// which is used
+fn gpt_disk() -> Vec<u8> {
+    let mut disk = vec![0u8; DISK_SIZE as usize];
+    mbr_entry(sector(&mut disk, 0), 0, 0xEE, 1, DISK_SECTORS as u32 - 1);
+
+    let mut entries = [0u8; 512];
+    let entry = &mut entries[128..256];
+    entry[0..16].copy_from_slice(&LINUX_GUID.to_bytes_le());
+    entry[16..32].copy_from_slice(&Uuid::from_u128(0x5678).to_bytes_le());
+    entry[32..40].copy_from_slice(&34u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&59u64.to_le_bytes());
+    for (i, c) in "root".encode_utf16().enumerate() {
+        entry[56 + i * 2..][..2].copy_from_slice(&c.to_le_bytes());
+    }
+    let crc = crc32fast::hash(&entries);
+
+    sector(&mut disk, 2).copy_from_slice(&entries);
+    gpt_header(&mut disk, 1, 2, 4, 128, crc);
+    sector(&mut disk, DISK_SECTORS - 2).copy_from_slice(&entries);
+    gpt_header(&mut disk, DISK_SECTORS - 1, DISK_SECTORS - 2, 4, 128, crc);
+    disk
+}
+
+fn assert_gpt_partition(disk: &[u8], used_backup_gpt: bool) {
+    let table = read_partition_table(&disk, DISK_SIZE, SECTOR).unwrap();
+    assert_eq!(table.kind, PartitionTableKind::Gpt);
+    assert_eq!(table.used_backup_gpt, used_backup_gpt);
+    assert_eq!(table.disk_guid, Some(Uuid::from_u128(0x1234)));
+
+    let [partition] = table.partitions.as_slice() else {
+        panic!("expected one partition, got {:?}", table.partitions);
+    };
+    assert_eq!(partition.number, 2);
+    assert_eq!(partition.range(), 34 * SECTOR..60 * SECTOR);
+    let PartitionKind::Gpt {
+        type_guid, name, ..
+    } = &partition.kind
+    else {
+        panic!("expected a GPT partition");
+    };
+    assert_eq!(*type_guid, LINUX_GUID);
+    assert_eq!(name, "root");
+}
+
+#[test]
+fn mbr_follows_the_ebr_chain() {
+    let disk = mbr_disk(3);
+    let table = read_partition_table(&disk, DISK_SIZE, SECTOR).unwrap();
+    assert_eq!(table.kind, PartitionTableKind::Mbr);
+
+    let layout: Vec<_> = table
+        .partitions
+        .iter()
+        .map(|p| {
+            let PartitionKind::Mbr { logical, .. } = p.kind else {
+                panic!("expected an MBR partition");
+            };
+            (p.number, p.range(), logical)
+        })
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            (1, SECTOR..9 * SECTOR, false),
+            (2, 16 * SECTOR..64 * SECTOR, false),
+            (5, 17 * SECTOR..19 * SECTOR, true),
+            (6, 21 * SECTOR..23 * SECTOR, true),
+            (7, 25 * SECTOR..27 * SECTOR, true),
+        ]
+    );
+}
+
+#[test]
+fn mbr_rejects_looping_ebr_chains() {
+    let mut disk = mbr_disk(2);
+    // The second EBR links back to the first
+    mbr_entry(sector(&mut disk, 20), 1, 0x05, 0, 4);
+    assert!(read_partition_table(&disk, DISK_SIZE, SECTOR).is_err());
+
+    let mut disk = mbr_disk(2);
+    sector(&mut disk, 20)[510] = 0;
+    assert!(read_partition_table(&disk, DISK_SIZE, SECTOR).is_err());
+}
+
+#[test]
+fn gpt_reads_the_primary_table() {
+    assert_gpt_partition(&gpt_disk(), false);
+}
+
+#[test]
+fn gpt_falls_back_to_the_backup_on_a_header_crc_mismatch() {
+    let mut disk = gpt_disk();
+    sector(&mut disk, 1)[60] ^= 1;
+    assert_gpt_partition(&disk, true);
+}
+
+#[test]
+fn gpt_falls_back_to_the_backup_on_an_entries_crc_mismatch() {
+    let mut disk = gpt_disk();
+    sector(&mut disk, 2)[200] ^= 1;
+    assert_gpt_partition(&disk, true);
+}
+
+#[test]
+fn gpt_falls_back_to_the_backup_on_oversized_entries() {
+    // Would ask for gigabytes if trusted
+    let mut disk = gpt_disk();
+    gpt_header(&mut disk, 1, 2, 16384, 0x8_0000, 0);
+    assert_gpt_partition(&disk, true);
+
+    let mut disk = gpt_disk();
+    gpt_header(&mut disk, 1, 2, 16384, 512, 0);
+    assert_gpt_partition(&disk, true);
+}
+
+#[test]
+fn gpt_fails_when_both_tables_are_damaged() {
+    let mut disk = gpt_disk();
+    sector(&mut disk, 1)[60] ^= 1;
+    sector(&mut disk, DISK_SECTORS - 2)[200] ^= 1;
+    assert!(read_partition_table(&disk, DISK_SIZE, SECTOR).is_err());
+}
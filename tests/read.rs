@@ -0,0 +1,266 @@
+mod support;
+
+use std::io::{Read, Seek, SeekFrom};
+
+use positioned_io2::ReadAt;
+use support::{Block, ImageBuilder, Layout, Rng, variants};
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+
+/// Reads `len` bytes at `pos` the way a raw image of `raw` would, short at its end
+fn raw_read(raw: &[u8], pos: u64, len: usize) -> &[u8] {
+    let start = std::cmp::min(pos, raw.len() as u64) as usize;
+    &raw[start..std::cmp::min(start + len, raw.len())]
+}
+
+/// Reads from `disk` until `buf` is full or the disk ends, returning the bytes read
+fn read_fully(disk: &impl ReadAt, pos: u64, buf: &mut [u8]) -> usize {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match disk
+            .read_at(pos + filled as u64, &mut buf[filled..])
+            .unwrap()
+        {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    filled
+}
+
+#[test]
+fn open_parses_header_and_block_map() {
+    let fixture = ImageBuilder::new(10 * 4096, 4096)
+        .pattern(&[Block::Data, Block::Free, Block::Zero])
+        .layout(Layout::Reversed)
+        .build();
+    let disk = fixture.open();
+
+    assert_eq!(disk.block_size, 4096);
+    assert_eq!(disk.header.disk_size.get(), 10 * 4096);
+    assert_eq!(disk.header.blocks_allocated.get(), 4);
+    assert_eq!(disk.block_offsets.len(), 10);
+
+    let data_offset = fixture.header.data_offset.get() as u64;
+    let allocated: Vec<(usize, u64)> = disk
+        .block_offsets
+        .iter()
+        .enumerate()
+        .filter_map(|(index, offset)| offset.map(|offset| (index, offset)))
+        .collect();
+    // Stored in reverse: the last data block comes first
+    assert_eq!(
+        allocated,
+        [
+            (0, data_offset + 3 * 4096),
+            (3, data_offset + 2 * 4096),
+            (6, data_offset + 4096),
+            (9, data_offset),
+        ]
+    );
+}
+
+#[test]
+fn open_rejects_invalid_headers() {
+    let fixture = ImageBuilder::new(8 * 4096, 4096).build();
+    let corrupted = [
+        fixture.with_header(|h| h.signature.set(0x1234_5678)),
+        fixture.with_header(|h| h.version.set(0x0002_0000)),
+        fixture.with_header(|h| h.image_type.set(2)), // fixed
+        fixture.with_header(|h| h.image_type.set(7)),
+    ];
+    for image in corrupted {
+        assert!(VdiDisk::open(Box::new(image)).is_err());
+    }
+
+    let truncated = fixture.image[..100].to_vec();
+    assert!(VdiDisk::open(Box::new(truncated)).is_err());
+
+    let without_map = fixture.image[..fixture.header.block_offsets_offset.get() as usize].to_vec();
+    assert!(VdiDisk::open(Box::new(without_map)).is_err());
+}
+
+#[test]
+fn read_at_matches_raw() {
+    for (name, fixture) in variants() {
+        let disk = fixture.open();
+
+        let mut all = vec![0u8; fixture.raw.len() + 4096];
+        let n = read_fully(&disk, 0, &mut all);
+        assert_eq!(&all[..n], &fixture.raw[..], "{name}: whole disk");
+
+        let mut rng = Rng::new(fixture.raw.len() as u64);
+        for _ in 0..200 {
+            let pos = rng.below(fixture.raw.len() as u64 + 100);
+            let len = rng.below(3 * disk.block_size as u64) as usize;
+            let mut buf = vec![0u8; len];
+            let n = read_fully(&disk, pos, &mut buf);
+            assert_eq!(
+                &buf[..n],
+                raw_read(&fixture.raw, pos, len),
+                "{name}: {len} bytes at {pos}"
+            );
+        }
+    }
+}
+
+#[test]
+fn read_at_end_of_disk() {
+    let fixture = ImageBuilder::new(4 * 4096 + 100, 4096).build();
+    let disk = fixture.open();
+    let end = fixture.raw.len() as u64;
+
+    let mut buf = [0u8; 300];
+    assert_eq!(read_fully(&disk, end - 50, &mut buf), 50);
+    assert_eq!(&buf[..50], &fixture.raw[end as usize - 50..]);
+    assert_eq!(disk.read_at(end, &mut buf).unwrap(), 0);
+    assert_eq!(disk.read_at(end + 5000, &mut buf).unwrap(), 0);
+    assert_eq!(disk.read_at(0, &mut []).unwrap(), 0);
+}
+
+#[test]
+fn read_and_seek_match_raw() {
+    for (name, fixture) in variants() {
+        let mut disk = fixture.open();
+
+        // Chunk sizes not dividing the block size
+        let mut rng = Rng::new(3);
+        let mut pos = 0;
+        loop {
+            let mut buf = vec![0u8; rng.below(disk.block_size as u64 * 2) as usize + 1];
+            let n = disk.read(&mut buf).unwrap();
+            assert_eq!(&buf[..n], raw_read(&fixture.raw, pos, n), "{name} at {pos}");
+            pos += n as u64;
+            if n == 0 {
+                break;
+            }
+        }
+        assert_eq!(pos, fixture.raw.len() as u64, "{name}");
+        assert_eq!(disk.stream_position().unwrap(), pos, "{name}");
+
+        let mut raw = std::io::Cursor::new(&fixture.raw[..]);
+        for seek in [
+            SeekFrom::Start(12345),
+            SeekFrom::Current(-300),
+            SeekFrom::Current(disk.block_size as i64),
+            SeekFrom::End(-1000),
+            SeekFrom::End(0),
+            SeekFrom::Start(0),
+        ] {
+            let pos = disk.seek(seek).unwrap();
+            assert_eq!(pos, raw.seek(seek).unwrap(), "{name}: {seek:?}");
+            let mut buf = vec![0u8; 5000];
+            let n = disk.read(&mut buf).unwrap();
+            assert_eq!(n == 0, pos == fixture.raw.len() as u64, "{name}: {seek:?}");
+            assert_eq!(
+                &buf[..n],
+                raw_read(&fixture.raw, pos, n),
+                "{name}: {seek:?}"
+            );
+            disk.seek(SeekFrom::Start(pos)).unwrap();
+        }
+    }
+}
+
+#[test]
+fn seek_out_of_bounds_fails() {
+    let fixture = ImageBuilder::new(8 * 4096, 4096).build();
+    let mut disk = fixture.open();
+    disk.seek(SeekFrom::Start(100)).unwrap();
+
+    assert!(disk.seek(SeekFrom::Start(8 * 4096 + 1)).is_err());
+    assert!(disk.seek(SeekFrom::End(1)).is_err());
+    assert!(disk.seek(SeekFrom::Current(-101)).is_err());
+    assert!(disk.seek(SeekFrom::End(-(8 * 4096) - 1)).is_err());
+    // A failed seek leaves the position unchanged
+    assert_eq!(disk.stream_position().unwrap(), 100);
+}
+
+#[test]
+fn read_to_end_matches_raw() {
+    for (name, fixture) in variants() {
+        let mut disk = fixture.open();
+        let mut all = Vec::new();
+        disk.read_to_end(&mut all).unwrap();
+        assert!(all == fixture.raw, "{name}");
+    }
+}
+
+#[test]
+fn differencing_image_reads_through_parents() {
+    use Block::*;
+
+    let base = ImageBuilder::new(40 * 4096, 4096)
+        .pattern(&[Data, Data, Free])
+        .layout(Layout::Shuffled(1))
+        .seed(0x10)
+        .build();
+    let middle = ImageBuilder::new(40 * 4096, 4096)
+        .pattern(&[Free, Data, Zero, Free])
+        .parent(&base)
+        .seed(0x20)
+        .build();
+    // Larger than its parents, the blocks past their end read as zeros
+    let top = ImageBuilder::new(50 * 4096 + 7, 4096)
+        .pattern(&[Free, Free, Zeros, Data, Free])
+        .layout(Layout::Reversed)
+        .parent(&middle)
+        .seed(0x30)
+        .build();
+    assert!(top.raw[40 * 4096..].iter().any(|&b| b != 0));
+
+    let disk = top.open_with_parent(middle.open_with_parent(base.open()));
+    let mut all = vec![0u8; top.raw.len()];
+    disk.read_exact_at(0, &mut all).unwrap();
+    assert!(all == top.raw);
+
+    let mut rng = Rng::new(9);
+    for _ in 0..500 {
+        let pos = rng.below(top.raw.len() as u64);
+        let len = rng.below(10_000) as usize;
+        let mut buf = vec![0u8; len];
+        let n = read_fully(&disk, pos, &mut buf);
+        assert_eq!(
+            &buf[..n],
+            raw_read(&top.raw, pos, len),
+            "{len} bytes at {pos}"
+        );
+    }
+}
+
+#[test]
+fn differencing_image_without_parent_fails_parent_reads() {
+    let base = ImageBuilder::new(8 * 4096, 4096).seed(0x10).build();
+    let child = ImageBuilder::new(8 * 4096, 4096)
+        .pattern(&[Block::Free, Block::Data, Block::Zero])
+        .parent(&base)
+        .build();
+    let mut disk = child.open();
+
+    let mut block = vec![0u8; 4096];
+    assert!(disk.read_exact_at(0, &mut block).is_err());
+    disk.read_exact_at(4096, &mut block).unwrap();
+    assert_eq!(block, &child.raw[4096..2 * 4096]);
+    disk.read_exact_at(2 * 4096, &mut block).unwrap();
+    assert!(block.iter().all(|&b| b == 0));
+
+    disk.set_parent(base.open()).unwrap();
+    let mut all = vec![0u8; child.raw.len()];
+    disk.read_exact_at(0, &mut all).unwrap();
+    assert!(all == child.raw);
+}
+
+#[test]
+fn set_parent_checks_the_link() {
+    let base = ImageBuilder::new(8 * 4096, 4096).build();
+    let other = ImageBuilder::new(8 * 4096, 4096).build();
+    let child = ImageBuilder::new(8 * 4096, 4096).parent(&base).build();
+
+    assert!(child.open().set_parent(other.open()).is_err());
+    assert!(base.open().set_parent(other.open()).is_err());
+    assert!(child.open().set_parent(base.open()).is_ok());
+
+    let header = child.with_header(|h| h.uuid_link = VdiHeader::uuid_to_disk(uuid::Uuid::nil()));
+    let mut disk = VdiDisk::open(Box::new(header)).unwrap();
+    assert!(disk.set_parent(base.open()).is_err());
+}
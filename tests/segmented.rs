@@ -0,0 +1,113 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use positioned_io2::{ReadAt, WriteAt};
+use vdi::segmented::{Segment, SegmentedSlice};
+
+fn inner() -> Vec<u8> {
+    (0..1000u32).map(|i| (i * 7 % 251) as u8 + 1).collect()
+}
+
+/// Three segments out of order, and an empty one that is left out
+fn segmented(inner: Vec<u8>) -> SegmentedSlice<Vec<u8>> {
+    SegmentedSlice::new(
+        inner,
+        [
+            Segment {
+                offset: 600,
+                len: 100,
+            },
+            Segment { offset: 0, len: 0 },
+            Segment {
+                offset: 100,
+                len: 200,
+            },
+            Segment {
+                offset: 900,
+                len: 50,
+            },
+        ],
+    )
+}
+
+fn expected(inner: &[u8]) -> Vec<u8> {
+    [&inner[600..700], &inner[100..300], &inner[900..950]].concat()
+}
+
+#[test]
+fn reads_span_segment_boundaries() {
+    let inner = inner();
+    let expected = expected(&inner);
+    let slice = segmented(inner);
+    assert_eq!(slice.len(), 350);
+    assert_eq!(slice.segments().len(), 3);
+
+    for pos in [0, 50, 99, 100, 250, 299, 300, 340, 349] {
+        for len in [1, 10, 150, 400] {
+            let mut buf = vec![0u8; len];
+            let n = slice.read_at(pos, &mut buf).unwrap();
+            let end = std::cmp::min(pos as usize + len, expected.len());
+            assert_eq!(n, end - pos as usize, "at {pos}, {len} bytes");
+            assert!(buf[..n] == expected[pos as usize..end], "at {pos}");
+        }
+    }
+
+    let mut buf = [0u8; 10];
+    assert_eq!(slice.read_at(350, &mut buf).unwrap(), 0);
+    assert_eq!(slice.read_at(u64::MAX, &mut buf).unwrap(), 0);
+}
+
+#[test]
+fn reads_stop_where_the_inner_reader_ends() {
+    let mut inner = inner();
+    let expected = expected(&inner);
+    inner.truncate(920);
+    let slice = segmented(inner);
+
+    let mut buf = vec![0u8; 350];
+    assert_eq!(slice.read_at(0, &mut buf).unwrap(), 320);
+    assert!(buf[..320] == expected[..320]);
+}
+
+#[test]
+fn seek_and_read_follow_the_logical_position() {
+    let inner = inner();
+    let expected = expected(&inner);
+    let mut slice = segmented(inner);
+
+    let mut buf = [0u8; 20];
+    assert_eq!(slice.seek(SeekFrom::Start(90)).unwrap(), 90);
+    slice.read_exact(&mut buf).unwrap();
+    assert!(buf[..] == expected[90..110]);
+    assert_eq!(slice.stream_position().unwrap(), 110);
+    assert_eq!(slice.seek(SeekFrom::Current(-30)).unwrap(), 80);
+    slice.read_exact(&mut buf).unwrap();
+    assert!(buf[..] == expected[80..100]);
+
+    assert_eq!(slice.seek(SeekFrom::End(-10)).unwrap(), 340);
+    let mut rest = Vec::new();
+    slice.read_to_end(&mut rest).unwrap();
+    assert!(rest == expected[340..]);
+    assert_eq!(slice.read(&mut buf).unwrap(), 0);
+
+    assert!(slice.seek(SeekFrom::End(1)).is_err());
+    assert!(slice.seek(SeekFrom::Current(-351)).is_err());
+    assert_eq!(slice.stream_position().unwrap(), 350);
+}
+
+#[test]
+fn writes_land_in_their_segments() {
+    let inner = inner();
+    let mut expected_inner = inner.clone();
+    let mut slice = segmented(inner);
+
+    assert_eq!(slice.write_at(90, &[0xEE; 20]).unwrap(), 20);
+    expected_inner[690..700].fill(0xEE);
+    expected_inner[100..110].fill(0xEE);
+
+    slice.seek(SeekFrom::Start(340)).unwrap();
+    assert_eq!(slice.write(&[0xDD; 20]).unwrap(), 10);
+    expected_inner[940..950].fill(0xDD);
+    assert_eq!(slice.write(&[0xDD; 20]).unwrap(), 0);
+
+    assert!(slice.into_inner() == expected_inner);
+}
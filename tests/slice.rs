@@ -0,0 +1,149 @@
+mod support;
+
+use std::io::{Read, Seek, SeekFrom};
+
+use positioned_io2::{ReadAt, WriteAt};
+use support::{Block, ImageBuilder, Layout, Rng};
+use vdi::VdiDisk;
+
+fn fixture() -> support::Fixture {
+    ImageBuilder::new(32 * 4096 + 512, 4096)
+        .pattern(&[Block::Data, Block::Free, Block::Data, Block::Zero])
+        .layout(Layout::Shuffled(5))
+        .build()
+}
+
+#[test]
+fn slice_reads_match_raw_range() {
+    let fixture = fixture();
+    let disk = fixture.open();
+
+    let mut rng = Rng::new(1);
+    for _ in 0..100 {
+        let start = rng.below(fixture.raw.len() as u64);
+        let end = start + rng.below(fixture.raw.len() as u64 - start + 1);
+        let expected = &fixture.raw[start as usize..end as usize];
+
+        let mut slice = disk.slice(start..end);
+        assert_eq!(slice.len(), expected.len());
+        assert_eq!(slice.range(), start..end);
+
+        let mut all = Vec::new();
+        slice.read_to_end(&mut all).unwrap();
+        assert!(all == expected, "{start}..{end}");
+
+        let pos = rng.below(expected.len() as u64 + 10);
+        let mut buf = vec![0u8; 3000];
+        let n = slice.read_at(pos, &mut buf).unwrap();
+        let from = std::cmp::min(pos as usize, expected.len());
+        let available = &expected[from..];
+        assert!(n <= available.len().min(buf.len()));
+        assert_eq!(&buf[..n], &available[..n]);
+    }
+}
+
+#[test]
+fn slice_is_bounded() {
+    let fixture = fixture();
+    let disk = fixture.open();
+    let mut slice = disk.slice(5000..9000);
+
+    let mut buf = vec![0u8; 10_000];
+    assert_eq!(slice.read_at(3900, &mut buf).unwrap(), 100);
+    assert_eq!(&buf[..100], &fixture.raw[8900..9000]);
+    assert_eq!(slice.read_at(4000, &mut buf).unwrap(), 0);
+
+    assert_eq!(slice.seek(SeekFrom::End(-10)).unwrap(), 3990);
+    assert_eq!(slice.read(&mut buf).unwrap(), 10);
+    assert_eq!(&buf[..10], &fixture.raw[8990..9000]);
+    assert_eq!(slice.read(&mut buf).unwrap(), 0);
+    assert!(slice.seek(SeekFrom::End(1)).is_err());
+    assert!(slice.seek(SeekFrom::Current(-4001)).is_err());
+}
+
+#[test]
+fn nested_slices_are_relative_and_clamped() {
+    let fixture = fixture();
+    let disk = fixture.open();
+    let outer = disk.slice(10_000..60_000);
+
+    let mut inner = outer.slice(4096..20_000);
+    assert_eq!(inner.range(), 14_096..30_000);
+    let mut all = Vec::new();
+    inner.read_to_end(&mut all).unwrap();
+    assert!(all == fixture.raw[14_096..30_000]);
+
+    // Past the end of the outer slice
+    let clamped = outer.slice(40_000..90_000);
+    assert_eq!(clamped.range(), 50_000..60_000);
+    let empty = outer.slice(70_000..80_000);
+    assert_eq!(empty.len(), 0);
+}
+
+#[test]
+fn owned_slice_matches_raw_range() {
+    let fixture = fixture();
+    let range = 4000..100_000;
+    let mut owned = fixture.open().slice_owned(range.clone()).unwrap();
+    let expected = &fixture.raw[range.start as usize..range.end as usize];
+
+    let mut all = Vec::new();
+    owned.read_to_end(&mut all).unwrap();
+    assert!(all == expected);
+
+    owned.seek(SeekFrom::Start(50_000)).unwrap();
+    let mut buf = vec![0u8; 7000];
+    owned.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, &expected[50_000..57_000]);
+    assert_eq!(owned.stream_position().unwrap(), 57_000);
+
+    let mut buf = vec![0u8; 1000];
+    let n = owned
+        .read_at(expected.len() as u64 - 300, &mut buf)
+        .unwrap();
+    assert_eq!(&buf[..n], &expected[expected.len() - 300..][..n]);
+    assert_eq!(owned.read_at(expected.len() as u64, &mut buf).unwrap(), 0);
+
+    let sub = owned.slice(1000..2000);
+    assert_eq!(sub.range(), 5000..6000);
+
+    let disk = owned.into_inner();
+    assert_eq!(disk.header.disk_size.get(), fixture.raw.len() as u64);
+}
+
+#[test]
+fn writes_stop_at_the_end_of_the_disk() {
+    let fixture = fixture();
+    let disk_size = fixture.raw.len() as u64;
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all_at(0, &fixture.image).unwrap();
+    let mut disk = VdiDisk::open_writable(Box::new(file.try_clone().unwrap())).unwrap();
+    // The last block is stored, and extends past the end of the disk
+    let tail_offset = disk.block_offsets[32].unwrap() + 512;
+
+    assert_eq!(disk.write_at(disk_size - 10, &[0x5A; 100]).unwrap(), 10);
+    assert_eq!(disk.write_at(disk_size, &[0x5A; 100]).unwrap(), 0);
+    assert!(disk.write_all_at(disk_size - 5, &[0x5A; 10]).is_err());
+
+    let mut slice = disk.slice_mut(disk_size - 100..disk_size + 5000);
+    assert_eq!(slice.write_at(90, &[0x5B; 100]).unwrap(), 10);
+    assert_eq!(slice.write_at(100, &[0x5B; 100]).unwrap(), 0);
+
+    let mut owned = disk.slice_owned(disk_size - 100..disk_size + 5000).unwrap();
+    assert_eq!(owned.write_at(95, &[0x5C; 100]).unwrap(), 5);
+    owned.seek(SeekFrom::Start(100)).unwrap();
+    assert_eq!(std::io::Write::write(&mut owned, &[0x5C; 100]).unwrap(), 0);
+
+    let disk = owned.into_inner();
+    let mut tail = [0u8; 10];
+    disk.read_exact_at(disk_size - 10, &mut tail).unwrap();
+    assert_eq!(
+        tail,
+        [0x5B, 0x5B, 0x5B, 0x5B, 0x5B, 0x5C, 0x5C, 0x5C, 0x5C, 0x5C]
+    );
+
+    // The rest of the last block is untouched
+    let mut stored = vec![0u8; 4096 - 512];
+    file.read_exact_at(tail_offset, &mut stored).unwrap();
+    assert!(stored[..] == fixture.image[tail_offset as usize..][..stored.len()]);
+}
@@ -0,0 +1,64 @@
+mod support;
+
+use support::{Block, ImageBuilder, Layout};
+
+const BLOCK_SIZE: u64 = 4096;
+/// Data in blocks 0, 4, 5 and 9, zeros stored in 2 and 7, free 1 and 6, marked zero 3 and 8
+const PATTERN: [Block; 5] = [
+    Block::Data,
+    Block::Free,
+    Block::Zeros,
+    Block::Zero,
+    Block::Data,
+];
+
+#[test]
+fn stats_count_the_blocks_by_state() {
+    let fixture = ImageBuilder::new(10 * BLOCK_SIZE, BLOCK_SIZE as u32)
+        .pattern(&PATTERN)
+        .build();
+    let disk = fixture.open();
+
+    let stats = disk.stats(fixture.image.len() as u64 + 100).unwrap();
+    assert_eq!(stats.allocated_blocks, 6);
+    assert_eq!(stats.free_blocks, 2);
+    assert_eq!(stats.zero_blocks, 2);
+    assert_eq!(stats.zeroed_allocated_blocks, 2);
+    assert_eq!(stats.zeroed_allocated_ratio, 2.0 / 6.0);
+    assert_eq!(stats.out_of_order_blocks, 0);
+    assert_eq!(stats.out_of_order_ratio, 0.0);
+    // Only blocks 4 and 5 follow each other
+    assert_eq!(stats.fragments, 5);
+    assert_eq!(stats.unused_slots, 0);
+    assert_eq!(stats.tail_bytes, 100);
+    assert_eq!(stats.reclaimable_bytes, 2 * BLOCK_SIZE + 100);
+
+    let info = disk.info();
+    assert_eq!(info.blocks_allocated, 6);
+    assert_eq!(info.allocated_size, 6 * BLOCK_SIZE);
+    assert_eq!(info.allocated_ratio, 0.6);
+}
+
+#[test]
+fn stats_count_unused_slots_and_blocks_out_of_order() {
+    let fixture = ImageBuilder::new(10 * BLOCK_SIZE, BLOCK_SIZE as u32)
+        .pattern(&PATTERN)
+        .layout(Layout::Reversed)
+        .stale_slots(2)
+        .build();
+    let disk = fixture.open();
+
+    let stats = disk.stats(fixture.image.len() as u64).unwrap();
+    assert_eq!(stats.allocated_blocks, 6);
+    assert_eq!(stats.free_blocks, 2);
+    assert_eq!(stats.zero_blocks, 2);
+    assert_eq!(stats.zeroed_allocated_blocks, 2);
+    // The stale slots push every block out of its place
+    assert_eq!(stats.out_of_order_blocks, 6);
+    assert_eq!(stats.out_of_order_ratio, 1.0);
+    assert_eq!(stats.fragments, 6);
+    assert_eq!(stats.unused_slots, 2);
+    assert_eq!(stats.tail_bytes, 0);
+    assert_eq!(stats.reclaimable_bytes, 4 * BLOCK_SIZE);
+    assert_eq!(disk.info().blocks_allocated, 8);
+}
@@ -0,0 +1,26 @@
+mod support;
+
+use support::{Block, ImageBuilder, Layout};
+use vdi::stream::{StreamOptions, VdiStream};
+
+#[test]
+fn stream_writes_raw_contents() {
+    let fixture = ImageBuilder::new(12 * 4096 + 100, 4096)
+        .pattern(&[Block::Data, Block::Free, Block::Zero, Block::Data])
+        .layout(Layout::Shuffled(9))
+        .build();
+
+    let options = StreamOptions { memory_limit: 4096 };
+    let stream = VdiStream::new(fixture.image.as_slice(), options).unwrap();
+    let mut raw = Vec::new();
+    let stats = stream.write_raw(&mut raw).unwrap();
+    assert!(raw == fixture.raw);
+    assert_eq!(stats.blocks_allocated, 7);
+}
+
+#[test]
+fn stream_rejects_differencing_images() {
+    let base = ImageBuilder::new(8 * 4096, 4096).build();
+    let child = ImageBuilder::new(8 * 4096, 4096).parent(&base).build();
+    assert!(VdiStream::new(child.image.as_slice(), StreamOptions::default()).is_err());
+}
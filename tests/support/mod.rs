@@ -0,0 +1,273 @@
+//! Builds VDI images in memory for the integration tests, along with the raw disk contents they
+//! must read as.
+
+#![allow(dead_code)]
+
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+
+/// State of a block in a built image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    /// Never written, reads as zeros or from the parent
+    Free,
+    /// Marked as zero in the block map
+    Zero,
+    /// Stored, filled with a pattern unique to the image and position
+    Data,
+    /// Stored, but containing only zeros
+    Zeros,
+}
+
+/// Order the stored blocks are written in the data area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Sequential,
+    Reversed,
+    Shuffled(u64),
+}
+
+/// Image built in memory, with the contents it must read as
+pub struct Fixture {
+    pub header: VdiHeader,
+    pub image: Vec<u8>,
+    pub raw: Vec<u8>,
+}
+
+impl Fixture {
+    pub fn open(&self) -> VdiDisk {
+        VdiDisk::open(Box::new(self.image.clone())).expect("fixture opens")
+    }
+
+    /// Opens the image with `parent` attached, see [`ImageBuilder::parent`]
+    pub fn open_with_parent(&self, parent: VdiDisk) -> VdiDisk {
+        let mut disk = self.open();
+        disk.set_parent(parent).expect("parent attaches");
+        disk
+    }
+
+    /// Image bytes with the header modified by `edit`, eg. to corrupt it
+    pub fn with_header(&self, edit: impl FnOnce(&mut VdiHeader)) -> Vec<u8> {
+        let mut header = self.header;
+        edit(&mut header);
+        let mut image = self.image.clone();
+        image[..std::mem::size_of::<VdiHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+        image
+    }
+}
+
+pub struct ImageBuilder {
+    disk_size: u64,
+    block_size: u32,
+    pattern: Vec<Block>,
+    layout: Layout,
+    offsets: Option<(u32, u32)>,
+    stale_slots: u32,
+    seed: u8,
+    parent: Option<(VdiHeader, Vec<u8>)>,
+}
+
+impl ImageBuilder {
+    /// Dynamic image with all its blocks stored in logical order, laid out like VirtualBox does
+    pub fn new(disk_size: u64, block_size: u32) -> Self {
+        Self {
+            disk_size,
+            block_size,
+            pattern: vec![Block::Data],
+            layout: Layout::Sequential,
+            offsets: None,
+            stale_slots: 0,
+            seed: 1,
+            parent: None,
+        }
+    }
+
+    /// States of the blocks, repeated over the whole disk
+    pub fn pattern(mut self, pattern: &[Block]) -> Self {
+        self.pattern = pattern.to_vec();
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Places the block map and block data at unaligned offsets
+    pub fn offsets(mut self, block_map_offset: u32, data_offset: u32) -> Self {
+        self.offsets = Some((block_map_offset, data_offset));
+        self
+    }
+
+    /// Places the block map right after the header and the block data right after the map,
+    /// without the alignment VirtualBox uses
+    pub fn packed(self) -> Self {
+        let block_map_offset = std::mem::size_of::<VdiHeader>() as u32;
+        let blocks = self.disk_size.div_ceil(self.block_size as u64) as u32;
+        self.offsets(block_map_offset, block_map_offset + blocks * 4)
+    }
+
+    /// Leaves `count` slots of garbage at the start of the data area that no block references,
+    /// as an interrupted allocation or a compaction would
+    pub fn stale_slots(mut self, count: u32) -> Self {
+        self.stale_slots = count;
+        self
+    }
+
+    /// Distinguishes the data of this image from the data of others at the same positions
+    pub fn seed(mut self, seed: u8) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Makes this a differencing image of `parent`, its free blocks reading from it
+    pub fn parent(mut self, parent: &Fixture) -> Self {
+        assert_eq!(parent.header.block_size.get(), self.block_size);
+        self.parent = Some((parent.header, parent.raw.clone()));
+        self
+    }
+
+    pub fn build(self) -> Fixture {
+        let mut header = VdiHeader::new(self.disk_size, self.block_size).expect("valid header");
+        if let Some((block_map_offset, data_offset)) = self.offsets {
+            header.block_offsets_offset.set(block_map_offset);
+            header.data_offset.set(data_offset);
+        }
+        if let Some((parent, _)) = &self.parent {
+            header.image_type.set(VdiHeader::TYPE_DIFFERENCING);
+            header.uuid_link = parent.uuid_image;
+            header.uuid_parent = parent.uuid_last_snap;
+        }
+
+        let block_size = self.block_size as u64;
+        let blocks = header.blocks_in_image.get() as usize;
+        let states: Vec<Block> = (0..blocks)
+            .map(|index| self.pattern[index % self.pattern.len()])
+            .collect();
+        let mut stored: Vec<usize> = (0..blocks)
+            .filter(|&index| matches!(states[index], Block::Data | Block::Zeros))
+            .collect();
+        match self.layout {
+            Layout::Sequential => {}
+            Layout::Reversed => stored.reverse(),
+            Layout::Shuffled(seed) => shuffle(&mut stored, seed),
+        }
+
+        let data_offset = header.data_offset.get() as u64;
+        let slots = self.stale_slots as u64 + stored.len() as u64;
+        let mut image = vec![0u8; (data_offset + slots * block_size) as usize];
+        image[data_offset as usize..][..(self.stale_slots as u64 * block_size) as usize].fill(0xAA);
+
+        let mut raw = vec![0u8; blocks * block_size as usize];
+        if let Some((_, parent_raw)) = &self.parent {
+            let len = std::cmp::min(parent_raw.len(), raw.len());
+            for (index, state) in states.iter().enumerate() {
+                let start = index * block_size as usize;
+                if *state == Block::Free && start < len {
+                    let end = std::cmp::min(start + block_size as usize, len);
+                    raw[start..end].copy_from_slice(&parent_raw[start..end]);
+                }
+            }
+        }
+
+        let mut map = vec![VdiHeader::BLOCK_FREE; blocks];
+        for (index, state) in states.iter().enumerate() {
+            if *state == Block::Zero {
+                map[index] = VdiHeader::BLOCK_ZERO;
+            }
+        }
+        for (slot, &index) in (self.stale_slots..).zip(&stored) {
+            map[index] = slot;
+            let start = index * block_size as usize;
+            let block = &mut raw[start..start + block_size as usize];
+            if states[index] == Block::Data {
+                for (offset, byte) in block.iter_mut().enumerate() {
+                    *byte = pattern_byte(self.seed, (start + offset) as u64);
+                }
+            }
+            let at = (data_offset + slot as u64 * block_size) as usize;
+            image[at..at + block_size as usize].copy_from_slice(block);
+        }
+        header.blocks_allocated.set(slots as u32);
+
+        let map: Vec<u8> = map.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+        let map_offset = header.block_offsets_offset.get() as usize;
+        image[map_offset..map_offset + map.len()].copy_from_slice(&map);
+        image[..std::mem::size_of::<VdiHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+
+        raw.truncate(self.disk_size as usize);
+        Fixture { header, image, raw }
+    }
+}
+
+/// Byte of the data of image `seed` at logical position `pos`, never zero
+pub fn pattern_byte(seed: u8, pos: u64) -> u8 {
+    let mixed = (pos ^ (pos >> 13)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    (mixed >> 56) as u8 ^ seed | 1
+}
+
+/// Xorshift generator, for reproducible positions and lengths
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Number in `0..bound`
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+fn shuffle(items: &mut [usize], seed: u64) {
+    let mut rng = Rng::new(seed);
+    for i in (1..items.len()).rev() {
+        items.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+}
+
+/// Every fixture variant the tests compare against the raw contents
+pub fn variants() -> Vec<(String, Fixture)> {
+    use Block::*;
+
+    let mut fixtures = Vec::new();
+    for block_size in [512, 4096, 64 * 1024] {
+        let disk_size = 24 * block_size as u64 + 1000;
+        for (name, pattern) in [
+            ("full", &[Data][..]),
+            ("empty", &[Free][..]),
+            ("sparse", &[Data, Free, Free, Zero, Data, Zeros][..]),
+        ] {
+            for layout in [Layout::Sequential, Layout::Reversed, Layout::Shuffled(7)] {
+                let fixture = ImageBuilder::new(disk_size, block_size)
+                    .pattern(pattern)
+                    .layout(layout)
+                    .build();
+                fixtures.push((format!("{name}/{layout:?}/{block_size}"), fixture));
+            }
+        }
+
+        let packed = ImageBuilder::new(disk_size, block_size)
+            .pattern(&[Data, Free, Data])
+            .packed()
+            .build();
+        fixtures.push((format!("packed/{block_size}"), packed));
+
+        let stale = ImageBuilder::new(disk_size, block_size)
+            .pattern(&[Free, Data])
+            .offsets(0x1000, 0x1000 + 12345)
+            .stale_slots(3)
+            .layout(Layout::Shuffled(3))
+            .build();
+        fixtures.push((format!("stale/{block_size}"), stale));
+    }
+    fixtures
+}
@@ -0,0 +1,206 @@
+#![cfg(feature = "vbox")]
+
+mod support;
+
+use std::path::Path;
+
+use positioned_io2::ReadAt;
+use support::{Block, Fixture, ImageBuilder};
+use uuid::{Uuid, uuid};
+use vdi::VdiDisk;
+use vdi::header::VdiHeader;
+use vdi::vbox::{GlobalRegistry, Machine};
+
+const MACHINE: Uuid = uuid!("6f1c4c44-8d5c-4d5e-9a43-2c5b0e7a1d01");
+const INSTALLED: Uuid = uuid!("0b7a1a4e-52c4-4f0e-8d8b-7b1e5d6c2a01");
+const UPDATED: Uuid = uuid!("0b7a1a4e-52c4-4f0e-8d8b-7b1e5d6c2a02");
+const BASE: Uuid = uuid!("a1e3f6d2-3b4c-4d5e-8f60-718293a4b5c6");
+const SNAPSHOT: Uuid = uuid!("b2f4a7e3-4c5d-4e6f-9071-8293a4b5c6d7");
+const CURRENT: Uuid = uuid!("c3a5b8f4-5d6e-4f70-a182-93a4b5c6d7e8");
+const SHARED: Uuid = uuid!("d4b6c9a5-6e7f-4081-b293-a4b5c6d7e8f9");
+
+const DISK_SIZE: u64 = 8 * 4096;
+
+/// Images of the fixture machine, the current one based on the snapshot one based on the base
+struct Images {
+    base: Fixture,
+    snapshot: Fixture,
+    current: Fixture,
+    shared: Fixture,
+}
+
+fn with_uuid(mut fixture: Fixture, uuid: Uuid) -> Fixture {
+    fixture.header.uuid_image = VdiHeader::uuid_to_disk(uuid);
+    fixture.image = fixture.with_header(|_| {});
+    fixture
+}
+
+/// Lays out `VirtualBox.xml`, the machine folder and the images like VirtualBox does in `dir`
+fn write_fixture(dir: &Path) -> Images {
+    let base = with_uuid(ImageBuilder::new(DISK_SIZE, 4096).build(), BASE);
+    let snapshot = ImageBuilder::new(DISK_SIZE, 4096)
+        .pattern(&[Block::Free, Block::Data])
+        .seed(2)
+        .parent(&base)
+        .build();
+    let snapshot = with_uuid(snapshot, SNAPSHOT);
+    let current = ImageBuilder::new(DISK_SIZE, 4096)
+        .pattern(&[Block::Free, Block::Free, Block::Data])
+        .seed(3)
+        .parent(&snapshot)
+        .build();
+    let current = with_uuid(current, CURRENT);
+    let shared = ImageBuilder::new(DISK_SIZE, 4096).seed(4).build();
+    let shared = with_uuid(shared, SHARED);
+
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/vbox");
+    let machine_dir = dir.join("Fixture");
+    std::fs::create_dir_all(machine_dir.join("Snapshots")).unwrap();
+    std::fs::create_dir_all(dir.join("Shared")).unwrap();
+    std::fs::copy(data.join("VirtualBox.xml"), dir.join("VirtualBox.xml")).unwrap();
+    std::fs::copy(data.join("Machine.vbox"), machine_dir.join("Fixture.vbox")).unwrap();
+    for (path, fixture) in [
+        (machine_dir.join("Fixture.vdi"), &base),
+        (
+            machine_dir.join(format!("Snapshots/{{{SNAPSHOT}}}.vdi")),
+            &snapshot,
+        ),
+        (
+            machine_dir.join(format!("Snapshots/{{{CURRENT}}}.vdi")),
+            &current,
+        ),
+        (dir.join("Shared/Shared.vdi"), &shared),
+    ] {
+        std::fs::write(path, &fixture.image).unwrap();
+    }
+
+    Images {
+        base,
+        snapshot,
+        current,
+        shared,
+    }
+}
+
+fn read_all(disk: &VdiDisk) -> Vec<u8> {
+    let mut data = vec![0u8; DISK_SIZE as usize];
+    disk.read_exact_at(0, &mut data).unwrap();
+    data
+}
+
+fn image_uuid(disk: &VdiDisk) -> Uuid {
+    VdiHeader::uuid_from_disk(disk.header.uuid_image)
+}
+
+#[test]
+fn machine_parses_media_and_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    write_fixture(dir.path());
+    let machine_dir = dir.path().join("Fixture");
+    let machine = Machine::load(machine_dir.join("Fixture.vbox")).unwrap();
+
+    assert_eq!(machine.uuid, MACHINE);
+    assert_eq!(machine.name, "Fixture");
+    assert_eq!(machine.current_snapshot, Some(UPDATED));
+
+    let [base] = machine.media_registry.hard_disks.as_slice() else {
+        panic!("expected one base image");
+    };
+    assert_eq!(base.uuid, BASE);
+    assert_eq!(base.location, machine_dir.join("Fixture.vdi"));
+    assert_eq!(base.format, "VDI");
+    assert_eq!(base.kind.as_deref(), Some("Normal"));
+    let chain: Vec<Uuid> = machine
+        .media_registry
+        .chain(CURRENT)
+        .unwrap()
+        .iter()
+        .map(|disk| disk.uuid)
+        .collect();
+    assert_eq!(chain, [BASE, SNAPSHOT, CURRENT]);
+    let current = machine.media_registry.find(CURRENT).unwrap();
+    assert_eq!(
+        current.location,
+        machine_dir.join(format!("Snapshots/{{{CURRENT}}}.vdi"))
+    );
+    assert_eq!(current.kind, None);
+    assert!(machine.media_registry.find(SHARED).is_none());
+
+    let installed = machine.snapshot.as_ref().unwrap();
+    assert_eq!(installed.uuid, INSTALLED);
+    assert_eq!(installed.name, "Installed");
+    assert_eq!(installed.description.as_deref(), Some("Fresh install"));
+    assert_eq!(installed.children.len(), 1);
+    assert_eq!(machine.find_snapshot(UPDATED).unwrap().name, "Updated");
+
+    // The DVD drive is no hard disk
+    let attached: Vec<_> = machine
+        .hard_disk_attachments(None)
+        .unwrap()
+        .into_iter()
+        .map(|(controller, a)| (controller.name.as_str(), a.port, a.image))
+        .collect();
+    assert_eq!(
+        attached,
+        [("SATA", 0, Some(CURRENT)), ("SATA", 1, Some(SHARED))]
+    );
+    let attached = machine.hard_disk_attachments(Some(INSTALLED)).unwrap();
+    assert_eq!(attached[0].1.image, Some(BASE));
+    assert!(machine.hard_disk_attachments(Some(MACHINE)).is_err());
+}
+
+#[test]
+fn global_registry_lists_machines_and_shared_media() {
+    let dir = tempfile::tempdir().unwrap();
+    write_fixture(dir.path());
+    let global = GlobalRegistry::load(dir.path().join("VirtualBox.xml")).unwrap();
+
+    assert_eq!(global.machines.len(), 1);
+    assert_eq!(global.machines[0].uuid, MACHINE);
+    assert_eq!(
+        global.machines[0].settings,
+        dir.path().join("Fixture/Fixture.vbox")
+    );
+    let shared = global.media_registry.find(SHARED).unwrap();
+    assert_eq!(shared.location, dir.path().join("Shared/Shared.vdi"));
+    assert_eq!(shared.kind.as_deref(), Some("Immutable"));
+
+    assert_eq!(global.machine(MACHINE).unwrap().name, "Fixture");
+    assert!(global.machine(BASE).is_err());
+}
+
+#[test]
+fn open_disk_attaches_the_parents_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let images = write_fixture(dir.path());
+    let global = GlobalRegistry::load(dir.path().join("VirtualBox.xml")).unwrap();
+    let machine = global.machine(MACHINE).unwrap();
+    let registry = Some(&global.media_registry);
+
+    let disk = machine.open_disk(None, "SATA", 0, 0, registry).unwrap();
+    assert_eq!(image_uuid(&disk), CURRENT);
+    let snapshot = disk.parent().unwrap();
+    assert_eq!(image_uuid(snapshot), SNAPSHOT);
+    let base = snapshot.parent().unwrap();
+    assert_eq!(image_uuid(base), BASE);
+    assert!(base.parent().is_none());
+    assert!(read_all(&disk) == images.current.raw);
+
+    let disk = machine
+        .open_disk(Some(UPDATED), "SATA", 0, 0, registry)
+        .unwrap();
+    assert_eq!(image_uuid(&disk), SNAPSHOT);
+    assert!(read_all(&disk) == images.snapshot.raw);
+    let disk = machine
+        .open_disk(Some(INSTALLED), "SATA", 0, 0, registry)
+        .unwrap();
+    assert!(read_all(&disk) == images.base.raw);
+
+    // Registered globally only
+    let disk = machine.open_disk(None, "SATA", 1, 0, registry).unwrap();
+    assert!(read_all(&disk) == images.shared.raw);
+    assert!(machine.open_disk(None, "SATA", 1, 0, None).is_err());
+
+    assert!(machine.open_disk(None, "IDE", 1, 0, registry).is_err());
+    assert!(machine.open_disk(None, "SATA", 2, 0, registry).is_err());
+}
@@ -0,0 +1,124 @@
+mod support;
+
+use std::cell::RefCell;
+use std::io::IoSliceMut;
+use std::rc::Rc;
+
+use positioned_io2::ReadAt;
+use support::{Block, Fixture, ImageBuilder, Layout};
+use vdi::VdiDisk;
+
+const BLOCK_SIZE: u64 = 4096;
+
+/// Position and length of each read of the image
+type Reads = Rc<RefCell<Vec<(u64, usize)>>>;
+
+/// Image reader recording the position and length of every read
+struct RecordingReader {
+    image: Vec<u8>,
+    reads: Reads,
+}
+
+impl ReadAt for RecordingReader {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads.borrow_mut().push((pos, buf.len()));
+        self.image.read_at(pos, buf)
+    }
+}
+
+/// Opens `fixture`, returning the reads made after opening it
+fn open_recording(fixture: &Fixture) -> (VdiDisk, Reads) {
+    let reads = Reads::default();
+    let reader = RecordingReader {
+        image: fixture.image.clone(),
+        reads: Rc::clone(&reads),
+    };
+    let disk = VdiDisk::open(Box::new(reader)).unwrap();
+    reads.borrow_mut().clear();
+    (disk, reads)
+}
+
+/// Reads from `pos` into buffers of `lens`, returning the bytes read and their concatenation
+fn read_vectored(disk: &VdiDisk, pos: u64, lens: &[usize]) -> (usize, Vec<u8>) {
+    let mut bufs: Vec<Vec<u8>> = lens.iter().map(|&len| vec![0u8; len]).collect();
+    let mut slices: Vec<IoSliceMut> = bufs.iter_mut().map(|b| IoSliceMut::new(b)).collect();
+    let n = disk.read_vectored_at(pos, &mut slices).unwrap();
+    (n, bufs.concat())
+}
+
+#[test]
+fn contiguous_blocks_are_read_at_once() {
+    let fixture = ImageBuilder::new(8 * BLOCK_SIZE, BLOCK_SIZE as u32).build();
+    let (disk, reads) = open_recording(&fixture);
+    let data_offset = fixture.header.data_offset.get() as u64;
+
+    let (n, data) = read_vectored(&disk, 0, &[8 * BLOCK_SIZE as usize]);
+    assert_eq!(n, 8 * BLOCK_SIZE as usize);
+    assert!(data == fixture.raw);
+    assert_eq!(*reads.borrow(), [(data_offset, 8 * BLOCK_SIZE as usize)]);
+
+    // Each buffer is read at once, across the blocks it covers
+    reads.borrow_mut().clear();
+    let (n, data) = read_vectored(&disk, 100, &[5000, 3 * BLOCK_SIZE as usize, 10]);
+    assert_eq!(n, data.len());
+    assert!(data[..] == fixture.raw[100..][..data.len()]);
+    assert_eq!(
+        *reads.borrow(),
+        [
+            (data_offset + 100, 5000),
+            (data_offset + 5100, 3 * BLOCK_SIZE as usize),
+            (data_offset + 5100 + 3 * BLOCK_SIZE, 10),
+        ]
+    );
+}
+
+#[test]
+fn blocks_out_of_order_are_read_one_by_one() {
+    let fixture = ImageBuilder::new(8 * BLOCK_SIZE, BLOCK_SIZE as u32)
+        .layout(Layout::Reversed)
+        .build();
+    let (disk, reads) = open_recording(&fixture);
+
+    let (n, data) = read_vectored(&disk, 10, &[3000, 8 * BLOCK_SIZE as usize]);
+    assert_eq!(n, 8 * BLOCK_SIZE as usize - 10);
+    assert!(data[..n] == fixture.raw[10..]);
+    // The first buffer, then the rest of block 0 and the seven blocks after it
+    assert_eq!(reads.borrow().len(), 9);
+}
+
+#[test]
+fn runs_end_at_unallocated_blocks() {
+    // Stored blocks 0-1, 4-6 and 9-10, one after the other in the image
+    let fixture = ImageBuilder::new(10 * BLOCK_SIZE + 1000, BLOCK_SIZE as u32)
+        .pattern(&[
+            Block::Data,
+            Block::Data,
+            Block::Free,
+            Block::Zero,
+            Block::Data,
+        ])
+        .build();
+    let (disk, reads) = open_recording(&fixture);
+    let data_offset = fixture.header.data_offset.get() as u64;
+    let slot = |slot: u64| data_offset + slot * BLOCK_SIZE;
+
+    let (n, data) = read_vectored(&disk, 0, &[fixture.raw.len() + 500]);
+    assert_eq!(n, fixture.raw.len(), "short at the end of the disk");
+    assert!(data[..n] == fixture.raw);
+    assert_eq!(
+        *reads.borrow(),
+        [
+            (slot(0), 2 * BLOCK_SIZE as usize),
+            (slot(2), 3 * BLOCK_SIZE as usize),
+            (slot(5), BLOCK_SIZE as usize + 1000),
+        ]
+    );
+
+    // Split over buffers ending inside and at the edges of blocks
+    let lens = [1, BLOCK_SIZE as usize - 1, 7000, 3000, 20000, 10000];
+    let (n, data) = read_vectored(&disk, 0, &lens);
+    assert_eq!(n, fixture.raw.len());
+    assert!(data[..n] == fixture.raw);
+    let (n, _) = read_vectored(&disk, fixture.raw.len() as u64, &[10, 10]);
+    assert_eq!(n, 0);
+}